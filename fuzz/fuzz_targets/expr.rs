@@ -0,0 +1,20 @@
+#![no_main]
+// cargo +nightly fuzz run expr
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/expr.rs"]
+mod expr;
+
+use expr::{Env, Program};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(src) = core::str::from_utf8(data) else {
+        return;
+    };
+    // Anything that compiles has to evaluate without panicking
+    if let Ok(program) = Program::compile(src, 2) {
+        let env = Env { inputs: &[1.0, 2.0], setpoints: &[3.0, 4.0], time: 5.0 };
+        program.eval(&env);
+    }
+});
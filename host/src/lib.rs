@@ -0,0 +1,49 @@
+// The firmware's hardware independent modules, built for the host so their
+// unit tests can run. The tests live next to the code in each module.
+#![allow(dead_code)]
+// micromath's F32Ext is shadowed by std's own float methods here
+#![allow(unused_imports)]
+// The firmware's existing style, its nightly also predates is_some_and and then_some
+#![allow(
+    clippy::wrong_self_convention,
+    clippy::field_reassign_with_default,
+    clippy::manual_clamp,
+    clippy::unnecessary_map_or,
+    clippy::unnecessary_lazy_evaluations,
+    clippy::too_many_arguments
+)]
+
+#[path = "../../src/analog.rs"]
+mod analog;
+#[path = "../../src/battery.rs"]
+mod battery;
+#[path = "../../src/capture.rs"]
+mod capture;
+#[path = "../../src/charge.rs"]
+mod charge;
+#[path = "../../src/console.rs"]
+mod console;
+#[path = "../../src/control.rs"]
+mod control;
+#[path = "../../src/energy.rs"]
+mod energy;
+#[path = "../../src/expr.rs"]
+mod expr;
+#[path = "../../src/logics.rs"]
+mod logics;
+#[path = "../../src/policy.rs"]
+mod policy;
+#[path = "../../src/profile.rs"]
+mod profile;
+#[path = "../../src/sequence.rs"]
+mod sequence;
+#[path = "../../src/state.rs"]
+mod state;
+#[path = "../../src/stats.rs"]
+mod stats;
+#[path = "../../src/sweep.rs"]
+mod sweep;
+#[path = "../../src/table.rs"]
+mod table;
+#[path = "../../src/timing.rs"]
+mod timing;
@@ -0,0 +1,28 @@
+// Stands in for the board crate on the host, with only the plain types the
+// tested modules name. Anything touching registers stays out of the tests.
+pub mod hal {
+    pub mod adc {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Resolution {
+            _16BIT,
+            _12BIT,
+            _10BIT,
+            _8BIT,
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum SampleRate {
+            _1,
+            _2,
+            _4,
+            _8,
+            _16,
+            _32,
+            _64,
+            _128,
+            _256,
+            _512,
+            _1024,
+        }
+    }
+}
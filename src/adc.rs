@@ -0,0 +1,187 @@
+// The register side of the analog inputs, kept apart from the conversions
+// in analog.rs so those build on the host
+use wio_terminal::pac::adc0::RegisterBlock;
+
+use crate::analog::{AdcReference, AnalogInput, Internal, Trip};
+
+impl AnalogInput {
+    /// Applies the reference, sampling time, differential input and window.
+    /// Must be called while the ADC is still disabled. `full_scale` is that
+    /// of the channel sensed on this input.
+    pub fn configure(&self, adc: &RegisterBlock, full_scale: f32) {
+        adc.refctrl.modify(|_, w| match self.reference {
+            AdcReference::Internal => w.refsel().intref(),
+            AdcReference::HalfVddana => w.refsel().intvcc0(),
+            AdcReference::Vddana => w.refsel().intvcc1(),
+            AdcReference::ArefA { .. } => w.refsel().arefa(),
+            AdcReference::ArefB { .. } => w.refsel().arefb(),
+            AdcReference::ArefC { .. } => w.refsel().arefc(),
+        });
+        while adc.syncbusy.read().refctrl().bit_is_set() {}
+
+        adc.sampctrl.modify(|_, w| unsafe { w.samplen().bits(self.sample_time.min(63)) });
+        while adc.syncbusy.read().sampctrl().bit_is_set() {}
+
+        adc.inputctrl.modify(|_, w| match self.negative {
+            Some(ain) => unsafe { w.diffmode().set_bit().muxneg().bits(ain) },
+            None => w.diffmode().clear_bit().muxneg().gnd(),
+        });
+        while adc.syncbusy.read().inputctrl().bit_is_set() {}
+
+        self.configure_window(adc, full_scale);
+    }
+
+    /// Sets up the window monitor from `window` and enables its interrupt,
+    /// or disables it when neither limit is set.
+    pub fn configure_window(&self, adc: &RegisterBlock, full_scale: f32) {
+        let under = self.window.under.map(|v| self.to_raw(v, full_scale));
+        let over = self.window.over.map(|v| self.to_raw(v, full_scale));
+
+        // WINLT is the lower bound and WINUT the upper one of the "inside"
+        // range, so a single limit uses the register on its own side.
+        adc.winlt.write(|w| unsafe { w.winlt().bits(under.or(over).unwrap_or(0)) });
+        while adc.syncbusy.read().winlt().bit_is_set() {}
+        adc.winut.write(|w| unsafe { w.winut().bits(over.or(under).unwrap_or(0)) });
+        while adc.syncbusy.read().winut().bit_is_set() {}
+
+        adc.ctrlb.modify(|_, w| match (under, over) {
+            (Some(_), Some(_)) => w.winmode().mode4(),
+            // RESULT > WINLT
+            (None, Some(_)) => w.winmode().mode1(),
+            // RESULT < WINUT
+            (Some(_), None) => w.winmode().mode2(),
+            (None, None) => w.winmode().disable(),
+        });
+        while adc.syncbusy.read().ctrlb().bit_is_set() {}
+
+        adc.intflag.write(|w| w.winmon().set_bit());
+        if under.is_some() || over.is_some() {
+            adc.intenset.write(|w| w.winmon().set_bit());
+        } else {
+            adc.intenclr.write(|w| w.winmon().set_bit());
+        }
+    }
+
+    /// Handles a window monitor interrupt. The interrupt is left disabled
+    /// afterwards since the trip is latched until reconfigured.
+    pub fn service_window(&self, adc: &RegisterBlock) -> Option<Trip> {
+        if adc.intflag.read().winmon().bit_is_clear() {
+            return None;
+        }
+        adc.intflag.write(|w| w.winmon().set_bit());
+        adc.intenclr.write(|w| w.winmon().set_bit());
+
+        // Compared against the limit the monitor was configured with
+        let result = adc.result.read().result().bits();
+        let under = adc.winlt.read().winlt().bits();
+        match self.window.under {
+            Some(_) if result < under => Some(Trip::Under),
+            _ => Some(Trip::Over),
+        }
+    }
+}
+
+impl Internal {
+    pub(crate) fn select(&self, adc: &RegisterBlock) {
+        adc.inputctrl.modify(|_, w| match self {
+            Internal::Ptat => w.muxpos().ptat(),
+            Internal::Ctat => w.muxpos().ctat(),
+            Internal::VddCore => w.muxpos().scaledcorevcc(),
+            Internal::Vbat => w.muxpos().scaledvbat(),
+            Internal::IoVcc => w.muxpos().scalediovcc(),
+        });
+    }
+}
+
+/// Interleaves the internal channels and the current sense pins into a
+/// free running ADC. Every `every` results the input is switched to the
+/// next of them for one result and then back. Results taken while the mux
+/// is changing are discarded, and the window monitor is paused so the
+/// scanned channels can't trip it.
+pub struct Scanner {
+    count: u16,
+    next: usize,
+    state: ScanState,
+    muxpos: u8,
+    winmode: u8,
+}
+
+#[derive(Clone, Copy)]
+enum Scanned {
+    Internal(Internal),
+    // index into the sensor pins
+    Sensor(usize),
+}
+
+#[derive(Clone, Copy)]
+enum ScanState {
+    External,
+    Switching(Scanned),
+    Scanned(Scanned),
+    Returning,
+}
+
+pub enum Sample {
+    External(u16),
+    Internal(Internal, u16),
+    Sensor(usize, u16),
+    Discarded,
+}
+
+impl Scanner {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            next: 0,
+            state: ScanState::External,
+            muxpos: 0,
+            winmode: 0,
+        }
+    }
+
+    /// `sensors` are the AIN numbers of the current sense pins.
+    pub fn sample(&mut self, adc: &RegisterBlock, every: u16, sensors: &[u8], raw: u16) -> Sample {
+        match self.state {
+            ScanState::External => {
+                self.count += 1;
+                if self.count >= every.max(1) {
+                    self.count = 0;
+                    let scanned = match Internal::ALL.get(self.next) {
+                        Some(internal) => Scanned::Internal(*internal),
+                        None => Scanned::Sensor(self.next - Internal::ALL.len()),
+                    };
+                    self.next = (self.next + 1) % (Internal::ALL.len() + sensors.len());
+
+                    self.muxpos = adc.inputctrl.read().muxpos().bits();
+                    self.winmode = adc.ctrlb.read().winmode().bits();
+                    adc.ctrlb.modify(|_, w| w.winmode().disable());
+                    match scanned {
+                        Scanned::Internal(internal) => internal.select(adc),
+                        Scanned::Sensor(i) => adc.inputctrl.modify(|_, w| unsafe { w.muxpos().bits(sensors[i]) }),
+                    }
+                    self.state = ScanState::Switching(scanned);
+                }
+                Sample::External(raw)
+            }
+            ScanState::Switching(scanned) => {
+                self.state = ScanState::Scanned(scanned);
+                Sample::Discarded
+            }
+            ScanState::Scanned(scanned) => {
+                let muxpos = self.muxpos;
+                adc.inputctrl.modify(|_, w| unsafe { w.muxpos().bits(muxpos) });
+                self.state = ScanState::Returning;
+                match scanned {
+                    Scanned::Internal(internal) => Sample::Internal(internal, raw),
+                    Scanned::Sensor(i) => Sample::Sensor(i, raw),
+                }
+            }
+            ScanState::Returning => {
+                let winmode = self.winmode;
+                adc.ctrlb.modify(|_, w| unsafe { w.winmode().bits(winmode) });
+                self.state = ScanState::External;
+                Sample::Discarded
+            }
+        }
+    }
+}
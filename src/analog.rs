@@ -0,0 +1,322 @@
+use micromath::F32Ext;
+use wio_terminal::hal::adc::{Resolution, SampleRate};
+
+use crate::state::InternalValues;
+
+// The sensed side is divided down so the channel's full scale lands at 3V on the pin
+const PIN_AT_FULL_SCALE: f32 = 3.0;
+const VDDANA: f32 = 3.3;
+// Temperature log row in the NVM software calibration area
+const TEMP_LOG: *const u32 = 0x0080_0100 as *const u32;
+
+/// REFCTRL.REFSEL. The SAMD51 ADC has no gain stage, so the reference is
+/// the only way to change the input range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdcReference {
+    // Bandgap from SUPC.VREF, 1.0V unless SUPC is set up otherwise
+    Internal,
+    HalfVddana,
+    Vddana,
+    ArefA { volts: f32 },
+    ArefB { volts: f32 },
+    ArefC { volts: f32 },
+}
+
+impl AdcReference {
+    pub fn volts(&self) -> f32 {
+        match self {
+            AdcReference::Internal => 1.0,
+            AdcReference::HalfVddana => VDDANA / 2.0,
+            AdcReference::Vddana => VDDANA,
+            AdcReference::ArefA { volts } | AdcReference::ArefB { volts } | AdcReference::ArefC { volts } => *volts,
+        }
+    }
+}
+
+/// Limits in volts on the sensed side, either of which can be left out.
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    pub under: Option<f32>,
+    pub over: Option<f32>,
+}
+
+impl Window {
+    pub const DISABLED: Window = Window { under: None, over: None };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AnalogInput {
+    pub samples: SampleRate,
+    pub resolution: Resolution,
+    pub reference: AdcReference,
+    // SAMPCTRL.SAMPLEN, extra sampling time in half ADC clock cycles (0..=63)
+    pub sample_time: u8,
+    // MUXNEG AIN number to measure against instead of GND
+    pub negative: Option<u8>,
+    // Hardware fast-trip, checked by the ADC on every conversion
+    pub window: Window,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trip {
+    Under,
+    Over,
+}
+
+impl AnalogInput {
+    pub fn full_scale(&self) -> u32 {
+        match self.resolution {
+            Resolution::_8BIT => 1 << 8,
+            Resolution::_10BIT => 1 << 10,
+            Resolution::_12BIT => 1 << 12,
+            Resolution::_16BIT => 1 << 16,
+        }
+    }
+
+    /// Volts at the pin, or between the pins for a differential input.
+    pub fn to_pin_volts(&self, raw: u16) -> f32 {
+        match self.negative {
+            // Sign extended two's complement spanning -Vref..Vref
+            Some(_) => raw as i16 as f32 / (self.full_scale() / 2) as f32 * self.reference.volts(),
+            None => raw as f32 / self.full_scale() as f32 * self.reference.volts(),
+        }
+    }
+
+    /// Sensed side volts for a channel scaled to `full_scale`.
+    pub fn to_volts(&self, raw: u16, full_scale: f32) -> f32 {
+        self.to_pin_volts(raw) / PIN_AT_FULL_SCALE * full_scale
+    }
+
+    /// Nearest raw code for a sensed side voltage, saturating at the ends of the range.
+    pub fn to_raw(&self, volts: f32, full_scale: f32) -> u16 {
+        let pin = volts / full_scale * PIN_AT_FULL_SCALE / self.reference.volts();
+        match self.negative {
+            Some(_) => {
+                let half = (self.full_scale() / 2) as f32;
+                (pin * half).round().max(-half).min(half - 1.0) as i16 as u16
+            }
+            None => {
+                let max = (self.full_scale() - 1) as f32;
+                (pin * self.full_scale() as f32).round().max(0.0).min(max) as u16
+            }
+        }
+    }
+
+    pub fn readings(&self, raw: &InternalValues) -> InternalReadings {
+        // The supplies are measured through a 1/4 divider
+        let supply = |raw: u16| raw as f32 / self.full_scale() as f32 * self.reference.volts() * 4.0;
+        // Calibration codes are 12 bit against the 1.0V bandgap
+        let calibrated = |raw: u16| raw as f32 * self.reference.volts() * 4096.0 / self.full_scale() as f32;
+
+        InternalReadings {
+            temperature: TemperatureCalibration::read().celsius(calibrated(raw.ptat), calibrated(raw.ctat)),
+            vddcore: supply(raw.vddcore),
+            vbat: supply(raw.vbat),
+            iovcc: supply(raw.iovcc),
+        }
+    }
+}
+
+/// Shunt amplifier or hall effect sensor on a scanned pin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurrentSensor {
+    // at the pin, negative for a sensor wired backwards
+    pub volts_per_amp: f32,
+    // pin volts at 0A, about half the supply for most hall sensors
+    pub offset: f32,
+    // on the board, an empty footprint reads noise rather than no current
+    pub fitted: bool,
+}
+
+impl CurrentSensor {
+    /// A zero or non-finite scale turns every current into infinity or NaN,
+    /// a non-finite offset does the same.
+    pub fn is_valid(&self) -> bool {
+        self.volts_per_amp != 0.0 && self.volts_per_amp.is_finite() && self.offset.is_finite()
+    }
+
+    pub fn amps(&self, pin_volts: f32) -> f32 {
+        (pin_volts - self.offset) / self.volts_per_amp
+    }
+}
+
+impl Default for CurrentSensor {
+    // A 100mV/A shunt amplifier, only a placeholder until calibrated
+    fn default() -> Self {
+        Self { volts_per_amp: 0.1, offset: 0.0, fitted: true }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Internal {
+    Ptat,
+    Ctat,
+    VddCore,
+    Vbat,
+    IoVcc,
+}
+
+impl Internal {
+    pub const ALL: [Internal; 5] = [Internal::Ptat, Internal::Ctat, Internal::VddCore, Internal::Vbat, Internal::IoVcc];
+
+    pub fn store(&self, values: &mut InternalValues, raw: u16) {
+        match self {
+            Internal::Ptat => values.ptat = raw,
+            Internal::Ctat => values.ctat = raw,
+            Internal::VddCore => values.vddcore = raw,
+            Internal::Vbat => values.vbat = raw,
+            Internal::IoVcc => values.iovcc = raw,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InternalReadings {
+    // Celsius
+    pub temperature: f32,
+    pub vddcore: f32,
+    pub vbat: f32,
+    pub iovcc: f32,
+}
+
+struct TemperatureCalibration {
+    // Celsius at the low and high calibration points
+    tl: f32,
+    th: f32,
+    // PTAT and CTAT codes at those points
+    vpl: f32,
+    vph: f32,
+    vcl: f32,
+    vch: f32,
+}
+
+impl TemperatureCalibration {
+    fn read() -> Self {
+        let word = |i: usize| unsafe { core::ptr::read_volatile(TEMP_LOG.add(i)) };
+        let (w0, w1, w2) = (word(0), word(1), word(2));
+        let bits = |w: u32, shift: u32, len: u32| ((w >> shift) & ((1 << len) - 1)) as f32;
+
+        Self {
+            tl: bits(w0, 0, 8) + bits(w0, 8, 4) / 10.0,
+            th: bits(w0, 12, 8) + bits(w0, 20, 4) / 10.0,
+            vpl: bits(w1, 8, 12),
+            vph: bits(w1, 20, 12),
+            vcl: bits(w2, 0, 12),
+            vch: bits(w2, 12, 12),
+        }
+    }
+
+    // From the SAMD5x datasheet, "Device Temperature Measurement"
+    fn celsius(&self, tp: f32, tc: f32) -> f32 {
+        let Self { tl, th, vpl, vph, vcl, vch } = *self;
+        (tl * vph * tc - vpl * th * tc - tl * vch * tp + th * vcl * tp)
+            / (vcl * tp - vch * tp - vpl * tc + vph * tc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL_SCALE: f32 = 20.0;
+    const RESOLUTIONS: [Resolution; 4] = [Resolution::_8BIT, Resolution::_10BIT, Resolution::_12BIT, Resolution::_16BIT];
+    const REFERENCES: [AdcReference; 4] = [
+        AdcReference::Internal,
+        AdcReference::HalfVddana,
+        AdcReference::Vddana,
+        AdcReference::ArefA { volts: 2.5 },
+    ];
+
+    fn input(resolution: Resolution, reference: AdcReference, negative: Option<u8>) -> AnalogInput {
+        AnalogInput {
+            samples: SampleRate::_1,
+            resolution,
+            reference,
+            sample_time: 0,
+            negative,
+            window: Window::DISABLED,
+        }
+    }
+
+    fn each_input(negative: Option<u8>) -> impl Iterator<Item=AnalogInput> {
+        RESOLUTIONS.iter()
+            .flat_map(move |r| REFERENCES.iter().map(move |v| input(*r, *v, negative)))
+    }
+
+    #[test]
+    fn full_scale_follows_resolution() {
+        let scales: [u32; 4] = RESOLUTIONS.map(|r| input(r, AdcReference::Vddana, None).full_scale());
+        assert_eq!(scales, [256, 1024, 4096, 65536]);
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        for input in each_input(None) {
+            let top = input.full_scale() - 1;
+            for raw in [0, 1, top / 3, top / 2, top - 1, top] {
+                let raw = raw as u16;
+                assert_eq!(input.to_raw(input.to_volts(raw, FULL_SCALE), FULL_SCALE), raw, "{:?}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn volts_scale_with_reference() {
+        for input in each_input(None) {
+            let half = (input.full_scale() / 2) as u16;
+            let expected = input.reference.volts() / 2.0 / PIN_AT_FULL_SCALE * FULL_SCALE;
+            assert!((input.to_volts(half, FULL_SCALE) - expected).abs() < 1e-4, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_code() {
+        for input in each_input(None) {
+            let lsb = input.to_volts(1, FULL_SCALE);
+            let at = input.to_volts(100, FULL_SCALE);
+            assert_eq!(input.to_raw(at + 0.4 * lsb, FULL_SCALE), 100, "{:?}", input);
+            assert_eq!(input.to_raw(at + 0.6 * lsb, FULL_SCALE), 101, "{:?}", input);
+            assert_eq!(input.to_raw(at - 0.4 * lsb, FULL_SCALE), 100, "{:?}", input);
+            assert_eq!(input.to_raw(at - 0.6 * lsb, FULL_SCALE), 99, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn saturates_at_the_ends() {
+        for input in each_input(None) {
+            let top = (input.full_scale() - 1) as u16;
+            assert_eq!(input.to_raw(-1.0, FULL_SCALE), 0, "{:?}", input);
+            assert_eq!(input.to_raw(0.0, FULL_SCALE), 0, "{:?}", input);
+            assert_eq!(input.to_raw(input.to_volts(top, FULL_SCALE) * 2.0, FULL_SCALE), top, "{:?}", input);
+            assert_eq!(input.to_raw(1000.0, FULL_SCALE), top, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn differential_is_signed() {
+        for input in each_input(Some(1)) {
+            let half = (input.full_scale() / 2) as i32;
+            let lowest = -half as i16 as u16;
+            let highest = (half - 1) as i16 as u16;
+            assert_eq!(input.to_raw(-1000.0, FULL_SCALE), lowest, "{:?}", input);
+            assert_eq!(input.to_raw(1000.0, FULL_SCALE), highest, "{:?}", input);
+            assert_eq!(input.to_raw(0.0, FULL_SCALE), 0, "{:?}", input);
+            for raw in [lowest, (-1i16) as u16, 1, highest] {
+                assert_eq!(input.to_raw(input.to_volts(raw, FULL_SCALE), FULL_SCALE), raw, "{:?}", input);
+            }
+            assert!(input.to_volts((-1i16) as u16, FULL_SCALE) < 0.0);
+        }
+    }
+
+    #[test]
+    fn sensors_need_a_finite_scale_and_offset() {
+        let sensor = CurrentSensor { volts_per_amp: 0.1, offset: 1.65, fitted: true };
+        assert!(sensor.is_valid());
+        for volts_per_amp in [0.0, f32::NAN, f32::INFINITY] {
+            assert!(!CurrentSensor { volts_per_amp, ..sensor }.is_valid(), "{}", volts_per_amp);
+        }
+        for offset in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert!(!CurrentSensor { offset, ..sensor }.is_valid(), "{}", offset);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::logics::{ChannelConfig, Route, State, CHANNELS};
+use crate::table::{Breakpoint, Table};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chemistry {
+    LiIon,
+    LiFePo4,
+    LeadAcid,
+    NiMh,
+}
+
+const fn point(input: f32, output: f32) -> Breakpoint {
+    Breakpoint { input, output }
+}
+
+// Resting cell voltage to state of charge, 0 to 1
+const LI_ION_OCV: [Breakpoint; 9] = [
+    point(3.0, 0.0), point(3.45, 0.05), point(3.6, 0.1), point(3.7, 0.3), point(3.8, 0.55),
+    point(3.9, 0.7), point(4.0, 0.8), point(4.1, 0.9), point(4.2, 1.0),
+];
+const LI_FE_PO4_OCV: [Breakpoint; 7] = [
+    point(2.5, 0.0), point(3.0, 0.1), point(3.2, 0.2), point(3.25, 0.4), point(3.3, 0.7),
+    point(3.35, 0.95), point(3.4, 1.0),
+];
+const LEAD_ACID_OCV: [Breakpoint; 11] = [
+    point(1.75, 0.0), point(1.885, 0.1), point(1.93, 0.2), point(1.958, 0.3), point(1.983, 0.4),
+    point(2.01, 0.5), point(2.033, 0.6), point(2.053, 0.7), point(2.07, 0.8), point(2.083, 0.9),
+    point(2.122, 1.0),
+];
+const NI_MH_OCV: [Breakpoint; 6] = [
+    point(1.0, 0.0), point(1.15, 0.1), point(1.2, 0.3), point(1.25, 0.6), point(1.3, 0.85),
+    point(1.4, 1.0),
+];
+
+impl Chemistry {
+    pub const ALL: [Chemistry; 4] = [Chemistry::LiIon, Chemistry::LiFePo4, Chemistry::LeadAcid, Chemistry::NiMh];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chemistry::LiIon => "liion",
+            Chemistry::LiFePo4 => "lifepo4",
+            Chemistry::LeadAcid => "lead",
+            Chemistry::NiMh => "nimh",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.name() == name)
+    }
+
+    /// Per cell (cutoff, recovery hysteresis) volts.
+    fn limits(&self) -> (f32, f32) {
+        match self {
+            Chemistry::LiIon => (3.0, 0.2),
+            Chemistry::LiFePo4 => (2.5, 0.3),
+            Chemistry::LeadAcid => (1.75, 0.1),
+            Chemistry::NiMh => (1.0, 0.1),
+        }
+    }
+
+    fn ocv_points(&self) -> &'static [Breakpoint] {
+        match self {
+            Chemistry::LiIon => &LI_ION_OCV,
+            Chemistry::LiFePo4 => &LI_FE_PO4_OCV,
+            Chemistry::LeadAcid => &LEAD_ACID_OCV,
+            Chemistry::NiMh => &NI_MH_OCV,
+        }
+    }
+}
+
+/// A battery on one side. Voltages are for the whole pack, the OCV curve
+/// is per cell.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryProfile {
+    pub chemistry: Chemistry,
+    pub cells: u8,
+    // transfer out of the battery stops below this
+    pub cutoff: f32,
+    // and starts again once it's this much above the cutoff
+    pub hysteresis: f32,
+    // internal resistance, ohms, to estimate the resting voltage under load
+    pub resistance: f32,
+    // cell volts to state of charge
+    pub ocv: Table,
+}
+
+impl BatteryProfile {
+    /// The chemistry's usual cutoff, hysteresis and curve for `cells` cells.
+    pub fn new(chemistry: Chemistry, cells: u8) -> Self {
+        let (cutoff, hysteresis) = chemistry.limits();
+        Self {
+            chemistry,
+            cells,
+            cutoff: cutoff * cells as f32,
+            hysteresis: hysteresis * cells as f32,
+            resistance: 0.0,
+            ocv: Table::new(chemistry.ocv_points()).unwrap_or_default(),
+        }
+    }
+
+    /// `channel` is the config of the side's channel, the pack is read on its input.
+    pub fn is_valid(&self, channel: &ChannelConfig) -> bool {
+        self.cells > 0
+            && self.cutoff > 0.0
+            && self.hysteresis >= 0.0
+            // recovering needs a reading past the cutoff
+            && self.cutoff + self.hysteresis < channel.full_scale
+            && self.resistance >= 0.0
+            && !self.ocv.is_empty()
+    }
+
+    /// State of charge, 0 to 1, for a resting pack voltage.
+    pub fn soc(&self, volts: f32) -> f32 {
+        self.ocv.lookup(volts / self.cells as f32).max(0.0).min(1.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BatteryStatus {
+    pub volts: f32,
+    pub soc: f32,
+    // below the cutoff and not recovered yet
+    pub cut_off: bool,
+}
+
+/// Battery profiles by side, and the protection they get. A side's
+/// battery feeds every channel whose route sources from it.
+#[derive(Debug, Clone, Default)]
+pub struct Batteries {
+    pub profiles: [Option<BatteryProfile>; CHANNELS],
+    status: [BatteryStatus; CHANNELS],
+}
+
+impl Batteries {
+    pub fn new(profiles: [Option<BatteryProfile>; CHANNELS]) -> Self {
+        Self { profiles, status: Default::default() }
+    }
+
+    /// Replaces a side's profile, None when it's no longer a battery.
+    pub fn set(&mut self, side: usize, profile: Option<BatteryProfile>) {
+        self.profiles[side] = profile;
+        self.status[side] = BatteryStatus::default();
+    }
+
+    /// Status for each side that has a battery.
+    pub fn status(&self) -> [Option<BatteryStatus>; CHANNELS] {
+        core::array::from_fn(|i| self.profiles[i].as_ref().map(|_| self.status[i]))
+    }
+
+    /// Updates every battery from `state`'s inputs and turns off the
+    /// channels drawing from one that's cut off.
+    pub fn protect(&mut self, state: &mut State, routes: &[Route; CHANNELS]) {
+        for (side, (profile, status)) in self.profiles.iter().zip(self.status.iter_mut()).enumerate() {
+            let Some(profile) = profile else {
+                continue;
+            };
+            let volts = state.channels[side].input;
+            status.volts = volts;
+            status.cut_off = if status.cut_off {
+                volts < profile.cutoff + profile.hysteresis
+            } else {
+                volts < profile.cutoff
+            };
+
+            // Lossless estimate of the battery current from what it feeds
+            let watts: f32 = routes.iter().zip(state.channels.iter())
+                .filter(|(route, _)| route.source == side)
+                .filter_map(|(_, fed)| fed.power())
+                .sum();
+            let amps = if volts > 0.0 { watts / volts } else { 0.0 };
+            status.soc = profile.soc(volts + amps * profile.resistance);
+
+            if status.cut_off {
+                routes.iter().zip(state.channels.iter_mut())
+                    .filter(|(route, _)| route.source == side)
+                    .for_each(|(_, fed)| fed.real_output = 0.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logics::ROUTES;
+
+    // Three Li-ion cells on the right, which channel 0 draws from
+    const SIDE: usize = 1;
+    const FED: usize = 0;
+
+    fn batteries() -> Batteries {
+        let mut profiles: [Option<BatteryProfile>; CHANNELS] = Default::default();
+        profiles[SIDE] = Some(BatteryProfile::new(Chemistry::LiIon, 3));
+        Batteries::new(profiles)
+    }
+
+    // Both channels driving 5V, at 2A when `current`
+    fn protect(batteries: &mut Batteries, volts: f32, current: bool) -> State {
+        let mut state = State::default();
+        for side in state.channels.iter_mut() {
+            side.real_output = 5.0;
+            side.current = current.then(|| 2.0);
+        }
+        state.channels[SIDE].input = volts;
+        batteries.protect(&mut state, &ROUTES);
+        state
+    }
+
+    #[test]
+    fn cuts_off_holds_and_recovers() {
+        let mut batteries = batteries();
+        let profile = batteries.profiles[SIDE].clone().unwrap();
+        assert!(profile.cutoff == 9.0 && (profile.hysteresis - 0.6).abs() < 1e-6);
+        // volts, cut off
+        let cases = [(10.0, false), (9.1, false), (8.9, true), (9.3, true), (9.59, true), (9.7, false), (9.1, false)];
+        for (volts, cut_off) in cases {
+            let state = protect(&mut batteries, volts, false);
+            let status = batteries.status()[SIDE].unwrap();
+            assert_eq!((status.volts, status.cut_off), (volts, cut_off), "{}V", volts);
+            assert_eq!(state.channels[FED].real_output, if cut_off { 0.0 } else { 5.0 }, "{}V", volts);
+            // Not fed from the battery, so left alone
+            assert_eq!(state.channels[SIDE].real_output, 5.0);
+        }
+        assert_eq!(batteries.status()[FED], None);
+    }
+
+    #[test]
+    fn state_of_charge_is_clamped() {
+        let profile = BatteryProfile::new(Chemistry::LiIon, 3);
+        let cases = [(5.0, 0.0), (9.0, 0.0), (11.1, 0.3), (12.6, 1.0), (15.0, 1.0)];
+        for (volts, soc) in cases {
+            assert!((profile.soc(volts) - soc).abs() < 1e-5, "{}V gave {}", volts, profile.soc(volts));
+        }
+    }
+
+    #[test]
+    fn load_is_added_back_for_the_resting_voltage() {
+        let mut batteries = batteries();
+        batteries.profiles[SIDE].as_mut().unwrap().resistance = 0.111;
+        // 10W out of 11.1V, 0.1V across the resistance puts it at 3.7333V a cell
+        protect(&mut batteries, 11.1, true);
+        let soc = batteries.status()[SIDE].unwrap().soc;
+        assert!((soc - (0.3 + 0.25 / 3.0)).abs() < 1e-3, "{}", soc);
+    }
+
+    #[test]
+    fn recovery_has_to_be_measurable() {
+        let profile = BatteryProfile::new(Chemistry::LiIon, 3);
+        let config = ChannelConfig::default();
+        assert!(profile.is_valid(&config));
+        assert!(!profile.is_valid(&ChannelConfig { full_scale: 9.5, max: 9.5, ..config }));
+        assert!(!BatteryProfile { cells: 0, ..profile.clone() }.is_valid(&config));
+        assert!(!BatteryProfile { hysteresis: -0.1, ..profile }.is_valid(&config));
+    }
+}
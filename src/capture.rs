@@ -0,0 +1,531 @@
+use crate::analog::AnalogInput;
+use crate::logics::CHANNELS;
+
+pub const MAX_RECORD: usize = 512;
+// Auto mode triggers by itself after this many records' worth of samples
+const AUTO_RECORDS: usize = 2;
+
+/// Thresholds are volts on the sensed side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    // crossing `level` in the given direction
+    Edge { level: f32, rising: bool },
+    // any sample on the given side of `level`
+    Level { level: f32, above: bool },
+    // any sample outside low..=high
+    Window { low: f32, high: f32 },
+}
+
+// Trigger in codes, so the ADC interrupt only compares integers. They're
+// compared as Scale::code gives them, so in order of voltage.
+#[derive(Clone, Copy)]
+enum RawTrigger {
+    Edge { level: i32, rising: bool },
+    Level { level: i32, above: bool },
+    Window { low: i32, high: i32 },
+}
+
+impl RawTrigger {
+    fn new(trigger: Trigger, scale: &Scale) -> Self {
+        let code = |volts| scale.code(scale.to_raw(volts));
+        match trigger {
+            Trigger::Edge { level, rising } => RawTrigger::Edge { level: code(level), rising },
+            Trigger::Level { level, above } => RawTrigger::Level { level: code(level), above },
+            Trigger::Window { low, high } => RawTrigger::Window { low: code(low), high: code(high) },
+        }
+    }
+
+    fn fires(&self, prev: i32, raw: i32) -> bool {
+        match *self {
+            RawTrigger::Edge { level, rising: true } => prev < level && raw >= level,
+            RawTrigger::Edge { level, rising: false } => prev > level && raw <= level,
+            RawTrigger::Level { level, above } => if above { raw >= level } else { raw <= level },
+            RawTrigger::Window { low, high } => raw < low || raw > high,
+        }
+    }
+}
+
+/// Converts a record's codes, with the input it was taken on and the
+/// full scale of the channel sensed there.
+#[derive(Debug, Clone, Copy)]
+pub struct Scale {
+    pub input: AnalogInput,
+    pub full_scale: f32,
+}
+
+impl Scale {
+    pub fn to_volts(&self, raw: u16) -> f32 {
+        self.input.to_volts(raw, self.full_scale)
+    }
+
+    pub fn to_raw(&self, volts: f32) -> u16 {
+        self.input.to_raw(volts, self.full_scale)
+    }
+
+    // A differential result is two's complement, its codes only order
+    // like the voltages once sign extended
+    fn code(&self, raw: u16) -> i32 {
+        match self.input.negative {
+            Some(_) => raw as i16 as i32,
+            None => raw as i32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // one record, then stays stopped
+    Single,
+    // re-arms after each record has been read out over serial
+    Normal,
+    // like Normal, but records anyway if nothing triggers
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaptureConfig {
+    pub input: usize,
+    // keep one of every this many ADC results
+    pub decimation: u16,
+    pub length: usize,
+    // samples kept from before the trigger
+    pub pre_trigger: usize,
+    pub trigger: Trigger,
+    pub mode: Mode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureError {
+    Input,
+    Length,
+    PreTrigger,
+    Decimation,
+}
+
+impl CaptureError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CaptureError::Input => "bad input",
+            CaptureError::Length => "bad record length",
+            CaptureError::PreTrigger => "pre-trigger longer than record",
+            CaptureError::Decimation => "bad decimation",
+        }
+    }
+}
+
+impl CaptureConfig {
+    pub fn validate(&self) -> Result<(), CaptureError> {
+        if self.input >= CHANNELS {
+            return Err(CaptureError::Input);
+        }
+        if !(2..=MAX_RECORD).contains(&self.length) {
+            return Err(CaptureError::Length);
+        }
+        if self.pre_trigger >= self.length {
+            return Err(CaptureError::PreTrigger);
+        }
+        if self.decimation == 0 {
+            return Err(CaptureError::Decimation);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Stopped,
+    // filling the pre-trigger part, or waiting for the trigger
+    Armed,
+    Triggered,
+    // complete record waiting to be read
+    Ready,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Stopped => "stopped",
+            State::Armed => "armed",
+            State::Triggered => "triggered",
+            State::Ready => "ready",
+        }
+    }
+}
+
+/// Ring buffer on the ADC result stream, recording around a trigger.
+pub struct Capture {
+    config: Option<CaptureConfig>,
+    // set along with config
+    scale: Option<Scale>,
+    trigger: RawTrigger,
+    state: State,
+    buf: [u16; MAX_RECORD],
+    // next slot to write
+    write: usize,
+    // samples written since arming, saturates at length
+    filled: usize,
+    remaining: usize,
+    skip: u16,
+    prev: Option<u16>,
+    // samples since arming, for auto mode and the sample period
+    taken: u32,
+    forced: bool,
+    // records completed, so readers can tell a new one from the last
+    records: u32,
+    // a readout is going out, the record must stay put
+    held: bool,
+    // DWT cycles at the first and latest sample since arming
+    first_cycles: u32,
+    last_cycles: u32,
+    cycles_per_us: f32,
+}
+
+/// A finished record copied out of the ring, oldest sample first.
+#[derive(Clone)]
+pub struct Waveform {
+    pub config: CaptureConfig,
+    pub scale: Scale,
+    pub samples: [u16; MAX_RECORD],
+    // measured, the ADC rate depends on its clock and averaging
+    pub period_us: f32,
+    // Auto mode gave up waiting for the trigger
+    pub forced: bool,
+}
+
+impl Waveform {
+    pub fn samples(&self) -> &[u16] {
+        &self.samples[..self.config.length]
+    }
+}
+
+impl Capture {
+    pub fn new(core_freq: u32) -> Self {
+        Self {
+            config: None,
+            scale: None,
+            trigger: RawTrigger::Level { level: 0, above: true },
+            state: State::Stopped,
+            buf: [0; MAX_RECORD],
+            write: 0,
+            filled: 0,
+            remaining: 0,
+            skip: 0,
+            prev: None,
+            taken: 0,
+            forced: false,
+            records: 0,
+            held: false,
+            first_cycles: 0,
+            last_cycles: 0,
+            cycles_per_us: core_freq as f32 / 1_000_000.0,
+        }
+    }
+
+    /// Arms with `config`, `scale` converts its thresholds for the input.
+    pub fn configure(&mut self, config: CaptureConfig, scale: Scale) {
+        self.config = Some(config);
+        self.scale = Some(scale);
+        self.trigger = RawTrigger::new(config.trigger, &scale);
+        self.arm();
+    }
+
+    pub fn config(&self) -> Option<&CaptureConfig> {
+        self.config.as_ref()
+    }
+
+    pub fn scale(&self) -> Option<&Scale> {
+        self.scale.as_ref()
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn forced(&self) -> bool {
+        self.forced
+    }
+
+    pub fn records(&self) -> u32 {
+        self.records
+    }
+
+    /// Keeps the record from being re-armed over until `release`.
+    pub fn hold(&mut self) {
+        self.held = true;
+    }
+
+    pub fn release(&mut self) {
+        self.held = false;
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    pub fn arm(&mut self) {
+        if self.config.is_none() || self.held {
+            return;
+        }
+        self.state = State::Armed;
+        self.write = 0;
+        self.filled = 0;
+        self.skip = 0;
+        self.prev = None;
+        self.taken = 0;
+        self.forced = false;
+    }
+
+    pub fn stop(&mut self) {
+        if !self.held {
+            self.state = State::Stopped;
+        }
+    }
+
+    /// Re-arms after the host read a record, unless it was a single shot.
+    pub fn done_reading(&mut self) {
+        let single = self.config.map_or(true, |c| c.mode == Mode::Single);
+        if self.state == State::Ready && !single {
+            self.arm();
+        }
+    }
+
+    /// Sample `i` of the finished record, oldest first.
+    pub fn get(&self, i: usize) -> Option<u16> {
+        let config = self.config?;
+        if self.state != State::Ready || i >= config.length {
+            return None;
+        }
+        // The ring is full, its oldest sample is the next one to be overwritten
+        Some(self.buf[(self.write + i) % config.length])
+    }
+
+    /// Average time between samples since arming.
+    pub fn period_us(&self) -> f32 {
+        let cycles = self.last_cycles.wrapping_sub(self.first_cycles);
+        cycles as f32 / self.taken.saturating_sub(1).max(1) as f32 / self.cycles_per_us
+    }
+
+    /// Copy of the finished record, if there is one.
+    pub fn waveform(&self) -> Option<Waveform> {
+        let (config, scale) = (self.config?, self.scale?);
+        if self.state != State::Ready {
+            return None;
+        }
+        let mut samples = [0; MAX_RECORD];
+        for (i, sample) in samples.iter_mut().take(config.length).enumerate() {
+            *sample = self.buf[(self.write + i) % config.length];
+        }
+        Some(Waveform { config, scale, samples, period_us: self.period_us(), forced: self.forced })
+    }
+
+    /// Feeds one ADC result from `input`. True when it completed a record.
+    pub fn sample(&mut self, input: usize, raw: u16, cycles: u32) -> bool {
+        let Some(config) = self.config else {
+            return false;
+        };
+        if input != config.input || !matches!(self.state, State::Armed | State::Triggered) {
+            return false;
+        }
+        self.skip += 1;
+        if self.skip < config.decimation {
+            return false;
+        }
+        self.skip = 0;
+
+        if self.taken == 0 {
+            self.first_cycles = cycles;
+        }
+        self.last_cycles = cycles;
+        self.taken = self.taken.wrapping_add(1);
+        self.buf[self.write] = raw;
+        self.write = (self.write + 1) % config.length;
+        self.filled = (self.filled + 1).min(config.length);
+        let prev = self.prev.replace(raw);
+
+        match self.state {
+            State::Armed => {
+                // Not before the pre-trigger part is full
+                if self.filled <= config.pre_trigger {
+                    return false;
+                }
+                let fired = match (prev, self.scale) {
+                    (Some(prev), Some(scale)) => self.trigger.fires(scale.code(prev), scale.code(raw)),
+                    _ => false,
+                };
+                let timed_out = config.mode == Mode::Auto && self.taken as usize >= config.length * AUTO_RECORDS;
+                if fired || timed_out {
+                    self.forced = !fired;
+                    self.state = State::Triggered;
+                    // The trigger sample is the first one after the pre-trigger part
+                    self.remaining = config.length - config.pre_trigger - 1;
+                }
+            }
+            State::Triggered => self.remaining -= 1,
+            _ => {}
+        }
+
+        if self.state == State::Triggered && self.remaining == 0 {
+            self.state = State::Ready;
+            self.records = self.records.wrapping_add(1);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analog::{AdcReference, Window};
+    use wio_terminal::hal::adc::{Resolution, SampleRate};
+
+    fn scale(negative: Option<u8>) -> Scale {
+        let input = AnalogInput {
+            samples: SampleRate::_1,
+            resolution: Resolution::_12BIT,
+            reference: AdcReference::Vddana,
+            sample_time: 0,
+            negative,
+            window: Window::DISABLED,
+        };
+        Scale { input, full_scale: 20.0 }
+    }
+
+    fn config(trigger: Trigger, mode: Mode) -> CaptureConfig {
+        CaptureConfig { input: 0, decimation: 1, length: 8, pre_trigger: 3, trigger, mode }
+    }
+
+    fn armed(config: CaptureConfig, scale: Scale) -> Capture {
+        let mut capture = Capture::new(1_000_000);
+        capture.configure(config, scale);
+        capture
+    }
+
+    /// Feeds `volts` one sample per us, giving the indices that completed a record.
+    fn feed(capture: &mut Capture, scale: &Scale, volts: &[f32]) -> std::vec::Vec<usize> {
+        volts.iter().enumerate()
+            .filter(|(i, v)| capture.sample(0, scale.to_raw(**v), *i as u32))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    fn recorded(capture: &Capture, scale: &Scale) -> std::vec::Vec<f32> {
+        let waveform = capture.waveform().unwrap();
+        waveform.samples().iter().map(|raw| (scale.to_volts(*raw) * 10.0).round() / 10.0).collect()
+    }
+
+    const RISING: Trigger = Trigger::Edge { level: 10.0, rising: true };
+
+    #[test]
+    fn rising_edge_keeps_the_pre_trigger_samples() {
+        let scale = scale(None);
+        let mut capture = armed(config(RISING, Mode::Single), scale);
+        let volts = [1.0, 2.0, 3.0, 4.0, 5.0, 12.0, 13.0, 14.0, 15.0, 16.0, 17.0];
+        assert_eq!(feed(&mut capture, &scale, &volts), [9]);
+        assert_eq!(capture.state(), State::Ready);
+        // Three before the trigger, the trigger sample and the rest after it
+        assert_eq!(recorded(&capture, &scale), [3.0, 4.0, 5.0, 12.0, 13.0, 14.0, 15.0, 16.0]);
+        assert!(!capture.waveform().unwrap().forced);
+    }
+
+    #[test]
+    fn ignores_the_trigger_until_the_pre_trigger_is_full() {
+        let scale = scale(None);
+        let mut capture = armed(config(RISING, Mode::Single), scale);
+        // The first edge comes too early, the second one counts
+        let volts = [1.0, 12.0, 1.0, 2.0, 12.0, 13.0, 14.0, 15.0, 16.0];
+        assert_eq!(feed(&mut capture, &scale, &volts), [8]);
+        assert_eq!(recorded(&capture, &scale), [12.0, 1.0, 2.0, 12.0, 13.0, 14.0, 15.0, 16.0]);
+    }
+
+    #[test]
+    fn falling_edge_level_and_window() {
+        let scale = scale(None);
+        let volts = [8.0, 8.0, 8.0, 8.0, 11.0, 9.0, 3.0, 3.0, 3.0, 3.0, 3.0, 3.0, 3.0];
+        let cases = [
+            // first sample that fires
+            (Trigger::Edge { level: 10.0, rising: false }, 5),
+            (Trigger::Level { level: 10.0, above: true }, 4),
+            (Trigger::Level { level: 5.0, above: false }, 6),
+            (Trigger::Window { low: 5.0, high: 10.0 }, 4),
+        ];
+        for (trigger, fires) in cases {
+            let mut capture = armed(config(trigger, Mode::Single), scale);
+            assert_eq!(feed(&mut capture, &scale, &volts), [fires + 4], "{:?}", trigger);
+        }
+    }
+
+    #[test]
+    fn differential_levels_compare_signed() {
+        let scale = scale(Some(0));
+        let trigger = Trigger::Edge { level: -1.0, rising: true };
+        let mut capture = armed(config(trigger, Mode::Single), scale);
+        // Below 0V the codes wrap round to the top of the u16 range
+        let volts = [-5.0, -5.0, -5.0, -5.0, -3.0, -0.5, 0.0, 2.0, 4.0, 4.0];
+        assert_eq!(feed(&mut capture, &scale, &volts), [9]);
+        assert_eq!(recorded(&capture, &scale), [-5.0, -5.0, -3.0, -0.5, 0.0, 2.0, 4.0, 4.0]);
+
+        let mut capture = armed(config(Trigger::Window { low: -2.0, high: 2.0 }, Mode::Single), scale);
+        assert_eq!(feed(&mut capture, &scale, &[0.0, 1.0, -1.0, 1.5, -2.5, 0.0, 0.0, 0.0, 0.0]), [8]);
+    }
+
+    #[test]
+    fn decimation_keeps_one_in_n() {
+        let scale = scale(None);
+        let mut capture = armed(CaptureConfig { decimation: 2, ..config(RISING, Mode::Single) }, scale);
+        let volts: std::vec::Vec<f32> = (0..20).map(|i| i as f32).collect();
+        assert_eq!(feed(&mut capture, &scale, &volts), [19]);
+        // Kept 1, 3, ... and 11 was the first past the level
+        assert_eq!(recorded(&capture, &scale), [5.0, 7.0, 9.0, 11.0, 13.0, 15.0, 17.0, 19.0]);
+    }
+
+    #[test]
+    fn single_stays_stopped_and_normal_re_arms() {
+        let scale = scale(None);
+        let volts = [1.0, 1.0, 1.0, 1.0, 12.0, 12.0, 12.0, 12.0, 12.0];
+
+        let mut capture = armed(config(RISING, Mode::Single), scale);
+        assert_eq!(feed(&mut capture, &scale, &volts), [8]);
+        capture.done_reading();
+        assert_eq!(capture.state(), State::Ready);
+        assert_eq!(feed(&mut capture, &scale, &volts), []);
+        assert_eq!(capture.records(), 1);
+
+        let mut capture = armed(config(RISING, Mode::Normal), scale);
+        assert_eq!(feed(&mut capture, &scale, &volts), [8]);
+        // Held while it goes out, so it isn't overwritten
+        capture.hold();
+        capture.done_reading();
+        assert_eq!(capture.state(), State::Ready);
+        capture.release();
+        capture.done_reading();
+        assert_eq!(capture.state(), State::Armed);
+        assert_eq!(feed(&mut capture, &scale, &volts), [8]);
+        assert_eq!(capture.records(), 2);
+    }
+
+    #[test]
+    fn auto_records_anyway_when_nothing_triggers() {
+        let scale = scale(None);
+        let mut capture = armed(config(RISING, Mode::Auto), scale);
+        let volts = [1.0; 40];
+        // Gives up after two records' worth, then fills the rest
+        assert_eq!(feed(&mut capture, &scale, &volts), [15 + 4]);
+        assert!(capture.waveform().unwrap().forced);
+
+        // A trigger in time is taken as usual
+        let mut capture = armed(config(RISING, Mode::Auto), scale);
+        assert_eq!(feed(&mut capture, &scale, &[1.0, 1.0, 1.0, 1.0, 12.0, 12.0, 12.0, 12.0, 12.0]), [8]);
+        assert!(!capture.waveform().unwrap().forced);
+    }
+
+    #[test]
+    fn other_inputs_are_ignored() {
+        let scale = scale(None);
+        let mut capture = armed(config(RISING, Mode::Single), scale);
+        for i in 0..20 {
+            assert!(!capture.sample(1, scale.to_raw(i as f32), i));
+        }
+        assert_eq!(capture.state(), State::Armed);
+    }
+}
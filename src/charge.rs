@@ -0,0 +1,361 @@
+use crate::logics::{ChannelConfig, CHANNELS};
+
+// Setpoint change per second for each amp or volt of error
+const CURRENT_GAIN: f32 = 2.0;
+const VOLTAGE_GAIN: f32 = 1.0;
+// Fastest the setpoint may move, V/s
+const MAX_SLEW: f32 = 2.0;
+// Above the CV target by this much the battery isn't taking the charge
+const OVER_VOLTAGE_MARGIN: f32 = 0.5;
+// Current must stay under the termination level this long to be done
+const TERMINATION_MS: u32 = 2000;
+// Within this of the CV target the battery is in the CV stage. Which loop
+// sets the step can't tell, the voltage loop also wins while the setpoint
+// is still climbing up to the battery and no current flows yet.
+const CV_BAND: f32 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChargeConfig {
+    // setpoint driven to charge
+    pub channel: usize,
+    // input the battery voltage is read on
+    pub measure: usize,
+    pub current_limit: f32,
+    pub cv_volts: f32,
+    // done once the CV stage current drops below this
+    pub termination: f32,
+    // 0 for no limit
+    pub timeout_ms: u32,
+    // below this the battery only gets precharge_current
+    pub precharge_volts: f32,
+    pub precharge_current: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeError {
+    Channel,
+    Range,
+}
+
+impl ChargeError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChargeError::Channel => "bad channel",
+            ChargeError::Range => "value out of range",
+        }
+    }
+}
+
+impl ChargeConfig {
+    /// Checks the CV target against `limits`, the charged channel's config.
+    pub fn validate(&self, limits: &ChannelConfig) -> Result<(), ChargeError> {
+        if self.channel >= CHANNELS || self.measure >= CHANNELS {
+            return Err(ChargeError::Channel);
+        }
+        let positive = |v: f32| v > 0.0 && v.is_finite();
+        // The setpoint climbs from 0 to the CV target, only the top has to fit
+        if !(positive(self.current_limit) && positive(self.cv_volts) && self.cv_volts <= limits.max) {
+            return Err(ChargeError::Range);
+        }
+        if !(0.0..self.current_limit).contains(&self.termination) {
+            return Err(ChargeError::Range);
+        }
+        if !(0.0..self.cv_volts).contains(&self.precharge_volts) || !(0.0..=self.current_limit).contains(&self.precharge_current) {
+            return Err(ChargeError::Range);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeFault {
+    // the channel has no current sensor
+    NoCurrent,
+    Timeout,
+    OverVoltage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeState {
+    Idle,
+    Precharge,
+    ConstantCurrent,
+    ConstantVoltage,
+    Done,
+    Fault(ChargeFault),
+}
+
+impl ChargeState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChargeState::Idle => "idle",
+            ChargeState::Precharge => "precharge",
+            ChargeState::ConstantCurrent => "cc",
+            ChargeState::ConstantVoltage => "cv",
+            ChargeState::Done => "done",
+            ChargeState::Fault(ChargeFault::NoCurrent) => "fault no current",
+            ChargeState::Fault(ChargeFault::Timeout) => "fault timeout",
+            ChargeState::Fault(ChargeFault::OverVoltage) => "fault over voltage",
+        }
+    }
+
+    pub fn is_charging(&self) -> bool {
+        matches!(self, ChargeState::Precharge | ChargeState::ConstantCurrent | ChargeState::ConstantVoltage)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChargeStatus {
+    pub state: ChargeState,
+    pub elapsed_ms: u32,
+    // setpoint driven
+    pub level: f32,
+    pub volts: f32,
+    pub amps: f32,
+}
+
+/// CC/CV charger steering one setpoint from the battery voltage and the
+/// channel's current. Pure, time and measurements are passed in.
+#[derive(Debug, Clone, Copy)]
+pub struct Charger {
+    config: Option<ChargeConfig>,
+    state: ChargeState,
+    started_ms: u32,
+    last_ms: u32,
+    // since when the current has been under the termination level
+    low_since_ms: Option<u32>,
+    level: f32,
+    volts: f32,
+    amps: f32,
+}
+
+impl Default for Charger {
+    fn default() -> Self {
+        Self {
+            config: None,
+            state: ChargeState::Idle,
+            started_ms: 0,
+            last_ms: 0,
+            low_since_ms: None,
+            level: 0.0,
+            volts: 0.0,
+            amps: 0.0,
+        }
+    }
+}
+
+impl Charger {
+    /// Starts from `level`, the setpoint the channel is at now.
+    pub fn start(&mut self, config: ChargeConfig, now_ms: u32, level: f32) {
+        *self = Self {
+            config: Some(config),
+            state: ChargeState::Precharge,
+            started_ms: now_ms,
+            last_ms: now_ms,
+            level,
+            ..Self::default()
+        };
+    }
+
+    /// Stops charging, the caller turns the setpoint off.
+    pub fn stop(&mut self) {
+        if self.state.is_charging() {
+            self.state = ChargeState::Idle;
+        }
+    }
+
+    pub fn config(&self) -> Option<&ChargeConfig> {
+        self.config.as_ref()
+    }
+
+    pub fn state(&self) -> ChargeState {
+        self.state
+    }
+
+    pub fn status(&self, now_ms: u32) -> ChargeStatus {
+        let elapsed_ms = if self.state.is_charging() { now_ms } else { self.last_ms }.wrapping_sub(self.started_ms);
+        ChargeStatus { state: self.state, elapsed_ms, level: self.level, volts: self.volts, amps: self.amps }
+    }
+
+    /// Steps the charge with the battery at `volts` taking `amps`, giving
+    /// the setpoint to drive. Once it's done or faulted that's 0.
+    pub fn update(&mut self, now_ms: u32, volts: f32, amps: Option<f32>) -> Option<f32> {
+        let config = self.config?;
+        if !self.state.is_charging() {
+            return None;
+        }
+        let dt = now_ms.wrapping_sub(self.last_ms) as f32 / 1000.0;
+        self.last_ms = now_ms;
+        self.volts = volts;
+
+        let Some(amps) = amps else {
+            return Some(self.finish(ChargeState::Fault(ChargeFault::NoCurrent)));
+        };
+        self.amps = amps;
+        if config.timeout_ms != 0 && now_ms.wrapping_sub(self.started_ms) > config.timeout_ms {
+            return Some(self.finish(ChargeState::Fault(ChargeFault::Timeout)));
+        }
+        if volts > config.cv_volts + OVER_VOLTAGE_MARGIN {
+            return Some(self.finish(ChargeState::Fault(ChargeFault::OverVoltage)));
+        }
+
+        let precharge = volts < config.precharge_volts;
+        let limit = if precharge { config.precharge_current } else { config.current_limit };
+        // Whichever loop wants the lower setpoint is in charge
+        let current_step = CURRENT_GAIN * (limit - amps);
+        let voltage_step = VOLTAGE_GAIN * (config.cv_volts - volts);
+        let step = current_step.min(voltage_step).max(-MAX_SLEW).min(MAX_SLEW) * dt;
+        self.level = (self.level + step).max(0.0).min(config.cv_volts);
+
+        self.state = if precharge {
+            ChargeState::Precharge
+        } else if volts >= config.cv_volts - CV_BAND {
+            ChargeState::ConstantVoltage
+        } else {
+            ChargeState::ConstantCurrent
+        };
+
+        if self.state == ChargeState::ConstantVoltage && amps < config.termination {
+            let since = *self.low_since_ms.get_or_insert(now_ms);
+            if now_ms.wrapping_sub(since) >= TERMINATION_MS {
+                return Some(self.finish(ChargeState::Done));
+            }
+        } else {
+            self.low_since_ms = None;
+        }
+        Some(self.level)
+    }
+
+    fn finish(&mut self, state: ChargeState) -> f32 {
+        self.state = state;
+        self.level = 0.0;
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_MS: u32 = 100;
+
+    // A cell whose open circuit voltage rises linearly with its charge,
+    // behind its own resistance and the wiring's from the channel
+    struct Cell {
+        charge: f32,
+        capacity_as: f32,
+        empty_volts: f32,
+        full_volts: f32,
+        internal_ohms: f32,
+        wiring_ohms: f32,
+    }
+
+    impl Cell {
+        fn new(charge: f32) -> Self {
+            Self { charge, capacity_as: 600.0, empty_volts: 2.7, full_volts: 4.2, internal_ohms: 0.1, wiring_ohms: 0.2 }
+        }
+
+        fn open_circuit(&self) -> f32 {
+            self.empty_volts + (self.full_volts - self.empty_volts) * self.charge
+        }
+
+        // Terminal volts and amps with the channel at `level`, it can't sink
+        fn drive(&mut self, level: f32, dt: f32) -> (f32, f32) {
+            let ocv = self.open_circuit();
+            let amps = ((level - ocv) / (self.internal_ohms + self.wiring_ohms)).max(0.0);
+            self.charge = (self.charge + amps * dt / self.capacity_as).min(1.0);
+            (ocv + amps * self.internal_ohms, amps)
+        }
+    }
+
+    fn config() -> ChargeConfig {
+        ChargeConfig {
+            channel: 0,
+            measure: 1,
+            current_limit: 1.0,
+            cv_volts: 4.2,
+            termination: 0.05,
+            timeout_ms: 0,
+            precharge_volts: 3.0,
+            precharge_current: 0.1,
+        }
+    }
+
+    // Charges until it stops, returning each state it went through and the
+    // highest volts and amps seen
+    fn run(config: ChargeConfig, cell: &mut Cell, limit_ms: u32) -> (std::vec::Vec<ChargeState>, f32, f32) {
+        let mut charger = Charger::default();
+        charger.start(config, 0, 0.0);
+        let (mut states, mut max_volts, mut max_amps) = (vec![charger.state()], 0.0f32, 0.0f32);
+        let (mut level, mut now) = (0.0, 0);
+        while charger.state().is_charging() && now < limit_ms {
+            now += TICK_MS;
+            let (volts, amps) = cell.drive(level, TICK_MS as f32 / 1000.0);
+            max_volts = max_volts.max(volts);
+            max_amps = max_amps.max(amps);
+            level = charger.update(now, volts, Some(amps)).unwrap_or(level);
+            if states.last() != Some(&charger.state()) {
+                states.push(charger.state());
+            }
+        }
+        (states, max_volts, max_amps)
+    }
+
+    #[test]
+    fn precharges_then_cc_then_cv_until_done() {
+        let mut cell = Cell::new(0.05);
+        let (states, max_volts, max_amps) = run(config(), &mut cell, 3_600_000);
+        assert_eq!(states, [
+            ChargeState::Precharge,
+            ChargeState::ConstantCurrent,
+            ChargeState::ConstantVoltage,
+            ChargeState::Done,
+        ]);
+        assert!(max_volts < config().cv_volts + 0.05, "{}", max_volts);
+        assert!(max_amps < config().current_limit * 1.1, "{}", max_amps);
+        assert!(cell.charge > 0.95, "{}", cell.charge);
+    }
+
+    #[test]
+    fn skips_precharge_above_its_voltage() {
+        let mut cell = Cell::new(0.5);
+        let (states, _, _) = run(config(), &mut cell, 3_600_000);
+        assert_eq!(states[1..], [ChargeState::ConstantCurrent, ChargeState::ConstantVoltage, ChargeState::Done]);
+    }
+
+    #[test]
+    fn times_out() {
+        let config = ChargeConfig { timeout_ms: 60_000, ..config() };
+        let mut cell = Cell::new(0.5);
+        let (states, _, _) = run(config, &mut cell, 3_600_000);
+        assert_eq!(states.last(), Some(&ChargeState::Fault(ChargeFault::Timeout)));
+        assert!(cell.charge < 0.95);
+    }
+
+    #[test]
+    fn faults_over_voltage() {
+        // A cell for a higher charge voltage than configured
+        let mut cell = Cell { empty_volts: 4.8, full_volts: 5.0, ..Cell::new(0.5) };
+        let (states, _, _) = run(config(), &mut cell, 3_600_000);
+        assert_eq!(states, [ChargeState::Precharge, ChargeState::Fault(ChargeFault::OverVoltage)]);
+    }
+
+    #[test]
+    fn cv_target_must_fit_the_channel() {
+        let limits = ChannelConfig { min: 2.0, max: 12.0, ..ChannelConfig::default() };
+        assert_eq!(config().validate(&limits), Ok(()));
+        assert_eq!(ChargeConfig { cv_volts: 12.5, ..config() }.validate(&limits), Err(ChargeError::Range));
+        // Only the top counts, the setpoint starts below the channel's min anyway
+        assert_eq!(ChargeConfig { cv_volts: 1.0, precharge_volts: 0.5, ..config() }.validate(&limits), Ok(()));
+        assert_eq!(ChargeConfig { measure: CHANNELS, ..config() }.validate(&limits), Err(ChargeError::Channel));
+    }
+
+    #[test]
+    fn faults_without_current() {
+        let mut charger = Charger::default();
+        charger.start(config(), 0, 1.0);
+        assert_eq!(charger.update(TICK_MS, 3.5, None), Some(0.0));
+        assert_eq!(charger.state(), ChargeState::Fault(ChargeFault::NoCurrent));
+        assert_eq!(charger.update(2 * TICK_MS, 3.5, Some(0.5)), None);
+    }
+}
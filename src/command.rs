@@ -0,0 +1,590 @@
+use arrayvec::ArrayString;
+
+use crate::analog::CurrentSensor;
+use crate::battery::{BatteryProfile, Chemistry};
+use crate::charge::ChargeConfig;
+use crate::capture::{CaptureConfig, CaptureError, Mode, Trigger};
+use crate::control::Trigger as ControlTrigger;
+use crate::expr::{ExprError, Program};
+use crate::logics::{ChannelUpdate, MpptConfig, Tracking, Units, CHANNELS, MAX_LEVEL};
+use crate::policy::{Detection, Policy, SourcePriority};
+use crate::profile::{Profile, Segment, Shape};
+use crate::sweep::{Spacing, SweepConfig};
+use crate::sequence::{Condition, Sequence, Step};
+use crate::state::SENSORS;
+use crate::stats::Signal;
+use crate::table::{Breakpoint, Table, TableError, MAX_POINTS};
+
+// Long enough for a full table on one line
+pub type CommandLine = ArrayString<[u8; 256]>;
+
+/// Everything that can be changed at runtime, from the serial port or the UI.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Policy(Policy),
+    // What releases the control runs
+    Trigger(ControlTrigger),
+    // Drive a setpoint directly, within the channel's limits or 0 for off
+    Set { channel: usize, level: f32 },
+    // Whether the setpoint buttons use the fine step
+    StepSize { fine: bool },
+    Channel { channel: usize, update: ChannelUpdate },
+    Table { channel: usize, table: Table },
+    Expr { channel: usize, program: Program },
+    // None goes back to independent setpoints
+    Track(Option<Tracking>),
+    Sequence(Sequence),
+    // Run the sequence up (true) or down
+    Power(bool),
+    Profile { channel: usize, profile: Profile },
+    Play { channel: usize, action: Play },
+    SweepStart(SweepConfig),
+    SweepAbort,
+    // Send the last sweep's results to the host
+    SweepDump,
+    CaptureStart(CaptureConfig),
+    // Arm again with the same settings
+    CaptureArm,
+    CaptureStop,
+    // Send the finished record to the host as a binary block
+    CaptureRead,
+    // Report one signal's statistics, or all of them
+    Stats(Option<Signal>),
+    StatsReset(Option<Signal>),
+    // Report the energy counters
+    Energy,
+    // Clear one channel's energy counters, or all of them
+    EnergyReset(Option<usize>),
+    // Calibrate a current sensor, which also marks it fitted
+    Sensor { index: usize, sensor: CurrentSensor },
+    // The board doesn't have this current sensor
+    SensorUnfitted(usize),
+    ChargeStart(ChargeConfig),
+    ChargeStop,
+    MpptStart(MpptConfig),
+    MpptStop,
+    // What counts as a source on one side
+    Detection { side: usize, detection: Detection },
+    // Which source wins when both sides present one
+    SourcePriority(SourcePriority),
+    // None when the side is no longer a battery
+    Battery { side: usize, profile: Option<BatteryProfile> },
+    // Replace a battery's OCV curve, state of charge from 0 to 1
+    BatteryOcv { side: usize, ocv: Table },
+    // Release a latched window trip, it trips again if the input is still out
+    FaultClear,
+    // Write the current settings to flash
+    Save,
+}
+
+/// Where a command came from. Only the host is sent a reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Host,
+    Ui,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    Unknown,
+    Argument,
+    Table(TableError),
+    Expr(ExprError),
+    Capture(CaptureError),
+}
+
+impl ParseError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParseError::Empty => "empty",
+            ParseError::Unknown => "unknown command",
+            ParseError::Argument => "bad argument",
+            ParseError::Table(e) => e.name(),
+            ParseError::Expr(e) => e.name(),
+            ParseError::Capture(e) => e.name(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Play {
+    Start,
+    Pause,
+    Resume,
+    Abort,
+}
+
+/// Parses one line: a command name followed by space separated arguments.
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_whitespace();
+    let name = words.next().ok_or(ParseError::Empty)?;
+
+    match name {
+        // policy <bridge|pass|clamp|priority <channel>|table|expr|disabled>
+        "policy" => {
+            let policy = words.next().ok_or(ParseError::Argument)?;
+            Policy::parse(policy, words.next())
+                .map(Command::Policy)
+                .ok_or(ParseError::Argument)
+        }
+        // trigger <polled|adc <decimation>>
+        "trigger" => {
+            let trigger = words.next().ok_or(ParseError::Argument)?;
+            ControlTrigger::parse(trigger, words.next())
+                .map(Command::Trigger)
+                .ok_or(ParseError::Argument)
+        }
+        // set <channel> <level>
+        "set" => {
+            let channel = words.next()
+                .and_then(|c| c.parse().ok())
+                .filter(|c| *c < CHANNELS)
+                .ok_or(ParseError::Argument)?;
+            let level = words.next().and_then(|w| w.parse().ok()).ok_or(ParseError::Argument)?;
+            Ok(Command::Set { channel, level })
+        }
+        // step fine|coarse
+        "step" => match words.next() {
+            Some("fine") => Ok(Command::StepSize { fine: true }),
+            Some("coarse") => Ok(Command::StepSize { fine: false }),
+            _ => Err(ParseError::Argument),
+        },
+        // channel <channel> [min=<level>] [max=<level>] [coarse=<step>] [fine=<step>]
+        //         [scale=<full scale>] [units=<text>] [precision=<decimals>]
+        // Anything left out keeps its current value
+        "channel" => {
+            let channel = words.next()
+                .and_then(|c| c.parse().ok())
+                .filter(|c| *c < CHANNELS)
+                .ok_or(ParseError::Argument)?;
+            let mut update = ChannelUpdate::default();
+            for word in words {
+                let (key, value) = word.split_once('=').ok_or(ParseError::Argument)?;
+                let number = || value.parse::<f32>().map_err(|_| ParseError::Argument);
+                match key {
+                    "min" => update.min = Some(number()?),
+                    "max" => update.max = Some(number()?),
+                    "coarse" => update.coarse_step = Some(number()?),
+                    "fine" => update.fine_step = Some(number()?),
+                    "scale" => update.full_scale = Some(number()?),
+                    "units" => update.units = Some(Units::from(value).map_err(|_| ParseError::Argument)?),
+                    "precision" => update.precision = Some(value.parse().map_err(|_| ParseError::Argument)?),
+                    _ => return Err(ParseError::Argument),
+                }
+            }
+            // Checked once it's applied to the current config
+            Ok(Command::Channel { channel, update })
+        }
+        // table <channel> <in>:<out> <in>:<out> ...
+        "table" => {
+            let channel = words.next()
+                .and_then(|c| c.parse().ok())
+                .filter(|c| *c < CHANNELS)
+                .ok_or(ParseError::Argument)?;
+            let mut points = [Breakpoint { input: 0.0, output: 0.0 }; MAX_POINTS];
+            let mut count = 0;
+            for word in words {
+                let point = points.get_mut(count).ok_or(ParseError::Table(TableError::TooManyPoints))?;
+                let (input, output) = word.split_once(':').ok_or(ParseError::Argument)?;
+                *point = Breakpoint {
+                    input: input.parse().map_err(|_| ParseError::Argument)?,
+                    output: output.parse().map_err(|_| ParseError::Argument)?,
+                };
+                count += 1;
+            }
+            let table = Table::new(&points[..count]).map_err(ParseError::Table)?;
+            Ok(Command::Table { channel, table })
+        }
+        // expr out<channel> = <expression>
+        "expr" => {
+            let rest = line.trim_start()[name.len()..].trim_start();
+            let (target, src) = rest.split_once('=').ok_or(ParseError::Argument)?;
+            let channel = target.trim().strip_prefix("out")
+                .and_then(|c| c.parse().ok())
+                .filter(|c| *c < CHANNELS)
+                .ok_or(ParseError::Argument)?;
+            let program = Program::compile(src, CHANNELS).map_err(ParseError::Expr)?;
+            Ok(Command::Expr { channel, program })
+        }
+        // track <leader> <ratio> [offset] [midpoint], or track off
+        "track" => {
+            let leader = words.next().ok_or(ParseError::Argument)?;
+            if leader == "off" {
+                return Ok(Command::Track(None));
+            }
+            let mut number = |default: Option<f32>| -> Result<f32, ParseError> {
+                match words.next() {
+                    Some(word) => word.parse().map_err(|_| ParseError::Argument),
+                    None => default.ok_or(ParseError::Argument),
+                }
+            };
+            let tracking = Tracking {
+                leader: leader.parse().ok().filter(|c| *c < CHANNELS).ok_or(ParseError::Argument)?,
+                ratio: number(None)?,
+                offset: number(Some(0.0))?,
+                midpoint: number(Some(0.0))?,
+            };
+            Ok(Command::Track(Some(tracking)))
+        }
+        // seq up, seq down, or seq <channel>:<delay ms>[:in<n>><volts>] ...
+        // with no steps to clear it
+        "seq" => match words.next() {
+            Some("up") => Ok(Command::Power(true)),
+            Some("down") => Ok(Command::Power(false)),
+            first => {
+                let mut sequence = Sequence::default();
+                for word in first.into_iter().chain(words) {
+                    let step = parse_step(word).ok_or(ParseError::Argument)?;
+                    sequence.steps.push(step).map_err(|_| ParseError::Argument)?;
+                }
+                Ok(Command::Sequence(sequence))
+            }
+        },
+        // profile <channel> start|pause|resume|abort, or
+        // profile <channel> [loops=<n>] <ms>:<volts>[:ramp] ...
+        "profile" => {
+            let channel = words.next()
+                .and_then(|c| c.parse().ok())
+                .filter(|c| *c < CHANNELS)
+                .ok_or(ParseError::Argument)?;
+            let mut words = words.peekable();
+            let action = match words.peek() {
+                Some(&"start") => Some(Play::Start),
+                Some(&"pause") => Some(Play::Pause),
+                Some(&"resume") => Some(Play::Resume),
+                Some(&"abort") => Some(Play::Abort),
+                _ => None,
+            };
+            if let Some(action) = action {
+                return Ok(Command::Play { channel, action });
+            }
+
+            let mut profile = Profile::default();
+            if let Some(loops) = words.peek().and_then(|w| w.strip_prefix("loops=")) {
+                profile.loops = loops.parse().map_err(|_| ParseError::Argument)?;
+                words.next();
+            }
+            for word in words {
+                let segment = parse_segment(word).ok_or(ParseError::Argument)?;
+                profile.segments.push(segment).map_err(|_| ParseError::Argument)?;
+            }
+            Ok(Command::Profile { channel, profile })
+        }
+        // sweep abort, sweep dump, or
+        // sweep <channel> <start> <stop> <points>|step=<volts> [log] [settle=<ms>] [dwell=<ms>] [measure=<input>]
+        "sweep" => {
+            let first = words.next().ok_or(ParseError::Argument)?;
+            match first {
+                "abort" => return Ok(Command::SweepAbort),
+                "dump" => return Ok(Command::SweepDump),
+                _ => {}
+            }
+            let channel: usize = first.parse().ok()
+                .filter(|c| *c < CHANNELS)
+                .ok_or(ParseError::Argument)?;
+            let mut number = || -> Result<f32, ParseError> {
+                words.next().and_then(|w| w.parse().ok()).ok_or(ParseError::Argument)
+            };
+            let (start, stop) = (number()?, number()?);
+            let count = words.next().ok_or(ParseError::Argument)?;
+            let points = match count.strip_prefix("step=") {
+                Some(step) => SweepConfig::points_for_step(start, stop, step.parse().map_err(|_| ParseError::Argument)?),
+                None => count.parse().map_err(|_| ParseError::Argument)?,
+            };
+            let mut config = SweepConfig {
+                channel,
+                measure: (channel + 1) % CHANNELS,
+                start,
+                stop,
+                points,
+                spacing: Spacing::Linear,
+                settle_ms: 50,
+                dwell_ms: 20,
+            };
+            for word in words {
+                let (key, value) = word.split_once('=').unwrap_or((word, ""));
+                let number = || value.parse().map_err(|_| ParseError::Argument);
+                match key {
+                    "log" => config.spacing = Spacing::Log,
+                    "settle" => config.settle_ms = number()?,
+                    "dwell" => config.dwell_ms = number()?,
+                    "measure" => config.measure = number()? as usize,
+                    _ => return Err(ParseError::Argument),
+                }
+            }
+            // Checked against the channel's limits when it starts
+            Ok(Command::SweepStart(config))
+        }
+        // capture arm|stop|read, or
+        // capture <input> [length=<n>] [pre=<n>] [decimate=<n>] [single|normal|auto]
+        //         [rise=<volts>|fall=<volts>|above=<volts>|below=<volts>|window=<low>:<high>]
+        "capture" => {
+            let first = words.next().ok_or(ParseError::Argument)?;
+            match first {
+                "arm" => return Ok(Command::CaptureArm),
+                "stop" => return Ok(Command::CaptureStop),
+                "read" => return Ok(Command::CaptureRead),
+                _ => {}
+            }
+            let mut config = CaptureConfig {
+                input: first.parse().map_err(|_| ParseError::Argument)?,
+                decimation: 1,
+                length: 256,
+                pre_trigger: 64,
+                trigger: Trigger::Edge { level: MAX_LEVEL / 2.0, rising: true },
+                mode: Mode::Auto,
+            };
+            for word in words {
+                let (key, value) = word.split_once('=').unwrap_or((word, ""));
+                let volts = || value.parse().map_err(|_| ParseError::Argument);
+                match key {
+                    "single" => config.mode = Mode::Single,
+                    "normal" => config.mode = Mode::Normal,
+                    "auto" => config.mode = Mode::Auto,
+                    "length" => config.length = value.parse().map_err(|_| ParseError::Argument)?,
+                    "pre" => config.pre_trigger = value.parse().map_err(|_| ParseError::Argument)?,
+                    "decimate" => config.decimation = value.parse().map_err(|_| ParseError::Argument)?,
+                    "rise" => config.trigger = Trigger::Edge { level: volts()?, rising: true },
+                    "fall" => config.trigger = Trigger::Edge { level: volts()?, rising: false },
+                    "above" => config.trigger = Trigger::Level { level: volts()?, above: true },
+                    "below" => config.trigger = Trigger::Level { level: volts()?, above: false },
+                    "window" => {
+                        let (low, high) = value.split_once(':').ok_or(ParseError::Argument)?;
+                        let number = |v: &str| v.parse().map_err(|_| ParseError::Argument);
+                        let (low, high) = (number(low)?, number(high)?);
+                        if low >= high {
+                            return Err(ParseError::Argument);
+                        }
+                        config.trigger = Trigger::Window { low, high };
+                    }
+                    _ => return Err(ParseError::Argument),
+                }
+            }
+            config.validate().map_err(ParseError::Capture)?;
+            Ok(Command::CaptureStart(config))
+        }
+        // stats [in<n>|out<n>], or stats reset [in<n>|out<n>]
+        "stats" => {
+            let mut word = words.next();
+            let reset = word == Some("reset");
+            if reset {
+                word = words.next();
+            }
+            let signal = match word {
+                Some(word) => Some(Signal::parse(word).ok_or(ParseError::Argument)?),
+                None => None,
+            };
+            Ok(if reset { Command::StatsReset(signal) } else { Command::Stats(signal) })
+        }
+        // energy, or energy reset [<channel>]
+        "energy" => match words.next() {
+            None => Ok(Command::Energy),
+            Some("reset") => match words.next() {
+                Some(channel) => channel.parse().ok()
+                    .filter(|c| *c < CHANNELS)
+                    .map(|c| Command::EnergyReset(Some(c)))
+                    .ok_or(ParseError::Argument),
+                None => Ok(Command::EnergyReset(None)),
+            },
+            Some(_) => Err(ParseError::Argument),
+        },
+        // sensor <index> none, or
+        // sensor <index> <volts per amp> <offset volts>
+        "sensor" => {
+            let index = words.next()
+                .and_then(|c| c.parse().ok())
+                .filter(|c| *c < SENSORS)
+                .ok_or(ParseError::Argument)?;
+            let first = words.next().ok_or(ParseError::Argument)?;
+            if first == "none" {
+                return Ok(Command::SensorUnfitted(index));
+            }
+            let volts_per_amp = first.parse().map_err(|_| ParseError::Argument)?;
+            let offset = words.next().and_then(|w| w.parse().ok()).ok_or(ParseError::Argument)?;
+            let sensor = CurrentSensor { volts_per_amp, offset, fitted: true };
+            if !sensor.is_valid() {
+                return Err(ParseError::Argument);
+            }
+            Ok(Command::Sensor { index, sensor })
+        }
+        // charge stop, or
+        // charge <channel> <limit amps> <cv volts> [term=<amps>] [timeout=<minutes>] [pre=<volts>:<amps>] [measure=<input>]
+        "charge" => {
+            let first = words.next().ok_or(ParseError::Argument)?;
+            if first == "stop" {
+                return Ok(Command::ChargeStop);
+            }
+            let channel: usize = first.parse().ok()
+                .filter(|c| *c < CHANNELS)
+                .ok_or(ParseError::Argument)?;
+            let mut number = || -> Result<f32, ParseError> {
+                words.next().and_then(|w| w.parse().ok()).ok_or(ParseError::Argument)
+            };
+            let (current_limit, cv_volts) = (number()?, number()?);
+            let mut config = ChargeConfig {
+                channel,
+                measure: (channel + 1) % CHANNELS,
+                current_limit,
+                cv_volts,
+                termination: current_limit / 10.0,
+                timeout_ms: 0,
+                precharge_volts: 0.0,
+                precharge_current: 0.0,
+            };
+            for word in words {
+                let (key, value) = word.split_once('=').ok_or(ParseError::Argument)?;
+                let number = |v: &str| v.parse::<f32>().map_err(|_| ParseError::Argument);
+                match key {
+                    "term" => config.termination = number(value)?,
+                    "timeout" => config.timeout_ms = (number(value)? * 60_000.0) as u32,
+                    "pre" => {
+                        let (volts, amps) = value.split_once(':').ok_or(ParseError::Argument)?;
+                        config.precharge_volts = number(volts)?;
+                        config.precharge_current = number(amps)?;
+                    }
+                    "measure" => config.measure = value.parse().map_err(|_| ParseError::Argument)?,
+                    _ => return Err(ParseError::Argument),
+                }
+            }
+            // Checked against the channel's limits when it starts
+            Ok(Command::ChargeStart(config))
+        }
+        // mppt stop, or
+        // mppt <channel> [step=<volts>] [period=<ms>] [voc=<seconds>] [fraction=<f>]
+        "mppt" => {
+            let first = words.next().ok_or(ParseError::Argument)?;
+            if first == "stop" {
+                return Ok(Command::MpptStop);
+            }
+            let mut config = MpptConfig {
+                channel: first.parse().map_err(|_| ParseError::Argument)?,
+                step: 0.1,
+                period_ms: 100,
+                voc_period_ms: 60_000,
+                fraction: 0.76,
+            };
+            for word in words {
+                let (key, value) = word.split_once('=').ok_or(ParseError::Argument)?;
+                let number = |v: &str| v.parse::<f32>().map_err(|_| ParseError::Argument);
+                match key {
+                    "step" => config.step = number(value)?,
+                    "period" => config.period_ms = value.parse().map_err(|_| ParseError::Argument)?,
+                    "voc" => config.voc_period_ms = (number(value)? * 1000.0) as u32,
+                    "fraction" => config.fraction = number(value)?,
+                    _ => return Err(ParseError::Argument),
+                }
+            }
+            if !config.is_valid() {
+                return Err(ParseError::Argument);
+            }
+            Ok(Command::MpptStart(config))
+        }
+        // source <side> <threshold> [hysteresis], or
+        // source priority <left|right|higher|last>
+        "source" => {
+            let first = words.next().ok_or(ParseError::Argument)?;
+            if first == "priority" {
+                return words.next()
+                    .and_then(SourcePriority::parse)
+                    .map(Command::SourcePriority)
+                    .ok_or(ParseError::Argument);
+            }
+            let side = first.parse().ok().filter(|c| *c < CHANNELS).ok_or(ParseError::Argument)?;
+            let threshold: f32 = words.next().and_then(|w| w.parse().ok()).ok_or(ParseError::Argument)?;
+            let hysteresis: f32 = match words.next() {
+                Some(word) => word.parse().map_err(|_| ParseError::Argument)?,
+                None => 0.0,
+            };
+            if !(0.0..=MAX_LEVEL).contains(&threshold) || !(0.0..=threshold).contains(&hysteresis) {
+                return Err(ParseError::Argument);
+            }
+            Ok(Command::Detection { side, detection: Detection { threshold, hysteresis } })
+        }
+        // battery <side> off, battery <side> ocv <cell volts>:<soc %> ..., or
+        // battery <side> <liion|lifepo4|lead|nimh> <cells> [cutoff=<volts>] [hyst=<volts>] [r=<ohms>]
+        "battery" => {
+            let side = words.next()
+                .and_then(|c| c.parse().ok())
+                .filter(|c| *c < CHANNELS)
+                .ok_or(ParseError::Argument)?;
+            let kind = words.next().ok_or(ParseError::Argument)?;
+            match kind {
+                "off" => return Ok(Command::Battery { side, profile: None }),
+                "ocv" => {
+                    let mut points = [Breakpoint { input: 0.0, output: 0.0 }; MAX_POINTS];
+                    let mut count = 0;
+                    for word in words {
+                        let point = points.get_mut(count).ok_or(ParseError::Table(TableError::TooManyPoints))?;
+                        let (volts, soc) = word.split_once(':').ok_or(ParseError::Argument)?;
+                        let soc: f32 = soc.parse().map_err(|_| ParseError::Argument)?;
+                        *point = Breakpoint {
+                            input: volts.parse().map_err(|_| ParseError::Argument)?,
+                            output: soc / 100.0,
+                        };
+                        count += 1;
+                    }
+                    let ocv = Table::new(&points[..count]).map_err(ParseError::Table)?;
+                    return Ok(Command::BatteryOcv { side, ocv });
+                }
+                _ => {}
+            }
+            let chemistry = Chemistry::parse(kind).ok_or(ParseError::Argument)?;
+            let cells = words.next().and_then(|w| w.parse().ok()).ok_or(ParseError::Argument)?;
+            let mut profile = BatteryProfile::new(chemistry, cells);
+            for word in words {
+                let (key, value) = word.split_once('=').ok_or(ParseError::Argument)?;
+                let value: f32 = value.parse().map_err(|_| ParseError::Argument)?;
+                match key {
+                    "cutoff" => profile.cutoff = value,
+                    "hyst" => profile.hysteresis = value,
+                    "r" => profile.resistance = value,
+                    _ => return Err(ParseError::Argument),
+                }
+            }
+            Ok(Command::Battery { side, profile: Some(profile) })
+        }
+        // fault clear
+        "fault" => match words.next() {
+            Some("clear") => Ok(Command::FaultClear),
+            _ => Err(ParseError::Argument),
+        },
+        "save" => Ok(Command::Save),
+        _ => Err(ParseError::Unknown),
+    }
+}
+
+fn parse_step(word: &str) -> Option<Step> {
+    let mut fields = word.split(':');
+    let channel = fields.next()?.parse().ok().filter(|c| *c < CHANNELS)?;
+    let delay_ms = fields.next()?.parse().ok()?;
+    let wait = match fields.next() {
+        Some(cond) => {
+            let (input, volts) = cond.strip_prefix("in")?.split_once('>')?;
+            Some(Condition {
+                input: input.parse().ok().filter(|i| *i < CHANNELS)?,
+                volts: volts.parse().ok()?,
+            })
+        }
+        None => None,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(Step { channel, delay_ms, wait })
+}
+
+fn parse_segment(word: &str) -> Option<Segment> {
+    let mut fields = word.split(':');
+    let duration_ms = fields.next()?.parse().ok().filter(|d| *d > 0)?;
+    let target = fields.next()?.parse().ok().filter(|v| (0.0..=MAX_LEVEL).contains(v))?;
+    let shape = match fields.next() {
+        None | Some("step") => Shape::Step,
+        Some("ramp") => Shape::Ramp,
+        Some(_) => return None,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(Segment { duration_ms, target, shape })
+}
@@ -0,0 +1,334 @@
+use heapless::Vec;
+
+// 320px / 6px wide glyphs
+// 240px wide at 6px a character, the console is drawn in portrait
+pub const COLUMNS: usize = 40;
+pub const HISTORY: usize = 64;
+
+const ESC: char = '\x1b';
+const MAX_PARAMS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attr {
+    // index into the 16 color ANSI palette
+    pub fg: u8,
+    pub bg: u8,
+    pub bold: bool,
+}
+
+impl Attr {
+    pub const DEFAULT: Attr = Attr { fg: 7, bg: 0, bold: false };
+}
+
+impl Default for Attr {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cell {
+    pub ch: u8,
+    pub attr: Attr,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Line {
+    cells: Vec<Cell, COLUMNS>,
+}
+
+impl Line {
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    /// Splits the line into runs of cells sharing the same attributes, yielding
+    /// the starting column of each run.
+    pub fn runs(&self) -> impl Iterator<Item=(usize, &[Cell])> {
+        let cells = &self.cells[..];
+        let mut start = 0;
+        core::iter::from_fn(move || {
+            if start >= cells.len() {
+                return None;
+            }
+            let attr = cells[start].attr;
+            let len = cells[start..].iter().take_while(|c| c.attr == attr).count();
+            let run = (start, &cells[start..start + len]);
+            start += len;
+            Some(run)
+        })
+    }
+
+    fn is_full(&self) -> bool {
+        self.cells.is_full()
+    }
+
+    fn clear(&mut self) {
+        self.cells.clear();
+    }
+}
+
+/// What a write touched, ordered so the largest change of a batch can be kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Change {
+    None,
+    Line,
+    Scroll,
+    Clear,
+}
+
+enum Escape {
+    None,
+    Esc,
+    // `index` is the parameter being read, it can run past the ones kept
+    Csi { params: [u16; MAX_PARAMS], index: usize },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn sgr(&self) -> &'static str {
+        match self {
+            Level::Debug => "\x1b[90m",
+            Level::Info => "\x1b[0m",
+            Level::Warn => "\x1b[1;33m",
+            Level::Error => "\x1b[1;31m",
+        }
+    }
+}
+
+/// Line ring buffer fed with text containing a subset of ANSI SGR codes
+/// (reset, bold, normal intensity, 8/16 foreground and background colors)
+/// and `ESC[2J` to clear.
+pub struct Console {
+    lines: [Line; HISTORY],
+    // index of the line being written
+    head: usize,
+    len: usize,
+    attr: Attr,
+    escape: Escape,
+    // lines scrolled back from the newest one
+    scroll: usize,
+    // lines started since boot, wrapping, so a view can tell how many are new
+    written: u32,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Self {
+            lines: [(); HISTORY].map(|_| Line::default()),
+            head: 0,
+            len: 1,
+            attr: Attr::DEFAULT,
+            escape: Escape::None,
+            scroll: 0,
+            written: 0,
+        }
+    }
+
+    pub fn write_str(&mut self, str: &str) -> Change {
+        str.chars().fold(Change::None, |change, c| change.max(self.write_char(c)))
+    }
+
+    pub fn write_char(&mut self, c: char) -> Change {
+        match self.escape {
+            Escape::None => {}
+            Escape::Esc => {
+                self.escape = if c == '[' {
+                    Escape::Csi { params: [0; MAX_PARAMS], index: 0 }
+                } else {
+                    Escape::None
+                };
+                return Change::None;
+            }
+            Escape::Csi { ref mut params, ref mut index } => {
+                match c {
+                    '0'..='9' => {
+                        // Parameters past the ones kept are dropped
+                        if let Some(param) = params.get_mut(*index) {
+                            *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                        }
+                        return Change::None;
+                    }
+                    ';' => {
+                        *index = index.saturating_add(1);
+                        return Change::None;
+                    }
+                    _ => {
+                        let params = *params;
+                        let count = (*index + 1).min(MAX_PARAMS);
+                        self.escape = Escape::None;
+                        return self.control(c, &params[..count]);
+                    }
+                }
+            }
+        }
+
+        match c {
+            ESC => {
+                self.escape = Escape::Esc;
+                Change::None
+            }
+            '\n' => self.new_line(),
+            '\r' => Change::None,
+            _ => {
+                let mut change = Change::Line;
+                if self.lines[self.head].is_full() {
+                    change = self.new_line();
+                }
+                let ch = if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'?' };
+                self.lines[self.head].cells.push(Cell { ch, attr: self.attr }).ok();
+                change
+            }
+        }
+    }
+
+    fn control(&mut self, command: char, params: &[u16]) -> Change {
+        match command {
+            'm' => {
+                params.iter().for_each(|&p| self.sgr(p));
+                Change::None
+            }
+            'J' if params[0] == 2 => {
+                self.clear();
+                Change::Clear
+            }
+            _ => Change::None,
+        }
+    }
+
+    fn sgr(&mut self, param: u16) {
+        match param {
+            0 => self.attr = Attr::DEFAULT,
+            1 => self.attr.bold = true,
+            22 => self.attr.bold = false,
+            30..=37 => self.attr.fg = (param - 30) as u8,
+            39 => self.attr.fg = Attr::DEFAULT.fg,
+            40..=47 => self.attr.bg = (param - 40) as u8,
+            49 => self.attr.bg = Attr::DEFAULT.bg,
+            90..=97 => self.attr.fg = (param - 90) as u8 + 8,
+            100..=107 => self.attr.bg = (param - 100) as u8 + 8,
+            _ => {}
+        }
+    }
+
+    fn new_line(&mut self) -> Change {
+        self.head = (self.head + 1) % HISTORY;
+        self.lines[self.head].clear();
+        self.len = (self.len + 1).min(HISTORY);
+        self.written = self.written.wrapping_add(1);
+        if self.scroll > 0 {
+            // keep the scrolled back view in place
+            self.scroll = (self.scroll + 1).min(self.len - 1);
+            Change::None
+        } else {
+            Change::Scroll
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.iter_mut().for_each(Line::clear);
+        self.head = 0;
+        self.len = 1;
+        self.scroll = 0;
+    }
+
+    /// Returns the `rows` lines in view, oldest first.
+    pub fn visible(&self, rows: usize) -> impl Iterator<Item=&Line> {
+        let newest = self.len - 1 - self.scroll;
+        let count = rows.min(newest + 1);
+        let oldest = newest + 1 - count;
+        (oldest..=newest).map(move |i| &self.lines[(self.head + HISTORY - (self.len - 1 - i)) % HISTORY])
+    }
+
+    /// Row of the line being written within a view of `rows` lines, if visible.
+    pub fn current_row(&self, rows: usize) -> Option<usize> {
+        if self.scroll > 0 {
+            return None;
+        }
+        Some((self.len - 1).min(rows - 1))
+    }
+
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    pub fn current(&self) -> &Line {
+        &self.lines[self.head]
+    }
+
+    pub fn scroll_back(&mut self, lines: usize) -> bool {
+        let scroll = (self.scroll + lines).min(self.len - 1);
+        core::mem::replace(&mut self.scroll, scroll) != scroll
+    }
+
+    pub fn scroll_forward(&mut self, lines: usize) -> bool {
+        let scroll = self.scroll.saturating_sub(lines);
+        core::mem::replace(&mut self.scroll, scroll) != scroll
+    }
+
+    pub fn is_scrolled(&self) -> bool {
+        self.scroll > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr_after(text: &str) -> Attr {
+        let mut console = Console::new();
+        console.write_str(text);
+        console.write_char('x');
+        console.current().cells()[0].attr
+    }
+
+    #[test]
+    fn sgr_params_apply_in_order() {
+        assert_eq!(attr_after("\x1b[1;31;44m"), Attr { fg: 1, bg: 4, bold: true });
+        assert_eq!(attr_after("\x1b[1;31m\x1b[0m"), Attr::DEFAULT);
+    }
+
+    #[test]
+    fn params_past_the_limit_are_dropped() {
+        // The fifth parameter must not run into the fourth
+        assert_eq!(attr_after("\x1b[0;0;0;32;1m"), Attr { fg: 2, ..Attr::DEFAULT });
+        assert_eq!(attr_after("\x1b[0;0;0;32;1;1;1m"), Attr { fg: 2, ..Attr::DEFAULT });
+    }
+
+    #[test]
+    fn clear_empties_the_history() {
+        let mut console = Console::new();
+        console.write_str("one\ntwo\n");
+        assert_eq!(console.write_str("\x1b[2J"), Change::Clear);
+        assert_eq!(console.visible(10).count(), 1);
+        assert!(console.current().cells().is_empty());
+    }
+
+    #[test]
+    fn scrollback_stays_put_while_lines_arrive() {
+        let mut console = Console::new();
+        console.write_str("a\nb\nc");
+        assert!(console.scroll_back(1));
+        assert_eq!(console.write_str("\nd"), Change::Line);
+        let first = console.visible(2).next().unwrap().cells()[0].ch;
+        assert_eq!(first, b'a');
+    }
+
+    #[test]
+    fn counts_every_line_started() {
+        let mut console = Console::new();
+        console.write_str("a\nb\nc");
+        assert_eq!(console.written(), 2);
+        // Wrapped and scrolled back lines count too
+        console.scroll_back(1);
+        console.write_str(&"x".repeat(COLUMNS + 1));
+        assert_eq!(console.written(), 3);
+    }
+}
@@ -0,0 +1,80 @@
+/// What releases a control run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    // Every control period from the monotonic
+    Polled,
+    // Every `decimation` completed ADC0 conversions; the polled task still
+    // takes over if conversions stop arriving.
+    Adc { decimation: u16 },
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Trigger::Adc { decimation: 16 }
+    }
+}
+
+impl Trigger {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Trigger::Polled => "polled",
+            Trigger::Adc { .. } => "adc",
+        }
+    }
+
+    pub fn parse(name: &str, arg: Option<&str>) -> Option<Self> {
+        match name {
+            "polled" => Some(Trigger::Polled),
+            // 0 would never release a run
+            "adc" => match arg?.parse().ok()? {
+                0 => None,
+                decimation => Some(Trigger::Adc { decimation }),
+            },
+            _ => None,
+        }
+    }
+}
+
+pub struct Decimator {
+    count: u16,
+}
+
+impl Decimator {
+    pub const fn new() -> Self {
+        Self { count: 0 }
+    }
+
+    /// Counts a sample, true once every `ratio` samples.
+    pub fn sample(&mut self, ratio: u16) -> bool {
+        self.count += 1;
+        if self.count >= ratio.max(1) {
+            self.count = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_both_triggers() {
+        assert_eq!(Trigger::parse("polled", None), Some(Trigger::Polled));
+        assert_eq!(Trigger::parse("adc", Some("4")), Some(Trigger::Adc { decimation: 4 }));
+        for (name, arg) in [("adc", None), ("adc", Some("0")), ("adc", Some("70000")), ("timer", None)] {
+            assert_eq!(Trigger::parse(name, arg), None, "{} {:?}", name, arg);
+        }
+    }
+
+    #[test]
+    fn decimator_releases_every_ratio_samples() {
+        let mut decimator = Decimator::new();
+        let released: std::vec::Vec<bool> = (0..6).map(|_| decimator.sample(3)).collect();
+        assert_eq!(released, [false, false, true, false, false, true]);
+        // 0 counts as 1 rather than never
+        assert!(decimator.sample(0));
+    }
+}
@@ -1,18 +1,210 @@
+use cortex_m::peripheral::DWT;
 use wio_terminal::aliases::{DAC0Reset, DAC1Reset};
-use wio_terminal::hal::adc::Adc;
 use wio_terminal::hal::clock::GenericClockController;
-use wio_terminal::hal::gpio::{Alternate, B, Pin};
+use wio_terminal::hal::gpio::B;
+use wio_terminal::hal::time::Hertz;
 use wio_terminal::pac::{DAC, MCLK};
 use wio_terminal::pac::gclk::genctrl::SRC_A::DFLL;
 use wio_terminal::pac::gclk::pchctrl::GEN_A;
-use wio_terminal::hal::ehal::digital::v2::OutputPin;
-use crate::delay::cycle_delay_ms;
 use crate::state::OutputValues;
 
+const DFLL_FREQ: u32 = 48_000_000;
+const VDDANA: f32 = 3.3;
+// Startup takes a few µs per the datasheet, leave plenty of margin
+const TIMEOUT_US: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Dac0 = 0,
+    Dac1 = 1,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 2] = [Channel::Dac0, Channel::Dac1];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wait {
+    Reset,
+    Enable,
+    Ready(Channel),
+    Conversion(Channel),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reference {
+    // External reference on VREFA, unbuffered (reset default) or buffered
+    VrefAU { volts: f32 },
+    VrefAB { volts: f32 },
+    Vddana,
+    // Bandgap from SUPC.VREF, 1.0V unless SUPC is set up otherwise
+    Internal,
+}
+
+/// CCTRL, must be set for the DAC clock rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Current {
+    CC100K,
+    CC1M,
+    CC12M,
+}
+
+impl Current {
+    pub fn max_clock(&self) -> u32 {
+        match self {
+            Current::CC100K => 1_200_000,
+            Current::CC1M => 6_000_000,
+            Current::CC12M => 12_000_000,
+        }
+    }
+}
+
+/// Interpolation filter oversampling ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Oversampling {
+    X1,
+    X2,
+    X4,
+    X8,
+    X16,
+    X32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    ClockTooFast { clock: u32, max: u32 },
+    ZeroDivider,
+    DitherWithoutOversampling,
+    ReferenceOutOfRange,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DacConfig {
+    reference: Reference,
+    current: Current,
+    // GCLK_DAC = DFLL / clock_divider
+    clock_divider: u16,
+    dither: bool,
+    oversampling: Oversampling,
+    // DAC1 output is the inverse of DAC0, only writes to Dac0 are used
+    differential: bool,
+}
+
+impl DacConfig {
+    pub const fn new() -> Self {
+        Self {
+            reference: Reference::VrefAU { volts: VDDANA },
+            current: Current::CC12M,
+            clock_divider: 4,
+            dither: false,
+            oversampling: Oversampling::X1,
+            differential: false,
+        }
+    }
+
+    pub const fn reference(mut self, reference: Reference) -> Self {
+        self.reference = reference;
+        self
+    }
+
+    pub const fn current(mut self, current: Current) -> Self {
+        self.current = current;
+        self
+    }
+
+    pub const fn clock_divider(mut self, divider: u16) -> Self {
+        self.clock_divider = divider;
+        self
+    }
+
+    pub const fn dither(mut self, dither: bool) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    pub const fn oversampling(mut self, oversampling: Oversampling) -> Self {
+        self.oversampling = oversampling;
+        self
+    }
+
+    pub const fn differential(mut self, differential: bool) -> Self {
+        self.differential = differential;
+        self
+    }
+
+    pub fn clock(&self) -> u32 {
+        DFLL_FREQ / self.clock_divider.max(1) as u32
+    }
+
+    pub fn reference_volts(&self) -> f32 {
+        match self.reference {
+            Reference::VrefAU { volts } | Reference::VrefAB { volts } => volts,
+            Reference::Vddana => VDDANA,
+            Reference::Internal => 1.0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.clock_divider == 0 {
+            return Err(ConfigError::ZeroDivider);
+        }
+        if self.clock() > self.current.max_clock() {
+            return Err(ConfigError::ClockTooFast { clock: self.clock(), max: self.current.max_clock() });
+        }
+        if self.dither && self.oversampling == Oversampling::X1 {
+            return Err(ConfigError::DitherWithoutOversampling);
+        }
+        if let Reference::VrefAU { volts } | Reference::VrefAB { volts } = self.reference {
+            if !(1.0..=VDDANA).contains(&volts) {
+                return Err(ConfigError::ReferenceOutOfRange);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for DacConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Completed writes on one channel, from DAC_EMPTY.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteStats {
+    pub completed: u32,
+    // from the write until the channel took it, in microseconds
+    pub latency_last: u32,
+    pub latency_max: u32,
+}
+
+impl WriteStats {
+    pub fn record(&mut self, latency_us: u32) {
+        self.completed = self.completed.wrapping_add(1);
+        self.latency_last = latency_us;
+        self.latency_max = self.latency_max.max(latency_us);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DacError {
+    Config(ConfigError),
+    Timeout(Wait),
+}
+
 pub struct Dac {
     dac: DAC,
-    dac0_value: u16,
-    dac1_value: u16,
+    config: DacConfig,
+    values: [u16; 2],
+    // written but the data buffer hasn't been taken yet
+    pending: [bool; 2],
+    // DWT cycle count at the last write
+    written_at: [u32; 2],
+    // core clock, for the timeouts and latencies
+    cycles_per_us: u32,
+    listening: bool,
+    // writes that replaced one still pending
+    overwritten: u32,
 }
 
 impl Dac {
@@ -23,58 +215,171 @@ impl Dac {
         mclk: &mut MCLK,
         clocks: &mut GenericClockController,
         gclk: GEN_A,
-    ) -> Self {
+        config: DacConfig,
+    ) -> Result<Self, DacError> {
+        config.validate().map_err(DacError::Config)?;
+        let cycles_per_us = Hertz::from(clocks.gclk0()).0 / 1_000_000;
+
         mclk.apbdmask.modify(|_, w| w.dac_().set_bit());
-        let dac_clock = clocks.configure_gclk_divider_and_source(gclk, 1, DFLL, false)
+        let dac_clock = clocks.configure_gclk_divider_and_source(gclk, config.clock_divider, DFLL, false)
             .expect("dac clock setup failed");
         clocks.dac(&dac_clock).expect("dac clock setup failed");
 
         dac.ctrla.modify(|_, w| w.swrst().set_bit());
 
-        while dac.ctrla.read().swrst().bit_is_set() || dac.syncbusy.read().swrst().bit_is_set() {}
+        wait_for(Wait::Reset, cycles_per_us, || dac.ctrla.read().swrst().bit_is_clear() && dac.syncbusy.read().swrst().bit_is_clear())?;
+
+        dac.ctrlb.write(|w| {
+            let w = match config.reference {
+                Reference::VrefAU { .. } => w.refsel().vrefau(),
+                Reference::VrefAB { .. } => w.refsel().vrefab(),
+                Reference::Vddana => w.refsel().vddana(),
+                Reference::Internal => w.refsel().intref(),
+            };
+            w.diff().bit(config.differential)
+        });
 
+        // Differential mode uses both channels with the same settings
         dac.dacctrl.iter()
-            .for_each(|ctrl| ctrl.modify(|_, w|
-                w.enable().set_bit()
+            .for_each(|ctrl| ctrl.modify(|_, w| {
+                let w = w.enable().set_bit()
                     .refresh().refresh_1()
-            ));
+                    .dither().bit(config.dither);
+                let w = match config.current {
+                    Current::CC100K => w.cctrl().cc100k(),
+                    Current::CC1M => w.cctrl().cc1m(),
+                    Current::CC12M => w.cctrl().cc12m(),
+                };
+                match config.oversampling {
+                    Oversampling::X1 => w.osr().osr_1(),
+                    Oversampling::X2 => w.osr().osr_2(),
+                    Oversampling::X4 => w.osr().osr_4(),
+                    Oversampling::X8 => w.osr().osr_8(),
+                    Oversampling::X16 => w.osr().osr_16(),
+                    Oversampling::X32 => w.osr().osr_32(),
+                }
+            }));
 
         dac.ctrla.modify(|_, w| w.enable().set_bit());
 
-        while dac.syncbusy.read().enable().bit_is_set() {}
+        wait_for(Wait::Enable, cycles_per_us, || dac.syncbusy.read().enable().bit_is_clear())?;
         dac0_pin.into_alternate::<B>();
         dac1_pin.into_alternate::<B>();
 
-        while dac.status.read().ready0().bit_is_clear() || dac.status.read().ready1().bit_is_clear() {}
-
-        Self {
+        let dac = Self {
             dac,
-            dac0_value: 0,
-            dac1_value: 0,
+            config,
+            values: [0; 2],
+            pending: [false; 2],
+            written_at: [0; 2],
+            cycles_per_us,
+            listening: false,
+            overwritten: 0,
+        };
+        for channel in Channel::ALL {
+            wait_for(Wait::Ready(channel), cycles_per_us, || dac.is_ready(channel))?;
+        }
+        Ok(dac)
+    }
+
+    /// Starts a conversion of `value` on `channel` and returns right away.
+    /// A write while the previous one is still pending replaces it. Writes
+    /// to Dac1 are ignored in differential mode.
+    pub fn write(&mut self, channel: Channel, value: u16) {
+        if self.config.differential && channel == Channel::Dac1 {
+            return;
+        }
+        let idx = channel as usize;
+        if self.is_busy(channel) {
+            self.overwritten = self.overwritten.wrapping_add(1);
+        }
+        self.values[idx] = value;
+        self.pending[idx] = true;
+        self.written_at[idx] = DWT::cycle_count();
+        self.dac.data[idx].write(|w| unsafe { w.data().bits(value) });
+        if self.listening {
+            match channel {
+                Channel::Dac0 => self.dac.intenset.write(|w| w.empty0().set_bit()),
+                Channel::Dac1 => self.dac.intenset.write(|w| w.empty1().set_bit()),
+            }
         }
     }
 
-    pub fn update(&self) {
-        self.dac.data[0].write(|w| unsafe { w.data().bits(self.dac0_value) });
-        self.dac.data[1].write(|w| unsafe { w.data().bits(self.dac1_value) });
-        while self.dac.status.read().eoc0().bit_is_clear() || self.dac.status.read().eoc1().bit_is_clear() {}
+    pub fn write_output(&mut self, output: &OutputValues) {
+        for (channel, value) in Channel::ALL.into_iter().zip(output.raw) {
+            self.write(channel, value);
+        }
     }
 
-    pub fn set_adc0_desired(&mut self, value: u16) {
-        self.dac0_value = value;
+    pub fn config(&self) -> &DacConfig {
+        &self.config
     }
 
-    pub fn set_adc1_desired(&mut self, value: u16) {
-        self.dac1_value = value;
+    pub fn value(&self, channel: Channel) -> u16 {
+        self.values[channel as usize]
     }
 
-    pub fn set(&mut self, dac0: u16, dac1: u16) {
-        self.dac0_value = dac0;
-        self.dac1_value = dac1;
+    pub fn is_ready(&self, channel: Channel) -> bool {
+        let status = self.dac.status.read();
+        match channel {
+            Channel::Dac0 => status.ready0().bit_is_set(),
+            Channel::Dac1 => status.ready1().bit_is_set(),
+        }
     }
 
-    pub fn set_output(&mut self, output: &OutputValues) {
-        self.dac0_value = output.dac0;
-        self.dac1_value = output.dac1;
+    pub fn is_busy(&self, channel: Channel) -> bool {
+        let status = self.dac.status.read();
+        let converted = match channel {
+            Channel::Dac0 => status.eoc0().bit_is_set(),
+            Channel::Dac1 => status.eoc1().bit_is_set(),
+        };
+        self.pending[channel as usize] && !converted
+    }
+
+    pub fn overwritten(&self) -> u32 {
+        self.overwritten
+    }
+
+    /// Blocks until the last write to `channel` has been converted.
+    pub fn wait(&self, channel: Channel) -> Result<(), DacError> {
+        wait_for(Wait::Conversion(channel), self.cycles_per_us, || !self.is_busy(channel))
+    }
+
+    /// Raises DAC_EMPTY_0/1 once each channel has taken its written value.
+    pub fn listen(&mut self) {
+        self.listening = true;
+        self.dac.intenset.write(|w| w.empty0().set_bit().empty1().set_bit());
+    }
+
+    /// Handles a DAC_EMPTY interrupt. When `channel` finished a pending
+    /// write, returns the microseconds it took.
+    pub fn service_interrupt(&mut self, channel: Channel) -> Option<u32> {
+        let flags = self.dac.intflag.read();
+        let empty = match channel {
+            Channel::Dac0 => flags.empty0().bit_is_set(),
+            Channel::Dac1 => flags.empty1().bit_is_set(),
+        };
+        if !empty {
+            return None;
+        }
+
+        // The flag stays set while the buffer is empty, so mask it until the next write
+        match channel {
+            Channel::Dac0 => self.dac.intenclr.write(|w| w.empty0().set_bit()),
+            Channel::Dac1 => self.dac.intenclr.write(|w| w.empty1().set_bit()),
+        }
+        let idx = channel as usize;
+        core::mem::replace(&mut self.pending[idx], false)
+            .then(|| DWT::cycle_count().wrapping_sub(self.written_at[idx]) / self.cycles_per_us)
+    }
+}
+
+fn wait_for(what: Wait, cycles_per_us: u32, mut done: impl FnMut() -> bool) -> Result<(), DacError> {
+    let start = DWT::cycle_count();
+    while !done() {
+        if DWT::cycle_count().wrapping_sub(start) > TIMEOUT_US * cycles_per_us {
+            return Err(DacError::Timeout(what));
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,154 @@
+use crate::logics::{State, CHANNELS};
+
+// Longest gap integrated in one go, so a stalled loop doesn't count the
+// whole stall at the last reading
+const MAX_STEP_MS: u32 = 100;
+
+/// What one direction has transferred. Kept in f64, an f32 total stops
+/// taking in a 10ms step long before a day's worth of watt-hours.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Counter {
+    pub watt_seconds: f64,
+    pub amp_seconds: f64,
+    // time with the output on
+    pub active_ms: u64,
+}
+
+impl Counter {
+    pub fn watt_hours(&self) -> f64 {
+        self.watt_seconds / 3600.0
+    }
+
+    pub fn amp_hours(&self) -> f64 {
+        self.amp_seconds / 3600.0
+    }
+}
+
+/// Energy, charge and active time per channel, integrated over control runs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counters {
+    pub channels: [Counter; CHANNELS],
+    last_ms: Option<u32>,
+}
+
+impl Counters {
+    pub fn new(channels: [Counter; CHANNELS]) -> Self {
+        Self { channels, last_ms: None }
+    }
+
+    /// Adds the time since the previous call at `state`'s outputs. A
+    /// channel without a current sensor only counts active time.
+    pub fn integrate(&mut self, state: &State, now_ms: u32) {
+        let Some(last) = self.last_ms.replace(now_ms) else {
+            return;
+        };
+        let dt_ms = now_ms.wrapping_sub(last).min(MAX_STEP_MS);
+        let dt_s = dt_ms as f64 / 1000.0;
+        for (counter, side) in self.channels.iter_mut().zip(state.channels.iter()) {
+            if side.real_output <= 0.0 {
+                continue;
+            }
+            counter.active_ms += dt_ms as u64;
+            if let (Some(amps), Some(watts)) = (side.current, side.power()) {
+                counter.amp_seconds += amps as f64 * dt_s;
+                counter.watt_seconds += watts as f64 * dt_s;
+            }
+        }
+    }
+
+    /// Clears one channel, or all of them for None.
+    pub fn reset(&mut self, channel: Option<usize>) {
+        match channel {
+            Some(channel) => self.channels[channel] = Counter::default(),
+            None => self.channels = Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (real output, amps) per channel
+    fn state(outputs: [(f32, Option<f32>); CHANNELS]) -> State {
+        let mut state = State::default();
+        for (side, (real_output, current)) in state.channels.iter_mut().zip(outputs) {
+            side.real_output = real_output;
+            side.current = current;
+        }
+        state
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn first_call_only_starts_the_clock() {
+        let mut counters = Counters::default();
+        counters.integrate(&state([(10.0, Some(2.0)); CHANNELS]), 5000);
+        assert_eq!(counters.channels, [Counter::default(); CHANNELS]);
+    }
+
+    #[test]
+    fn integrates_each_direction_on_its_own() {
+        let mut counters = Counters::default();
+        // Left to right for a second at 10V 2A, then right to left for two at 5V 1A
+        let left = state([(10.0, Some(2.0)), (0.0, Some(0.0))]);
+        let right = state([(0.0, Some(0.0)), (5.0, Some(1.0))]);
+        for now in (0..=1000).step_by(10) {
+            counters.integrate(&left, now);
+        }
+        for now in (1010..=3000).step_by(10) {
+            counters.integrate(&right, now);
+        }
+        let [l, r] = counters.channels;
+        assert!(close(l.watt_seconds, 20.0) && close(l.amp_seconds, 2.0), "{:?}", l);
+        assert_eq!(l.active_ms, 1000);
+        // Each step counts at the outputs it ends on, so 1000 to 1010 is the right's
+        assert!(close(r.watt_seconds, 10.0) && close(r.amp_seconds, 2.0), "{:?}", r);
+        assert_eq!(r.active_ms, 2000);
+        assert!(close(l.watt_hours(), l.watt_seconds / 3600.0) && close(r.amp_hours(), r.amp_seconds / 3600.0));
+    }
+
+    #[test]
+    fn long_gaps_count_as_one_step() {
+        let mut counters = Counters::default();
+        let on = state([(10.0, Some(1.0)), (0.0, None)]);
+        counters.integrate(&on, 0);
+        counters.integrate(&on, 5000);
+        assert_eq!(counters.channels[0].active_ms, MAX_STEP_MS as u64);
+        assert!(close(counters.channels[0].watt_seconds, 10.0 * MAX_STEP_MS as f64 / 1000.0));
+        // Across the counter wrapping
+        let mut counters = Counters::default();
+        counters.integrate(&on, u32::MAX - 4);
+        counters.integrate(&on, 5);
+        assert_eq!(counters.channels[0].active_ms, 10);
+    }
+
+    #[test]
+    fn without_a_sensor_only_time_counts() {
+        let mut counters = Counters::default();
+        let on = state([(10.0, None), (0.0, None)]);
+        counters.integrate(&on, 0);
+        counters.integrate(&on, 50);
+        assert_eq!(counters.channels[0], Counter { watt_seconds: 0.0, amp_seconds: 0.0, active_ms: 50 });
+        assert_eq!(counters.channels[1], Counter::default());
+    }
+
+    #[test]
+    fn reset_clears_one_or_all() {
+        let mut counters = Counters::default();
+        let on = state([(10.0, Some(1.0)), (5.0, Some(1.0))]);
+        counters.integrate(&on, 0);
+        counters.integrate(&on, 100);
+        counters.reset(Some(0));
+        assert_eq!(counters.channels[0], Counter::default());
+        assert_eq!(counters.channels[1].active_ms, 100);
+        // The clock keeps running through a reset
+        counters.integrate(&on, 150);
+        assert_eq!(counters.channels[0].active_ms, 50);
+        counters.reset(None);
+        assert_eq!(counters.channels, [Counter::default(); CHANNELS]);
+    }
+}
@@ -0,0 +1,389 @@
+//! Small expression language for channel outputs, e.g.
+//! `min(in1 * 0.5 + 1, set0)`.
+//!
+//! Source is compiled once into a fixed size stack program. There are no
+//! loops or calls, so evaluating it takes at most `MAX_OPS` steps and never
+//! allocates. Only depends on `core` and heapless so the fuzz harness can
+//! build it on the host.
+
+use heapless::{String, Vec};
+
+pub const MAX_OPS: usize = 64;
+// Kept with the program so it can be saved and shown again
+pub const MAX_SOURCE: usize = 128;
+pub const MAX_STACK: usize = 16;
+// Bounds the parser's recursion, not the program
+const MAX_NESTING: usize = 16;
+
+/// Values a program can read.
+pub struct Env<'a> {
+    // in0, in1, ...
+    pub inputs: &'a [f32],
+    // set0, set1, ...
+    pub setpoints: &'a [f32],
+    // t, seconds
+    pub time: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Const(f32),
+    Input(u8),
+    Setpoint(u8),
+    Time,
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    Abs,
+    Min,
+    Max,
+    // x lo hi
+    Clamp,
+    // cond then else, both sides are always evaluated
+    Select,
+}
+
+impl Op {
+    // Net change in stack depth
+    fn effect(&self) -> isize {
+        match self {
+            Op::Const(_) | Op::Input(_) | Op::Setpoint(_) | Op::Time => 1,
+            Op::Neg | Op::Abs => 0,
+            Op::Clamp | Op::Select => -2,
+            _ => -1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExprError {
+    // byte offset into the source
+    Syntax(usize),
+    UnknownName(usize),
+    // wrong number of function arguments
+    Arity(usize),
+    TooLong,
+    TooDeep,
+}
+
+impl ExprError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExprError::Syntax(_) => "syntax error",
+            ExprError::UnknownName(_) => "unknown name",
+            ExprError::Arity(_) => "wrong argument count",
+            ExprError::TooLong => "expression too long",
+            ExprError::TooDeep => "expression too deep",
+        }
+    }
+}
+
+/// A compiled expression. The default program is empty and evaluates to 0.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    code: Vec<Op, MAX_OPS>,
+    source: String<MAX_SOURCE>,
+}
+
+impl Program {
+    /// Compiles `src` for `channels` inputs and setpoints.
+    pub fn compile(src: &str, channels: usize) -> Result<Self, ExprError> {
+        let mut parser = Parser {
+            src: src.as_bytes(),
+            pos: 0,
+            channels,
+            code: Vec::new(),
+            depth: 0,
+            nesting: 0,
+        };
+        parser.expr()?;
+        parser.skip_space();
+        if parser.pos != parser.src.len() {
+            return Err(ExprError::Syntax(parser.pos));
+        }
+        let mut source = String::new();
+        source.push_str(src.trim()).map_err(|_| ExprError::TooLong)?;
+        Ok(Self { code: parser.code, source })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// What the program was compiled from, trimmed.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn eval(&self, env: &Env) -> f32 {
+        let mut stack = [0f32; MAX_STACK];
+        let mut sp = 0;
+        for op in self.code.iter() {
+            match *op {
+                Op::Const(v) => {
+                    stack[sp] = v;
+                    sp += 1;
+                }
+                Op::Input(i) => {
+                    stack[sp] = env.inputs.get(i as usize).copied().unwrap_or(0.0);
+                    sp += 1;
+                }
+                Op::Setpoint(i) => {
+                    stack[sp] = env.setpoints.get(i as usize).copied().unwrap_or(0.0);
+                    sp += 1;
+                }
+                Op::Time => {
+                    stack[sp] = env.time;
+                    sp += 1;
+                }
+                Op::Neg => stack[sp - 1] = -stack[sp - 1],
+                Op::Abs => {
+                    let v = stack[sp - 1];
+                    stack[sp - 1] = if v < 0.0 { -v } else { v };
+                }
+                Op::Clamp => {
+                    sp -= 2;
+                    stack[sp - 1] = stack[sp - 1].max(stack[sp]).min(stack[sp + 1]);
+                }
+                Op::Select => {
+                    sp -= 2;
+                    stack[sp - 1] = if stack[sp - 1] != 0.0 { stack[sp] } else { stack[sp + 1] };
+                }
+                binary => {
+                    sp -= 1;
+                    let (a, b) = (stack[sp - 1], stack[sp]);
+                    stack[sp - 1] = match binary {
+                        Op::Add => a + b,
+                        Op::Sub => a - b,
+                        Op::Mul => a * b,
+                        Op::Div => a / b,
+                        Op::Lt => (a < b) as u8 as f32,
+                        Op::Le => (a <= b) as u8 as f32,
+                        Op::Gt => (a > b) as u8 as f32,
+                        Op::Ge => (a >= b) as u8 as f32,
+                        Op::Eq => (a == b) as u8 as f32,
+                        Op::Ne => (a != b) as u8 as f32,
+                        Op::Min => a.min(b),
+                        Op::Max => a.max(b),
+                        _ => unreachable!(),
+                    };
+                }
+            }
+        }
+        if sp == 0 { 0.0 } else { stack[sp - 1] }
+    }
+}
+
+// expr    := sum (cmp sum)?
+// sum     := product (("+" | "-") product)*
+// product := unary (("*" | "/") unary)*
+// unary   := "-" unary | atom
+// atom    := number | name | name "(" expr ("," expr)* ")" | "(" expr ")"
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    channels: usize,
+    code: Vec<Op, MAX_OPS>,
+    // stack depth the program has reached so far
+    depth: usize,
+    nesting: usize,
+}
+
+impl Parser<'_> {
+    fn emit(&mut self, op: Op) -> Result<(), ExprError> {
+        self.code.push(op).map_err(|_| ExprError::TooLong)?;
+        self.depth = (self.depth as isize + op.effect()) as usize;
+        if self.depth > MAX_STACK {
+            return Err(ExprError::TooDeep);
+        }
+        Ok(())
+    }
+
+    fn skip_space(&mut self) {
+        while self.src.get(self.pos).map_or(false, |c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_space();
+        self.src.get(self.pos).copied()
+    }
+
+    // Consumes `token` if it's next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.src[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ExprError> {
+        if self.eat(token) { Ok(()) } else { Err(ExprError::Syntax(self.pos)) }
+    }
+
+    fn expr(&mut self) -> Result<(), ExprError> {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            return Err(ExprError::TooDeep);
+        }
+        self.sum()?;
+        // Two character operators first so "<=" isn't taken as "<"
+        const CMP: [(&str, Op); 6] = [
+            ("<=", Op::Le), (">=", Op::Ge), ("==", Op::Eq), ("!=", Op::Ne), ("<", Op::Lt), (">", Op::Gt),
+        ];
+        if let Some((_, op)) = CMP.iter().find(|(token, _)| self.eat(token)) {
+            self.sum()?;
+            self.emit(*op)?;
+        }
+        self.nesting -= 1;
+        Ok(())
+    }
+
+    fn sum(&mut self) -> Result<(), ExprError> {
+        self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some(b'+') => Op::Add,
+                Some(b'-') => Op::Sub,
+                _ => return Ok(()),
+            };
+            self.pos += 1;
+            self.product()?;
+            self.emit(op)?;
+        }
+    }
+
+    fn product(&mut self) -> Result<(), ExprError> {
+        self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Some(b'*') => Op::Mul,
+                Some(b'/') => Op::Div,
+                _ => return Ok(()),
+            };
+            self.pos += 1;
+            self.unary()?;
+            self.emit(op)?;
+        }
+    }
+
+    fn unary(&mut self) -> Result<(), ExprError> {
+        if self.eat("-") {
+            self.nesting += 1;
+            if self.nesting > MAX_NESTING {
+                return Err(ExprError::TooDeep);
+            }
+            self.unary()?;
+            self.nesting -= 1;
+            return self.emit(Op::Neg);
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<(), ExprError> {
+        let start = self.pos;
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                self.expr()?;
+                self.expect(")")
+            }
+            Some(c) if c.is_ascii_digit() || c == b'.' => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => self.name(),
+            _ => Err(ExprError::Syntax(start)),
+        }
+    }
+
+    fn number(&mut self) -> Result<(), ExprError> {
+        let (src, start) = (self.src, self.pos);
+        while src.get(self.pos).map_or(false, |c| c.is_ascii_digit() || *c == b'.') {
+            self.pos += 1;
+        }
+        let text = core::str::from_utf8(&src[start..self.pos]).map_err(|_| ExprError::Syntax(start))?;
+        let value = text.parse().map_err(|_| ExprError::Syntax(start))?;
+        self.emit(Op::Const(value))
+    }
+
+    fn name(&mut self) -> Result<(), ExprError> {
+        let (src, start) = (self.src, self.pos);
+        while src.get(self.pos).map_or(false, |c| c.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        let name = &src[start..self.pos];
+
+        if self.peek() == Some(b'(') {
+            let (op, arity) = match name {
+                b"abs" => (Op::Abs, 1),
+                b"min" => (Op::Min, 2),
+                b"max" => (Op::Max, 2),
+                b"clamp" => (Op::Clamp, 3),
+                b"if" => (Op::Select, 3),
+                _ => return Err(ExprError::UnknownName(start)),
+            };
+            self.pos += 1;
+            let mut args = 0;
+            loop {
+                self.expr()?;
+                args += 1;
+                if !self.eat(",") {
+                    break;
+                }
+            }
+            self.expect(")")?;
+            if args != arity {
+                return Err(ExprError::Arity(start));
+            }
+            return self.emit(op);
+        }
+
+        let channels = self.channels;
+        let channel = |prefix: &[u8]| -> Option<u8> {
+            let index = name.strip_prefix(prefix)?;
+            let index: u8 = core::str::from_utf8(index).ok()?.parse().ok()?;
+            Some(index).filter(|i| (*i as usize) < channels)
+        };
+        let op = if name == b"t" {
+            Op::Time
+        } else if let Some(i) = channel(b"in") {
+            Op::Input(i)
+        } else if let Some(i) = channel(b"set") {
+            Op::Setpoint(i)
+        } else {
+            return Err(ExprError::UnknownName(start));
+        };
+        self.emit(op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_trimmed_source() {
+        let program = Program::compile("  min(in1 * 0.5 + 1, set0) ", 2).unwrap();
+        assert_eq!(program.source(), "min(in1 * 0.5 + 1, set0)");
+        let env = Env { inputs: &[0.0, 4.0], setpoints: &[10.0, 0.0], time: 0.0 };
+        assert_eq!(program.eval(&env), 3.0);
+        assert_eq!(Program::compile(program.source(), 2), Ok(program));
+    }
+
+    #[test]
+    fn source_too_long_to_keep() {
+        // Only a few ops, the spaces make it long
+        let src = format!("in0 +{}1", " ".repeat(MAX_SOURCE));
+        assert_eq!(Program::compile(&src, 2), Err(ExprError::TooLong));
+    }
+}
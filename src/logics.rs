@@ -1,74 +1,887 @@
-use crate::state::{InputValues, OutputValues};
+use arrayvec::ArrayString;
+
+use crate::analog::{AnalogInput, CurrentSensor};
+use crate::policy::{Sources, TransferPolicy};
+use crate::state::{InputValues, OutputValues, SENSORS, SENSOR_INPUT};
 use micromath::F32Ext;
 
-#[derive(Debug, Clone, Default)]
-pub struct State {
-    pub left: Side,
-    pub right: Side,
+pub const CHANNELS: usize = 2;
+// Default setpoint range, 0V (off) is also allowed. Nothing drives a
+// setpoint past MAX_LEVEL whatever the channel is configured for.
+pub const MIN_LEVEL: f32 = 1.0;
+pub const MAX_LEVEL: f32 = 20.0;
+
+pub type Units = ArrayString<[u8; 4]>;
+
+/// Setpoint limits, button steps, scaling and display for one channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelConfig {
+    // lowest the buttons step down to, 0 (off) is allowed too
+    pub min: f32,
+    pub max: f32,
+    pub coarse_step: f32,
+    pub fine_step: f32,
+    // level at the top of the ADC and DAC range
+    pub full_scale: f32,
+    pub units: Units,
+    // decimals shown
+    pub precision: u8,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            min: MIN_LEVEL,
+            max: MAX_LEVEL,
+            coarse_step: 0.5,
+            fine_step: 0.1,
+            full_scale: 20.0,
+            units: Units::from("V").unwrap_or_default(),
+            precision: 1,
+        }
+    }
+}
+
+impl ChannelConfig {
+    pub fn is_valid(&self) -> bool {
+        (0.0..self.max).contains(&self.min)
+            // the DAC can't go past full scale
+            && self.max <= MAX_LEVEL && self.max <= self.full_scale
+            && self.coarse_step > 0.0 && self.fine_step > 0.0
+            && self.full_scale > 0.0 && self.full_scale.is_finite()
+            && self.precision <= 6
+    }
+
+    pub fn step(&self, fine: bool) -> f32 {
+        if fine { self.fine_step } else { self.coarse_step }
+    }
+
+    /// Whether a setpoint can be driven to `level`, 0 (off) always can.
+    pub fn allows(&self, level: f32) -> bool {
+        level == 0.0 || (self.min..=self.max).contains(&level)
+    }
+
+    /// `level` pulled inside min..=max, anything at or below 0 (or NaN) is off.
+    pub fn limit(&self, level: f32) -> f32 {
+        if level > 0.0 { level.max(self.min).min(self.max) } else { 0.0 }
+    }
+}
+
+/// The fields a `channel` command gave, the rest keep their current value.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelUpdate {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub coarse_step: Option<f32>,
+    pub fine_step: Option<f32>,
+    pub full_scale: Option<f32>,
+    pub units: Option<Units>,
+    pub precision: Option<u8>,
+}
+
+impl ChannelUpdate {
+    pub fn apply(&self, config: &ChannelConfig) -> ChannelConfig {
+        ChannelConfig {
+            min: self.min.unwrap_or(config.min),
+            max: self.max.unwrap_or(config.max),
+            coarse_step: self.coarse_step.unwrap_or(config.coarse_step),
+            fine_step: self.fine_step.unwrap_or(config.fine_step),
+            full_scale: self.full_scale.unwrap_or(config.full_scale),
+            units: self.units.unwrap_or(config.units),
+            precision: self.precision.unwrap_or(config.precision),
+        }
+    }
+}
+
+/// Connects a channel to the hardware and to the channel it bridges from.
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    // ADC input sensed for this channel
+    pub input: usize,
+    // Channel whose input decides what this channel gets
+    pub source: usize,
+    // DAC driven with this channel's real output
+    pub output: usize,
+    // Current sensor on that output, if it has one
+    pub sensor: Option<usize>,
+    pub name: &'static str,
+}
+
+impl Route {
+    /// The route's current sensor, unless it isn't fitted.
+    pub fn fitted_sensor(&self, sensors: &[CurrentSensor; SENSORS]) -> Option<usize> {
+        self.sensor.filter(|n| sensors[*n].fitted)
+    }
+}
+
+pub const ROUTES: [Route; CHANNELS] = [
+    Route { input: 0, source: 1, output: 1, sensor: Some(0), name: "Left to Right" },
+    Route { input: 1, source: 0, output: 0, sensor: Some(1), name: "Right to Left" },
+];
+
+#[derive(Debug, Clone)]
+pub struct State<const N: usize = CHANNELS> {
+    pub channels: [Side; N],
+    pub fault: Option<Fault>,
+}
+
+impl<const N: usize> Default for State<N> {
+    fn default() -> Self {
+        Self {
+            channels: [Side::default(); N],
+            fault: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    OverVoltage { adc: usize },
+    UnderVoltage { adc: usize },
+}
+
+impl Fault {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fault::OverVoltage { adc: 0 } => "ADC0 over voltage",
+            Fault::OverVoltage { .. } => "ADC1 over voltage",
+            Fault::UnderVoltage { adc: 0 } => "ADC0 under voltage",
+            Fault::UnderVoltage { .. } => "ADC1 under voltage",
+        }
+    }
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct Side {
     pub input: f32,
     pub desired_output: f32,
     pub real_output: f32,
+    // amps delivered at the output, None without a sensor
+    pub current: Option<f32>,
+    // the input counts as a source
+    pub present: bool,
+    // present, and the source the priority picks when there's more than one
+    pub preferred: bool,
+}
+
+impl Side {
+    /// Watts delivered at the output.
+    pub fn power(&self) -> Option<f32> {
+        self.current.map(|amps| amps * self.real_output)
+    }
+}
+
+/// Derives every other channel's setpoint from the leader's:
+/// `midpoint + ratio * (leader - midpoint) + offset`. A negative ratio
+/// tracks in the opposite direction around the midpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tracking {
+    pub leader: usize,
+    pub ratio: f32,
+    pub offset: f32,
+    pub midpoint: f32,
+}
+
+impl Tracking {
+    /// The follower's setpoint within its own limits, off while the
+    /// leader is off. Called with every new leader level, so the follower
+    /// moves along when the leader ramps.
+    pub fn follow(&self, leader: f32, config: &ChannelConfig) -> f32 {
+        if leader == 0.0 {
+            return 0.0;
+        }
+        let level = self.midpoint + self.ratio * (leader - self.midpoint) + self.offset;
+        level.max(config.min).min(config.max)
+    }
+}
+
+/// A mode that drives a setpoint on its own. Only one at a time may own a
+/// channel, the buttons and `set` leave it alone meanwhile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Profile,
+    Sweep,
+    Charge,
+    Mppt,
 }
 
-#[derive(Default)]
-pub struct DesiredOutput {
-    pub dac0: f32,
-    pub dac1: f32,
-}
-
-impl State {
-    pub fn from(input: &InputValues, desired_out: &DesiredOutput) -> Self {
-        let mut s = Self {
-            left: Side {
-                input: Self::adc_convert(input.raw_adc_a0).1,
-                desired_output: desired_out.dac0,
-                real_output: 0.0,
-            },
-            right: Side {
-                input: Self::adc_convert(input.raw_adc_a1).1,
-                desired_output: desired_out.dac1,
-                real_output: 0.0,
-            },
-        };
-
-        s.right.real_output = s.left.output_for(&s.right).0;
-        s.left.real_output = s.right.output_for(&s.left).0;
+impl Owner {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Owner::Profile => "profile",
+            Owner::Sweep => "sweep",
+            Owner::Charge => "charge",
+            Owner::Mppt => "mppt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetpointError {
+    // derived from the leader, nothing else may drive it
+    Following,
+    Owned(Owner),
+}
+
+impl SetpointError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SetpointError::Following => "channel is following",
+            SetpointError::Owned(Owner::Profile) => "channel is running a profile",
+            SetpointError::Owned(Owner::Sweep) => "channel is sweeping",
+            SetpointError::Owned(Owner::Charge) => "channel is charging",
+            SetpointError::Owned(Owner::Mppt) => "channel is tracking the MPP",
+        }
+    }
+}
+
+pub struct DesiredOutput<const N: usize = CHANNELS> {
+    // volts, indexed by channel
+    pub levels: [f32; N],
+    pub tracking: Option<Tracking>,
+    pub config: [ChannelConfig; N],
+    // the buttons move by the fine step rather than the coarse one
+    pub fine: bool,
+    pub owners: [Option<Owner>; N],
+}
+
+impl<const N: usize> Default for DesiredOutput<N> {
+    fn default() -> Self {
+        Self {
+            levels: [0.0; N],
+            tracking: None,
+            config: [ChannelConfig::default(); N],
+            fine: false,
+            owners: [None; N],
+        }
+    }
+}
+
+impl<const N: usize> DesiredOutput<N> {
+    /// Steps `channel` one button step up or down, within its limits.
+    pub fn step(&mut self, channel: usize, up: bool) {
+        if self.check(channel).is_err() {
+            // Its setpoint comes from the leader or a running mode
+            return;
+        }
+        let config = &self.config[channel];
+        let step = config.step(self.fine);
+        let level = &mut self.levels[channel];
+        if up && *level < config.min {
+            // From off straight to the lowest level, nothing below it is allowed
+            *level = config.min;
+        } else if !up && *level > config.min {
+            *level = (*level - step).max(config.min);
+        } else if up && *level < config.max {
+            *level = (*level + step).min(config.max);
+        }
+    }
+
+    /// Sets `channel` directly, for anything driving it other than the buttons.
+    pub fn set(&mut self, channel: usize, level: f32) {
+        self.levels[channel] = level.max(0.0).min(self.config[channel].max);
+    }
+
+    /// The setpoint `channel` is driven to, derived when it's following.
+    pub fn level(&self, channel: usize) -> f32 {
+        match self.tracking {
+            Some(tracking) if channel != tracking.leader => {
+                tracking.follow(self.levels[tracking.leader], &self.config[channel])
+            }
+            _ => self.levels[channel],
+        }
+    }
+
+    pub fn is_follower(&self, channel: usize) -> bool {
+        self.tracking.map_or(false, |t| t.leader != channel)
+    }
+
+    /// Whether `channel`'s setpoint is free to be driven directly.
+    pub fn check(&self, channel: usize) -> Result<(), SetpointError> {
+        if self.is_follower(channel) {
+            return Err(SetpointError::Following);
+        }
+        self.owners[channel].map_or(Ok(()), |owner| Err(SetpointError::Owned(owner)))
+    }
+
+    /// Hands `channel` to `owner`, unless it's following or already owned
+    /// by something else.
+    pub fn claim(&mut self, channel: usize, owner: Owner) -> Result<(), SetpointError> {
+        if self.owners[channel] != Some(owner) {
+            self.check(channel)?;
+        }
+        self.owners[channel] = Some(owner);
+        Ok(())
+    }
+
+    /// Gives `channel` back if `owner` has it.
+    pub fn release(&mut self, channel: usize, owner: Owner) {
+        if self.owners[channel] == Some(owner) {
+            self.owners[channel] = None;
+        }
+    }
+
+    /// `set` for the mode owning `channel`, ignored from anything else.
+    pub fn set_by(&mut self, channel: usize, owner: Owner, level: f32) {
+        if self.owners[channel] == Some(owner) {
+            self.set(channel, level);
+        }
+    }
+}
+
+impl<const N: usize> State<N> {
+    pub fn from<const I: usize>(
+        input: &InputValues<I>,
+        analog: &[AnalogInput; I],
+        desired_out: &DesiredOutput<N>,
+        fault: Option<Fault>,
+        routes: &[Route; N],
+        sensors: &[CurrentSensor; SENSORS],
+        sources: &mut Sources,
+        policy: &impl TransferPolicy,
+    ) -> Self {
+        let mut s = Self::default();
+        s.fault = fault;
+        for (i, (side, route)) in s.channels.iter_mut().zip(routes.iter()).enumerate() {
+            side.input = analog[route.input].to_volts(input.raw[route.input], desired_out.config[i].full_scale);
+            side.desired_output = desired_out.level(i);
+            side.current = route
+                .fitted_sensor(sensors)
+                .map(|n| sensors[n].amps(analog[SENSOR_INPUT].to_pin_volts(input.current[n])));
+        }
+        sources.detect(&mut s.channels);
+
+        // A latched fault keeps every output off
+        if fault.is_none() {
+            policy.apply(&mut s.channels, routes);
+        }
+        // Whatever the policy came up with stays within the channel's limits
+        for (side, config) in s.channels.iter_mut().zip(desired_out.config.iter()) {
+            side.real_output = config.limit(side.real_output);
+        }
         s
     }
 
-    pub fn get_output_level(&self) -> OutputValues {
-        OutputValues{
-            dac0: Self::dac_convert(self.right.real_output),
-            dac1: Self::dac_convert(self.left.real_output),
+    /// Scales each output by the share the power sequence lets through.
+    pub fn gate(&mut self, gain: &[f32; N]) {
+        for (side, gain) in self.channels.iter_mut().zip(gain.iter()) {
+            side.real_output *= gain;
         }
     }
 
-    fn adc_convert(raw: u16) -> (f32, f32) {
-        const MAX_VOLTAGE_RATIO: f32 = 3f32/3.3;
-        let percent = raw as f32 / 4096f32;
-        let volt = percent * 3.3;
-        let real = (percent / MAX_VOLTAGE_RATIO) * 20.0;
-        (volt, real)
+    pub fn get_output_level<const O: usize>(&self, routes: &[Route; N], config: &[ChannelConfig; N]) -> OutputValues<O> {
+        let mut output = OutputValues::default();
+        for ((side, route), config) in self.channels.iter().zip(routes.iter()).zip(config.iter()) {
+            output.raw[route.output] = Self::dac_convert(side.real_output, config.full_scale);
+        }
+        output
     }
-    fn dac_convert(out_voltage: f32) -> u16 {
-        ((out_voltage / 20.0) * 4096.0) as u16
+
+    fn dac_convert(out_voltage: f32, full_scale: f32) -> u16 {
+        // At or past full scale would wrap the 12 bit DAC back to 0
+        ((out_voltage / full_scale) * 4096.0).max(0.0).min(4095.0) as u16
     }
 }
 
-impl Side {
-    fn output_for(&self, want: &Side) -> (f32, bool) {
-        if self.input < 1.0 {
-            (want.desired_output, true)
+// Time with the output off before the open circuit voltage is read
+const VOC_SETTLE_MS: u32 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpptMethod {
+    // steps the setpoint and keeps going the way the power went up
+    PerturbObserve,
+    // holds the source at a fraction of its open circuit voltage
+    FractionalVoc,
+}
+
+impl MpptMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MpptMethod::PerturbObserve => "po",
+            MpptMethod::FractionalVoc => "voc",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MpptConfig {
+    // setpoint moved to load the source, which is this channel's route source
+    pub channel: usize,
+    // setpoint change per perturbation, volts
+    pub step: f32,
+    pub period_ms: u32,
+    // how often fractional Voc lets the source go open again, 0 for only at start
+    pub voc_period_ms: u32,
+    // operating point as a fraction of the open circuit voltage
+    pub fraction: f32,
+}
+
+impl MpptConfig {
+    pub fn is_valid(&self) -> bool {
+        self.channel < CHANNELS
+            && self.step > 0.0 && self.step <= MAX_LEVEL
+            && self.period_ms > 0
+            && self.fraction > 0.0 && self.fraction < 1.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpptPhase {
+    Idle,
+    Tracking,
+    // output off, waiting for the source to settle to its open circuit voltage
+    MeasuringVoc,
+}
+
+impl MpptPhase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MpptPhase::Idle => "idle",
+            MpptPhase::Tracking => "tracking",
+            MpptPhase::MeasuringVoc => "voc",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MpptStatus {
+    pub phase: MpptPhase,
+    pub method: MpptMethod,
+    pub level: f32,
+    // source voltage
+    pub volts: f32,
+    // output power, None without a current sensor
+    pub watts: Option<f32>,
+    pub voc: Option<f32>,
+}
+
+/// Maximum power point tracker steering one setpoint to get the most out
+/// of its source, e.g. a solar panel. Perturb and observe needs the
+/// output's current, without it the source is held at a fraction of its
+/// open circuit voltage. Pure, time and measurements are passed in.
+#[derive(Debug, Clone, Copy)]
+pub struct Mppt {
+    config: Option<MpptConfig>,
+    method: MpptMethod,
+    phase: MpptPhase,
+    level: f32,
+    // +1 or -1, which way the next perturbation goes
+    direction: f32,
+    last_watts: Option<f32>,
+    next_ms: u32,
+    voc: Option<f32>,
+    voc_at_ms: u32,
+    volts: f32,
+    watts: Option<f32>,
+}
+
+impl Default for Mppt {
+    fn default() -> Self {
+        Self {
+            config: None,
+            method: MpptMethod::PerturbObserve,
+            phase: MpptPhase::Idle,
+            level: 0.0,
+            direction: 1.0,
+            last_watts: None,
+            next_ms: 0,
+            voc: None,
+            voc_at_ms: 0,
+            volts: 0.0,
+            watts: None,
+        }
+    }
+}
+
+impl Mppt {
+    /// Starts from `level`, the setpoint the channel is at now, giving the
+    /// setpoint to drive. `sensed` is whether the channel's current is
+    /// measured, which picks the method.
+    pub fn start(&mut self, config: MpptConfig, now_ms: u32, level: f32, sensed: bool) -> f32 {
+        let method = if sensed { MpptMethod::PerturbObserve } else { MpptMethod::FractionalVoc };
+        *self = Self { config: Some(config), method, phase: MpptPhase::Tracking, level, next_ms: now_ms, ..Self::default() };
+        if method == MpptMethod::FractionalVoc {
+            self.open(now_ms);
+            return 0.0;
+        }
+        level
+    }
+
+    /// Stops tracking, the setpoint stays where it got to.
+    pub fn stop(&mut self) {
+        self.phase = MpptPhase::Idle;
+    }
+
+    pub fn config(&self) -> Option<&MpptConfig> {
+        self.config.as_ref()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.phase != MpptPhase::Idle
+    }
+
+    pub fn status(&self) -> MpptStatus {
+        MpptStatus {
+            phase: self.phase,
+            method: self.method,
+            level: self.level,
+            volts: self.volts,
+            watts: self.watts,
+            voc: self.voc,
+        }
+    }
+
+    /// Steps the tracker with the source at `volts` and the output
+    /// delivering `watts`, giving the setpoint to drive when it changes.
+    /// `channel` is the config of the channel it drives.
+    pub fn update(&mut self, now_ms: u32, volts: f32, watts: Option<f32>, channel: &ChannelConfig) -> Option<f32> {
+        let config = self.config?;
+        if !self.is_running() || (now_ms.wrapping_sub(self.next_ms) as i32) < 0 {
+            return None;
+        }
+        self.volts = volts;
+        self.watts = watts;
+
+        match (self.phase, self.method) {
+            (MpptPhase::MeasuringVoc, _) => {
+                self.voc = Some(volts);
+                self.voc_at_ms = now_ms;
+                self.phase = MpptPhase::Tracking;
+            }
+            (_, MpptMethod::FractionalVoc)
+                if config.voc_period_ms != 0 && now_ms.wrapping_sub(self.voc_at_ms) >= config.voc_period_ms => {
+                self.open(now_ms);
+                return Some(0.0);
+            }
+            (_, MpptMethod::FractionalVoc) => {
+                // Loading the source harder pulls it down
+                let target = config.fraction * self.voc.unwrap_or(volts);
+                self.direction = if volts > target { 1.0 } else { -1.0 };
+                self.level += self.direction * config.step;
+            }
+            (_, MpptMethod::PerturbObserve) => {
+                let watts = watts.unwrap_or(0.0);
+                if self.last_watts.map_or(false, |last| watts < last) {
+                    self.direction = -self.direction;
+                }
+                self.last_watts = Some(watts);
+                self.level += self.direction * config.step;
+            }
+        }
+        self.level = self.level.max(0.0).min(channel.max);
+        self.next_ms = now_ms.wrapping_add(config.period_ms);
+        Some(self.level)
+    }
+
+    // Turns the output off to read the open circuit voltage once it settles
+    fn open(&mut self, now_ms: u32) {
+        self.phase = MpptPhase::MeasuringVoc;
+        self.next_ms = now_ms.wrapping_add(VOC_SETTLE_MS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analog::{AdcReference, Window};
+    use crate::policy::Policy;
+    use wio_terminal::hal::adc::{Resolution, SampleRate};
+
+    fn analog_input(resolution: Resolution) -> AnalogInput {
+        AnalogInput {
+            samples: SampleRate::_1,
+            resolution,
+            reference: AdcReference::Vddana,
+            sample_time: 0,
+            negative: None,
+            window: Window::DISABLED,
+        }
+    }
+
+    #[test]
+    fn inputs_convert_at_their_resolution_and_full_scale() {
+        let analog = [analog_input(Resolution::_16BIT), analog_input(Resolution::_12BIT)];
+        let mut out = DesiredOutput::default();
+        out.config[1].full_scale = 10.0;
+        let mut sensors = [CurrentSensor::default(); SENSORS];
+        sensors[0].fitted = false;
+        sensors[1] = CurrentSensor { volts_per_amp: 0.5, offset: 0.0, fitted: true };
+        // Half of each range, 1.65V at the pin
+        let input = InputValues { raw: [0x8000, 0x800], current: [0, 0x800], ..Default::default() };
+
+        let state = State::from(&input, &analog, &out, None, &ROUTES, &sensors, &mut Sources::default(), &Policy::AlwaysPass);
+        assert!((state.channels[0].input - 11.0).abs() < 1e-4);
+        assert!((state.channels[1].input - 5.5).abs() < 1e-4);
+        // Read with the ADC1 settings it is scanned under
+        assert_eq!(state.channels[0].current, None);
+        assert!((state.channels[1].current.unwrap() - 3.3).abs() < 1e-4);
+    }
+
+    fn tracking(ratio: f32, offset: f32, midpoint: f32) -> DesiredOutput {
+        DesiredOutput { tracking: Some(Tracking { leader: 0, ratio, offset, midpoint }), ..Default::default() }
+    }
+
+    #[test]
+    fn follower_stays_within_its_limits() {
+        let mut out = tracking(2.0, 0.0, 0.0);
+        out.config[1].min = 3.0;
+        out.config[1].max = 12.0;
+        // leader, follower
+        let cases = [(1.0, 3.0), (2.0, 4.0), (5.0, 10.0), (8.0, 12.0), (0.0, 0.0)];
+        for (leader, follower) in cases {
+            out.set(0, leader);
+            assert_eq!(out.level(1), follower, "leader {}", leader);
+        }
+    }
+
+    #[test]
+    fn negative_ratio_mirrors_around_the_midpoint() {
+        let mut out = tracking(-1.0, 0.0, 10.0);
+        for (leader, follower) in [(10.0, 10.0), (12.0, 8.0), (5.0, 15.0), (1.0, 19.0)] {
+            out.set(0, leader);
+            assert_eq!(out.level(1), follower, "leader {}", leader);
+        }
+        // Would be below 0, held at the default minimum instead
+        let mut out = tracking(-1.0, -15.0, 10.0);
+        out.set(0, 12.0);
+        assert_eq!(out.level(1), MIN_LEVEL);
+    }
+
+    #[test]
+    fn follower_moves_with_a_ramping_leader() {
+        let mut out = tracking(0.5, 1.0, 0.0);
+        let mut last = out.level(1);
+        for step in 1..=10 {
+            let leader = 2.0 * step as f32;
+            out.set(0, leader);
+            let follower = out.level(1);
+            assert_eq!(follower, 0.5 * leader + 1.0);
+            assert!(follower > last);
+            last = follower;
+        }
+    }
+
+    #[test]
+    fn dac_code_stays_in_range() {
+        assert_eq!(State::<CHANNELS>::dac_convert(0.0, 20.0), 0);
+        assert_eq!(State::<CHANNELS>::dac_convert(10.0, 20.0), 2048);
+        assert_eq!(State::<CHANNELS>::dac_convert(20.0, 20.0), 4095);
+        assert_eq!(State::<CHANNELS>::dac_convert(25.0, 20.0), 4095);
+        assert_eq!(State::<CHANNELS>::dac_convert(-1.0, 20.0), 0);
+    }
+
+    #[test]
+    fn channel_config_limits() {
+        let config = ChannelConfig::default();
+        assert!(config.is_valid());
+        assert!(!ChannelConfig { full_scale: 15.0, ..config }.is_valid());
+        assert!(ChannelConfig { full_scale: 15.0, max: 15.0, ..config }.is_valid());
+        assert!(!ChannelConfig { min: 20.0, ..config }.is_valid());
+
+        let config = ChannelConfig { min: 2.0, max: 12.0, ..config };
+        assert!(config.allows(0.0) && config.allows(2.0) && config.allows(12.0));
+        assert!(!config.allows(1.0) && !config.allows(12.5) && !config.allows(-1.0));
+    }
+
+    #[test]
+    fn update_keeps_what_it_leaves_out() {
+        let current = ChannelConfig { min: 2.0, max: 12.0, precision: 3, ..ChannelConfig::default() };
+        let update = ChannelUpdate { max: Some(15.0), ..ChannelUpdate::default() };
+        assert_eq!(update.apply(&current), ChannelConfig { max: 15.0, ..current });
+        assert_eq!(ChannelUpdate::default().apply(&current), current);
+    }
+
+    #[test]
+    fn stepping_up_from_off_starts_at_min() {
+        let mut out = DesiredOutput::<CHANNELS>::default();
+        out.config[0] = ChannelConfig { min: 3.0, max: 4.0, coarse_step: 0.5, ..ChannelConfig::default() };
+        out.step(0, true);
+        assert_eq!(out.level(0), 3.0);
+        out.step(0, true);
+        out.step(0, true);
+        out.step(0, true);
+        assert_eq!(out.level(0), 4.0);
+        out.step(0, false);
+        out.step(0, false);
+        out.step(0, false);
+        assert_eq!(out.level(0), 3.0);
+    }
+
+    #[test]
+    fn one_owner_at_a_time() {
+        let mut out = DesiredOutput::<CHANNELS>::default();
+        assert_eq!(out.claim(0, Owner::Profile), Ok(()));
+        assert_eq!(out.claim(0, Owner::Profile), Ok(()));
+        assert_eq!(out.claim(0, Owner::Mppt), Err(SetpointError::Owned(Owner::Profile)));
+        assert_eq!(out.check(0), Err(SetpointError::Owned(Owner::Profile)));
+        assert_eq!(out.claim(1, Owner::Mppt), Ok(()));
+
+        // Only the owner moves it, the buttons and others are ignored
+        out.set_by(0, Owner::Profile, 5.0);
+        out.set_by(0, Owner::Mppt, 9.0);
+        out.step(0, true);
+        assert_eq!(out.level(0), 5.0);
+
+        // Releasing from the wrong owner does nothing
+        out.release(0, Owner::Mppt);
+        assert_eq!(out.owners[0], Some(Owner::Profile));
+        out.release(0, Owner::Profile);
+        assert_eq!(out.check(0), Ok(()));
+        out.set_by(0, Owner::Profile, 7.0);
+        assert_eq!(out.level(0), 5.0);
+        assert_eq!(out.claim(0, Owner::Mppt), Ok(()));
+    }
+
+    #[test]
+    fn follower_cant_be_claimed() {
+        let mut out = tracking(1.0, 0.0, 0.0);
+        assert_eq!(out.claim(1, Owner::Profile), Err(SetpointError::Following));
+        assert_eq!(out.claim(0, Owner::Profile), Ok(()));
+    }
+
+    #[test]
+    fn follower_ignores_its_own_setpoint() {
+        let mut out = tracking(1.0, 0.0, 0.0);
+        out.set(0, 5.0);
+        out.set(1, 9.0);
+        out.step(1, true);
+        assert_eq!(out.level(1), 5.0);
+        assert!(out.is_follower(1));
+        assert!(!out.is_follower(0));
+    }
+
+    // A panel with the usual single diode I-V curve. The setpoint sets how
+    // much current the output draws from it, more load pulls it down.
+    struct Panel {
+        voc: f32,
+        isc: f32,
+        // thermal voltage times the cells in series
+        vt: f32,
+        amps_per_volt: f32,
+    }
+
+    const PANEL: Panel = Panel { voc: 20.0, isc: 4.0, vt: 1.5, amps_per_volt: 0.25 };
+
+    impl Panel {
+        fn volts(&self, level: f32) -> f32 {
+            let amps = (level * self.amps_per_volt).min(self.isc);
+            (self.voc + self.vt * (1.0 - amps / self.isc).ln()).max(0.0)
+        }
+
+        fn watts(&self, level: f32) -> f32 {
+            self.volts(level) * (level * self.amps_per_volt).min(self.isc)
+        }
+
+        fn max_watts(&self) -> f32 {
+            (0..=2000).map(|i| self.watts(i as f32 * MAX_LEVEL / 2000.0)).fold(0.0, f32::max)
+        }
+    }
+
+    // Runs the tracker for `ms` and gives the mean power over the last second
+    fn settle(mppt: &mut Mppt, mut level: f32, ms: u32, sensed: bool) -> f32 {
+        let (mut total, mut count) = (0.0, 0);
+        for now in (0..ms).step_by(10) {
+            let watts = PANEL.watts(level);
+            if let Some(next) = mppt.update(now, PANEL.volts(level), sensed.then(|| watts), &ChannelConfig::default()) {
+                level = next;
+            }
+            if now >= ms - 1000 {
+                total += watts;
+                count += 1;
+            }
+        }
+        total / count as f32
+    }
+
+    const MPPT: MpptConfig = MpptConfig { channel: 0, step: 0.05, period_ms: 20, voc_period_ms: 0, fraction: 0.8 };
+
+    #[test]
+    fn fitted_sensor_picks_perturb_and_observe() {
+        let mut sensors = [CurrentSensor::default(); SENSORS];
+        assert_eq!(ROUTES[0].fitted_sensor(&sensors), Some(0));
+        sensors[0].fitted = false;
+        assert_eq!(ROUTES[0].fitted_sensor(&sensors), None);
+        assert_eq!(ROUTES[1].fitted_sensor(&sensors), Some(1));
+
+        let mut mppt = Mppt::default();
+        assert_eq!(mppt.start(MPPT, 0, 5.0, true), 5.0);
+        assert_eq!(mppt.status().method, MpptMethod::PerturbObserve);
+        // Fractional Voc opens the output first
+        assert_eq!(mppt.start(MPPT, 0, 5.0, false), 0.0);
+        assert_eq!(mppt.status().method, MpptMethod::FractionalVoc);
+        assert_eq!(mppt.status().phase, MpptPhase::MeasuringVoc);
+    }
+
+    #[test]
+    fn perturb_and_observe_finds_the_peak() {
+        for start in [1.0, 10.0, 15.0] {
+            let mut mppt = Mppt::default();
+            let level = mppt.start(MPPT, 0, start, true);
+            let watts = settle(&mut mppt, level, 30_000, true);
+            assert!(watts > 0.98 * PANEL.max_watts(), "from {}: {} of {}", start, watts, PANEL.max_watts());
         }
-        else if self.input < want.desired_output {
-            (self.input, true)
+    }
+
+    #[test]
+    fn fractional_voc_holds_near_the_peak() {
+        let mut mppt = Mppt::default();
+        let level = mppt.start(MPPT, 0, 10.0, false);
+        let watts = settle(&mut mppt, level, 30_000, false);
+        assert_eq!(mppt.status().voc, Some(PANEL.voc));
+        assert!((mppt.status().volts - MPPT.fraction * PANEL.voc).abs() < 0.2, "{}", mppt.status().volts);
+        assert!(watts > 0.95 * PANEL.max_watts(), "{} of {}", watts, PANEL.max_watts());
+    }
+
+    #[test]
+    fn fractional_voc_measures_again() {
+        let config = MpptConfig { voc_period_ms: 5000, ..MPPT };
+        let mut mppt = Mppt::default();
+        let channel = ChannelConfig::default();
+        mppt.start(config, 0, 10.0, false);
+        // Back where it was once the voltage is read
+        assert_eq!(mppt.update(VOC_SETTLE_MS, PANEL.voc, None, &channel), Some(10.0));
+        assert_eq!(mppt.update(VOC_SETTLE_MS + 100, 16.0, None, &channel), Some(10.0 - config.step));
+        assert_eq!(mppt.update(VOC_SETTLE_MS + 5000, 16.0, None, &channel), Some(0.0));
+        assert_eq!(mppt.status().phase, MpptPhase::MeasuringVoc);
+        assert_eq!(mppt.update(2 * VOC_SETTLE_MS + 5000, 19.0, None, &channel), Some(10.0 - config.step));
+        assert_eq!(mppt.status().voc, Some(19.0));
+    }
+
+    #[test]
+    fn tracker_stays_under_the_channel_max() {
+        let channel = ChannelConfig { max: 5.0, ..Default::default() };
+        let mut mppt = Mppt::default();
+        mppt.start(MPPT, 0, 4.0, false);
+        assert_eq!(mppt.update(VOC_SETTLE_MS, PANEL.voc, None, &channel), Some(4.0));
+        // Held above the target, so it keeps loading the source harder
+        let mut level = 0.0;
+        for i in 1..100 {
+            level = mppt.update(VOC_SETTLE_MS + i * MPPT.period_ms, PANEL.voc, None, &channel).unwrap_or(level);
         }
-        else {
-            (0.0, false)
+        assert_eq!(level, 5.0);
+    }
+
+    #[test]
+    fn limit_keeps_off_and_clamps_the_rest() {
+        let config = ChannelConfig { min: 2.0, max: 12.0, ..Default::default() };
+        let cases = [(0.0, 0.0), (-1.0, 0.0), (f32::NAN, 0.0), (0.5, 2.0), (5.0, 5.0), (15.0, 12.0)];
+        for (level, limited) in cases {
+            assert_eq!(config.limit(level), limited, "{}", level);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn outputs_are_limited_after_the_policy() {
+        let analog = [analog_input(Resolution::_12BIT); CHANNELS];
+        let mut out = DesiredOutput::default();
+        out.config[0].max = 8.0;
+        out.config[1].min = 3.0;
+        out.levels = [MAX_LEVEL; CHANNELS];
+        // Follows the sources, left at about 1V and right at 20V
+        let input = InputValues { raw: [186, 0xE8C], ..Default::default() };
+        let state = State::from(&input, &analog, &out, None, &ROUTES, &[CurrentSensor::default(); SENSORS],
+                                &mut Sources::default(), &Policy::ClampToDesired);
+        assert_eq!(state.channels[0].real_output, 8.0);
+        assert_eq!(state.channels[1].real_output, 3.0);
+    }
+}
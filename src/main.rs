@@ -3,10 +3,31 @@
 #![feature(let_else)]
 
 mod terminal;
+mod console;
 mod delay;
 mod state;
 mod dac;
 mod logics;
+mod ui;
+mod timing;
+mod serial;
+mod telemetry;
+mod control;
+mod analog;
+mod adc;
+mod policy;
+mod command;
+mod table;
+mod settings;
+mod expr;
+mod sequence;
+mod profile;
+mod sweep;
+mod capture;
+mod stats;
+mod energy;
+mod charge;
+mod battery;
 
 use panic_halt as _;
 use wio_terminal as wio;
@@ -53,6 +74,8 @@ mod app {
     use crate::delay::InstDelay;
     // use crate::strings::str_to_fixed as stf;
     use crate::terminal::Terminal;
+    use crate::console::Level;
+    use crate::ui::{Renderer, StateView, UiMessage, UiQueue};
     use wio::hal::adc::{Adc, Resolution, FreeRunning, InterruptAdc, Reference, SampleRate};
     use wio::pac::{ADC0, ADC1};
     use wio::hal::pac::gclk::pchctrl::GEN_A::{GCLK9, GCLK10, GCLK11};
@@ -62,7 +85,6 @@ mod app {
     use wio_terminal::ButtonController;
 
     // use crate::descriptors::{KeyboardNkroReport, KeyboardNkroReportOut};
-    use arrayvec::ArrayString;
     use core::fmt::Write;
     // use heapless::consts::*;
     // use heapless::spsc::{Consumer, MultiCore, Producer, Queue, SingleCore};
@@ -70,13 +92,45 @@ mod app {
     use wio_terminal::hal::rtc::*;
     use ssmarshal::{deserialize, serialize};
     use cortex_m::asm::nop;
-    use embedded_graphics::geometry::Point;
+    use cortex_m::peripheral::DWT;
     use rtic::Mutex;
     use wio_terminal::hal::time::Hertz;
     // use nb::block;
-    use crate::dac::Dac;
+    use crate::dac::{Channel, Dac, DacConfig, WriteStats};
     use crate::logics::State;
+    use crate::serial::Serial;
+    use crate::timing::{Deadline, TimingStats};
+    use crate::control::{Decimator, Trigger};
+    use crate::state::{InputValues, OutputValues, INPUTS, SENSORS};
+    use crate::logics::{ChannelConfig, DesiredOutput, Fault, Mppt, Owner, SetpointError, ROUTES};
+    use crate::adc::{Sample, Scanner};
+    use crate::analog::{AdcReference, AnalogInput, CurrentSensor, Trip, Window};
+    use crate::command::{Command, CommandLine, Origin};
+    use crate::policy::{Formula, Lookup, Policy, Sources};
+    use crate::expr::Program;
+    use crate::sequence::{Event, Phase, Sequence, Sequencer};
+    use crate::profile::{Player, Profile};
+    use crate::command::Play;
+    use crate::sweep::Sweep;
+    use crate::capture::{Capture, Scale, State as CaptureState};
+    use crate::stats::ChannelStats;
+    use crate::energy::Counters;
+    use crate::charge::Charger;
+    use crate::battery::Batteries;
+    use crate::ui::LogLine;
+    use crate::settings::{Settings, Store};
+    use crate::table::Table;
+    use crate::logics::CHANNELS;
+    use usb_device::bus::UsbBusAllocator;
+    use wio::hal::usb::UsbBus;
 
+    // Priorities, highest first. Anything touching the display or the host
+    // link stays below control so a long SPI redraw can't hold it up.
+    //   5: ADC window trip
+    //   4: ADC result ready
+    //   3: control, DAC empty
+    //   2: buttons, USB, power_sequence, profile_tick, sweep_tick, charge_tick, mppt_tick
+    //   1: render, print_state, telemetry, blinky, command, capture_send
     #[shared]
     struct Resources {
         // Buttons
@@ -84,19 +138,48 @@ mod app {
         button_ctr: ButtonController,
 
         // Data
-        #[lock_free]
         inputs: crate::state::InputValues,
-        #[lock_free]
         outputs: crate::state::OutputValues,
-        #[lock_free]
         desired_out: crate::logics::DesiredOutput,
-        #[lock_free]
         state: crate::logics::State,
+
+        // Display
+        ui: UiQueue,
+
+        // Control
+        dac: Dac,
+        dac_writes: [WriteStats; 2],
+        fault: Option<Fault>,
+        #[lock_free]
+        last_event: Option<Instant>,
+        trigger: Trigger,
+        event_overruns: u32,
+        policy: Policy,
+        tables: [Table; CHANNELS],
+        programs: [Program; CHANNELS],
+        sequence: Sequence,
+        sequencer: Sequencer,
+        profiles: [Profile; CHANNELS],
+        players: [Player; CHANNELS],
+        sweep: Sweep,
+        capture: Capture,
+        stats: ChannelStats,
+        energy: Counters,
+        sensors: [CurrentSensor; SENSORS],
+        charger: Charger,
+        mppt: Mppt,
+        sources: Sources,
+        batteries: Batteries,
+        store: Store,
+
+        // Host link
+        serial: Serial,
+        control_timing: TimingStats,
     }
 
     #[local]
     struct Local {
-        terminal: Terminal,
+        renderer: Renderer,
         backlight: LcdBacklight,
         backlight_state: bool,
 
@@ -105,16 +188,49 @@ mod app {
         i_adc0: InterruptAdc<ADC0, FreeRunning>,
         i_adc1: InterruptAdc<ADC1, FreeRunning>,
 
-        // ADC
-        dac: Dac,
+        control_deadline: Deadline,
     }
 
     #[monotonic(binds = SysTick, default = true)]
-    type SysTickMonotonic = Systick<100>;
+    type SysTickMonotonic = Systick<1000>;
+    type Instant = <SysTickMonotonic as rtic::Monotonic>::Instant;
+
+    const CONTROL_PERIOD_MS: u64 = 10;
+    const SEQUENCE_POLL_MS: u64 = 5;
+    const PROFILE_PERIOD_MS: u64 = 10;
+    const SWEEP_PERIOD_MS: u64 = 5;
+    const CHARGE_PERIOD_MS: u64 = 50;
+    // The tracker keeps its own perturbation period, this only polls it
+    const MPPT_POLL_MS: u64 = 10;
+    // 16 saves fit between erases, so the counter block is erased every 160 minutes
+    const ENERGY_SAVE_PERIOD_S: u64 = 600;
+
+    const DAC_CONFIG: DacConfig = DacConfig::new();
 
-    const PERIOD: u32 = 16_000_000;
+    const A0_INPUT: AnalogInput = AnalogInput {
+        samples: SampleRate::_256,
+        resolution: Resolution::_16BIT,
+        reference: AdcReference::Vddana,
+        sample_time: 0,
+        negative: None,
+        window: Window { under: None, over: Some(19.0) },
+    };
+    const A1_INPUT: AnalogInput = AnalogInput {
+        samples: SampleRate::_256,
+        resolution: Resolution::_12BIT,
+        reference: AdcReference::Vddana,
+        sample_time: 0,
+        negative: None,
+        window: Window { under: None, over: Some(19.0) },
+    };
+    const ANALOG_INPUTS: [AnalogInput; INPUTS] = [A0_INPUT, A1_INPUT];
+    // Internal channels and current sensors are read on ADC1, one every this
+    // many A1 results, so each sensor comes round every 7 * 16 results
+    const INTERNAL_SCAN_EVERY: u16 = 16;
+    // ADC1 AIN numbers of the current sense pins, A3 (PB04) and A4 (PB05)
+    const CURRENT_SENSE_AIN: [u8; SENSORS] = [6, 7];
 
-    #[init]
+    #[init(local = [usb_alloc: Option<UsbBusAllocator<UsbBus>> = None])]
     fn init(cx: init::Context) -> (Resources, Local, init::Monotonics) {
         let mut core = cx.core;
         core.DWT.enable_cycle_counter();
@@ -133,6 +249,11 @@ mod app {
 
         let gclk = clocks.gclk0();
         let freq: Hertz = gclk.into();
+
+        let mut store = Store::new(device.NVMCTRL, freq.0 / 1_000_000);
+        let settings = store.load();
+        let counters = store.load_counters();
+
         let systick = Systick::new(core.SYST, freq.0);
         // PORT
         let mut sets: Sets = Pins::new(device.PORT).split();
@@ -154,21 +275,37 @@ mod app {
                 &mut InstDelay {},
             )
             .unwrap();
-        let mut term = Terminal::new(display);
+        let renderer = Renderer::new(Terminal::new(display));
+        let mut ui = UiQueue::new();
 
-        term.write_str("Hello World! -----------------------------------\n");
+        ui.log(Level::Info, format_args!("Hello World! -----------------------------------"));
+        if settings.is_none() {
+            ui.log(Level::Warn, format_args!("No saved settings, using defaults"));
+        }
+        let settings = settings.unwrap_or_default();
+        ui.log(Level::Info, format_args!("Control trigger: {:?}", settings.trigger));
+        if counters.is_none() {
+            ui.log(Level::Warn, format_args!("No saved energy counters, starting at 0"));
+        }
 
         // ADC
         let mut header_pins = sets.header_pins;
 
         let mut adc0 = Adc::adc0(device.ADC0, &mut device.MCLK, &mut clocks, GCLK10);
         let mut adc1 = Adc::adc1(device.ADC1, &mut device.MCLK, &mut clocks, GCLK11);
-        adc0.samples(SampleRate::_256);
-        adc0.resolution(Resolution::_16BIT);
-        adc1.samples(SampleRate::_256);
-        adc1.resolution(Resolution::_12BIT);
+        adc0.samples(A0_INPUT.samples);
+        adc0.resolution(A0_INPUT.resolution);
+        adc1.samples(A1_INPUT.samples);
+        adc1.resolution(A1_INPUT.resolution);
+        A0_INPUT.configure(adc_registers(0), input_full_scale(&settings.channels, 0));
+        A1_INPUT.configure(adc_registers(1), input_full_scale(&settings.channels, 1));
+        // Temperature sensor for the internal channels
+        device.SUPC.vref.modify(|_, w| w.tsen().set_bit().ondemand().set_bit());
         let mut a0_d0: Pin<PB08, Alternate<B>> = header_pins.a0_d0.into();
         let mut a1_d1: Pin<PB09, Alternate<B>> = header_pins.a1_d1.into();
+        // Only switched to analog, the scanner selects them on ADC1
+        let _a3_d3: Pin<PB04, Alternate<B>> = header_pins.a3_d3.into();
+        let _a4_d4: Pin<PB05, Alternate<B>> = header_pins.a4_d4.into();
 
         let mut i_adc0: InterruptAdc<_, FreeRunning> = InterruptAdc::from(adc0);
         let mut i_adc1: InterruptAdc<_, FreeRunning> = InterruptAdc::from(adc1);
@@ -176,7 +313,9 @@ mod app {
         i_adc1.start_conversion(&mut a1_d1);
 
         // DAC
-        let dac = Dac::new(device.DAC, header_pins.dac0, header_pins.dac1, &mut device.MCLK, &mut clocks, GCLK9);
+        let mut dac = Dac::new(device.DAC, header_pins.dac0, header_pins.dac1, &mut device.MCLK, &mut clocks, GCLK9, DAC_CONFIG)
+            .expect("dac setup failed");
+        dac.listen();
 
         user_led.set_low().unwrap();
 
@@ -185,219 +324,1089 @@ mod app {
             sets.buttons
                 .init(device.EIC, &mut clocks, &mut device.MCLK);
 
+        // USB
+        let usb_alloc = cx.local.usb_alloc.insert(
+            sets.usb.usb_allocator(device.USB, &mut clocks, &mut device.MCLK)
+        );
+        let serial = Serial::new(usb_alloc);
+
         // Start Tasks
         blinky::spawn().unwrap();
         print_state::spawn().unwrap();
-        sync::spawn().unwrap();
-        dac_update::spawn().unwrap();
+        control::spawn(monotonics::now()).unwrap();
+        render::spawn().unwrap();
+        telemetry::spawn().unwrap();
+        energy_save::spawn_after(ENERGY_SAVE_PERIOD_S.secs()).unwrap();
 
         (Resources {
             button_ctr,
             inputs: Default::default(),
             outputs: Default::default(),
-            desired_out: Default::default(),
+            desired_out: DesiredOutput { config: settings.channels, ..Default::default() },
             state: Default::default(),
+            ui,
+            dac,
+            dac_writes: Default::default(),
+            fault: None,
+            last_event: None,
+            trigger: settings.trigger,
+            event_overruns: 0,
+            policy: settings.policy,
+            tables: settings.tables,
+            programs: settings.programs,
+            sequencer: Sequencer::new(&settings.sequence),
+            sequence: settings.sequence,
+            profiles: settings.profiles,
+            players: Default::default(),
+            sweep: Default::default(),
+            capture: Capture::new(freq.0),
+            stats: Default::default(),
+            energy: Counters::new(counters.unwrap_or_default()),
+            sensors: settings.sensors,
+            sources: Sources::new(settings.detection, settings.source_priority),
+            batteries: Batteries::new(settings.batteries),
+            charger: Default::default(),
+            mppt: Default::default(),
+            store,
+            serial,
+            control_timing: Default::default(),
         }, Local {
-            terminal: term,
+            renderer,
             backlight,
             backlight_state: true,
             user_led,
             i_adc0,
             i_adc1,
-            dac,
+            control_deadline: Deadline::new(CONTROL_PERIOD_MS as u32 * 1000, freq.0),
         }, init::Monotonics(systick))
     }
 
-    #[task(local = [user_led])] // ,d7
+    #[task(local = [user_led], priority = 1)] // ,d7
     fn blinky(cx: blinky::Context) {
         cx.local.user_led.toggle();
         blinky::spawn_after(200.millis()).unwrap();
     }
 
-    #[task(local = [terminal], capacity = 6)]
-    fn print(cx: print::Context, msg: ArrayString<[u8; 256]>, pos: Point) {
-        // cx.local.terminal.write_str(&msg[..]);
-        cx.local.terminal.write_pos(pos, &msg[..]);
+    #[task(local = [renderer], shared = [ui, sweep, capture], priority = 1)]
+    fn render(mut cx: render::Context) {
+        while let Some(msg) = cx.shared.ui.lock(|ui| ui.receive()) {
+            let diagnostics = cx.shared.ui.lock(|ui| ui.diagnostics());
+            if let Some(cmd) = cx.local.renderer.render(msg, &diagnostics) {
+                command::spawn(cmd, Origin::Ui).ok();
+            }
+        }
+
+        // Copied out so the plot can take its time without holding up sweep_tick
+        let points = cx.shared.sweep.lock(|s| s.results().len());
+        if cx.local.renderer.needs_sweep(points) {
+            let sweep = cx.shared.sweep.lock(|s| s.clone());
+            cx.local.renderer.draw_sweep(&sweep);
+        }
+
+        let records = cx.shared.capture.lock(|c| c.records());
+        if cx.local.renderer.needs_capture(records) {
+            let (state, waveform) = cx.shared.capture.lock(|c| (c.state(), c.waveform()));
+            // Only drawn, the record stays until the host reads it or it is re-armed
+            cx.local.renderer.draw_capture(records, state, waveform.as_ref());
+        }
+    }
+
+    fn adc_registers(input: usize) -> &'static wio::pac::adc0::RegisterBlock {
+        if input == 0 { unsafe { &*ADC0::ptr() } } else { unsafe { &*ADC1::ptr() } }
+    }
+
+    // Volts at full scale of the channel sensed on ADC input `input`
+    fn input_full_scale(config: &[ChannelConfig; CHANNELS], input: usize) -> f32 {
+        ROUTES.iter().position(|route| route.input == input)
+            .map_or(ChannelConfig::default().full_scale, |channel| config[channel].full_scale)
     }
 
-    #[task(shared = [inputs, outputs, desired_out, state])]
-    fn sync(mut cx: sync::Context) {
-        let state = State::from(&cx.shared.inputs, &cx.shared.desired_out);
+    fn send(mut ui: impl Mutex<T=UiQueue>, msg: UiMessage) {
+        ui.lock(|ui| ui.send(msg));
+        // Already pending means the queue gets drained anyway
+        render::spawn().ok();
+    }
+
+    // Reads the inputs, computes the new state and writes the DAC in one go
+    fn update(
+        dac: impl Mutex<T=Dac>,
+        mut fault: impl Mutex<T=Option<Fault>>,
+        mut inputs: impl Mutex<T=InputValues>,
+        mut desired_out: impl Mutex<T=DesiredOutput>,
+        mut outputs: impl Mutex<T=OutputValues>,
+        mut state: impl Mutex<T=State>,
+        mut policy: impl Mutex<T=Policy>,
+        tables: impl Mutex<T=[Table; CHANNELS]>,
+        programs: impl Mutex<T=[Program; CHANNELS]>,
+        mut sequencer: impl Mutex<T=Sequencer>,
+        mut stats: impl Mutex<T=ChannelStats>,
+        mut energy: impl Mutex<T=Counters>,
+        mut sensors: impl Mutex<T=[CurrentSensor; SENSORS]>,
+        mut sources: impl Mutex<T=Sources>,
+        mut batteries: impl Mutex<T=Batteries>,
+    ) {
+        let inputs = inputs.lock(|inputs| *inputs);
+        let latched = fault.lock(|f| *f);
+        let policy = policy.lock(|p| *p);
+        let gain = sequencer.lock(|s| s.gain());
+        let sensors = sensors.lock(|s| *s);
+        let config = desired_out.lock(|d| d.config);
+        let time = millis() as f32 / 1000.0;
+        let mut new_state = (desired_out, tables, programs, sources).lock(|desired_out, tables, programs, sources| match policy {
+            Policy::Table => State::from(&inputs, &ANALOG_INPUTS, desired_out, latched, &ROUTES, &sensors, sources, &Lookup { tables: &tables[..] }),
+            Policy::Expr => State::from(&inputs, &ANALOG_INPUTS, desired_out, latched, &ROUTES, &sensors, sources, &Formula { programs: &programs[..], time }),
+            _ => State::from(&inputs, &ANALOG_INPUTS, desired_out, latched, &ROUTES, &sensors, sources, &policy),
+        });
+        new_state.gate(&gain);
+        batteries.lock(|b| b.protect(&mut new_state, &ROUTES));
+        let new_outputs = new_state.get_output_level(&ROUTES, &config);
 
-        *cx.shared.outputs = state.get_output_level();
-        *cx.shared.state = state;
+        // Check the fault again with the DAC held so a trip in between can't be overwritten
+        (dac, fault).lock(|dac, fault| {
+            dac.write_output(if fault.is_some() { &OutputValues::default() } else { &new_outputs });
+        });
 
-        sync::spawn_after(10.millis()).unwrap();
+        outputs.lock(|o| *o = new_outputs);
+        stats.lock(|s| s.add(&new_state));
+        energy.lock(|e| e.integrate(&new_state, millis()));
+        state.lock(|s| *s = new_state);
     }
 
-    #[task(local = [dac], shared = [outputs])]
-    fn dac_update(cx: dac_update::Context) {
-        cx.local.dac.set_output(&cx.shared.outputs);
-        cx.local.dac.update();
-        dac_update::spawn_after(10.millis()).unwrap();
+    fn millis() -> u32 {
+        monotonics::now().duration_since_epoch().to_millis() as u32
     }
 
-    #[task(shared = [desired_out])]
-    fn button(mut cx: button::Context, event: ButtonEvent) {
-        let mut buf = ArrayString::new();
+    // Released every CONTROL_PERIOD_MS from the previous release rather than
+    // from whenever the last run finished. With an ADC trigger it only runs
+    // when conversions have stopped driving control_event.
+    #[task(local = [control_deadline], shared = [dac, fault, last_event, trigger, inputs, outputs, desired_out, state, policy, tables, programs, sequencer, stats, energy, sensors, sources, batteries, control_timing], priority = 3)]
+    fn control(mut cx: control::Context, release: Instant) {
+        let now = monotonics::now();
+        let adc_driven = match (cx.shared.trigger.lock(|t| *t), *cx.shared.last_event) {
+            (Trigger::Adc { .. }, Some(last)) => now < last + (2 * CONTROL_PERIOD_MS).millis(),
+            _ => false,
+        };
+
+        let deadline = cx.local.control_deadline;
+        if adc_driven {
+            deadline.skip();
+        } else {
+            deadline.start(DWT::cycle_count());
+            update(cx.shared.dac, cx.shared.fault, cx.shared.inputs, cx.shared.desired_out, cx.shared.outputs, cx.shared.state, cx.shared.policy, cx.shared.tables, cx.shared.programs, cx.shared.sequencer, cx.shared.stats, cx.shared.energy, cx.shared.sensors, cx.shared.sources, cx.shared.batteries);
+            deadline.finish(DWT::cycle_count());
+            cx.shared.control_timing.lock(|t| *t = deadline.stats());
+        }
+
+        // Don't try to catch up on periods that were missed entirely
+        let mut next = release + CONTROL_PERIOD_MS.millis();
+        if next < now {
+            next = now;
+        }
+        control::spawn_at(next, next).unwrap();
+    }
+
+    #[task(shared = [dac, fault, last_event, inputs, outputs, desired_out, state, policy, tables, programs, sequencer, stats, energy, sensors, sources, batteries], priority = 3)]
+    fn control_event(cx: control_event::Context) {
+        *cx.shared.last_event = Some(monotonics::now());
+        update(cx.shared.dac, cx.shared.fault, cx.shared.inputs, cx.shared.desired_out, cx.shared.outputs, cx.shared.state, cx.shared.policy, cx.shared.tables, cx.shared.programs, cx.shared.sequencer, cx.shared.stats, cx.shared.energy, cx.shared.sensors, cx.shared.sources, cx.shared.batteries);
+    }
+
+    // Zeroes both outputs without waiting for the next control run
+    fn trip(dac: impl Mutex<T=Dac>, fault: impl Mutex<T=Option<Fault>>, new_fault: Fault) {
+        (dac, fault).lock(|dac, fault| {
+            dac.write_output(&OutputValues::default());
+            if fault.is_none() {
+                *fault = Some(new_fault);
+                fault_report::spawn(new_fault).ok();
+            }
+        });
+    }
+
+    #[task(binds = ADC0_OTHER, shared = [dac, fault], priority = 5)]
+    fn adc0_window(cx: adc0_window::Context) {
+        let Some(trip_kind) = A0_INPUT.service_window(unsafe { &*ADC0::ptr() }) else {
+            return;
+        };
+        trip(cx.shared.dac, cx.shared.fault, match trip_kind {
+            Trip::Over => Fault::OverVoltage { adc: 0 },
+            Trip::Under => Fault::UnderVoltage { adc: 0 },
+        });
+    }
+
+    #[task(binds = ADC1_OTHER, shared = [dac, fault], priority = 5)]
+    fn adc1_window(cx: adc1_window::Context) {
+        let Some(trip_kind) = A1_INPUT.service_window(unsafe { &*ADC1::ptr() }) else {
+            return;
+        };
+        trip(cx.shared.dac, cx.shared.fault, match trip_kind {
+            Trip::Over => Fault::OverVoltage { adc: 1 },
+            Trip::Under => Fault::UnderVoltage { adc: 1 },
+        });
+    }
+
+    #[task(binds = DAC_EMPTY_0, shared = [dac], priority = 3)]
+    fn dac_empty0(mut cx: dac_empty0::Context) {
+        if let Some(latency) = cx.shared.dac.lock(|dac| dac.service_interrupt(Channel::Dac0)) {
+            dac_written::spawn(Channel::Dac0, latency).ok();
+        }
+    }
+
+    #[task(binds = DAC_EMPTY_1, shared = [dac], priority = 3)]
+    fn dac_empty1(mut cx: dac_empty1::Context) {
+        if let Some(latency) = cx.shared.dac.lock(|dac| dac.service_interrupt(Channel::Dac1)) {
+            dac_written::spawn(Channel::Dac1, latency).ok();
+        }
+    }
+
+    // A write reached the output, `latency` microseconds after it was made
+    #[task(shared = [dac_writes], priority = 2, capacity = 2)]
+    fn dac_written(mut cx: dac_written::Context, channel: Channel, latency: u32) {
+        cx.shared.dac_writes.lock(|writes| writes[channel as usize].record(latency));
+    }
+
+    // The trip already zeroed the DAC, this ramps a configured sequence down
+    // behind it and stops a charge or the tracker so they can't pick up
+    // again once the fault is cleared
+    #[task(shared = [sequence, sequencer, charger, mppt, desired_out, ui], priority = 1)]
+    fn fault_report(mut cx: fault_report::Context, fault: Fault) {
+        (cx.shared.sequence, cx.shared.sequencer).lock(|sequence, sequencer| {
+            if !sequence.is_empty() {
+                sequencer.start(Phase::Abort, millis());
+            }
+        });
+        (cx.shared.charger, cx.shared.desired_out).lock(|charger, desired_out| {
+            if let Some(config) = charger.config().filter(|_| charger.state().is_charging()) {
+                desired_out.set_by(config.channel, Owner::Charge, 0.0);
+                desired_out.release(config.channel, Owner::Charge);
+            }
+            charger.stop();
+        });
+        (cx.shared.mppt, cx.shared.desired_out).lock(|mppt, desired_out| {
+            if let Some(config) = mppt.config() {
+                desired_out.release(config.channel, Owner::Mppt);
+            }
+            mppt.stop();
+        });
+        power_sequence::spawn().ok();
+        send(cx.shared.ui, UiMessage::Fault(fault.name()));
+    }
+
+    // Moves the setpoints along their profiles while any of them is running
+    #[task(shared = [profiles, players, desired_out], priority = 2)]
+    fn profile_tick(cx: profile_tick::Context) {
+        let now = millis();
+        let running = (cx.shared.profiles, cx.shared.players, cx.shared.desired_out).lock(|profiles, players, desired_out| {
+            for (channel, (player, profile)) in players.iter_mut().zip(profiles.iter()).enumerate() {
+                if let Some(level) = player.update(profile, now) {
+                    desired_out.set_by(channel, Owner::Profile, level);
+                }
+                if !player.is_active() {
+                    desired_out.release(channel, Owner::Profile);
+                }
+            }
+            players.iter().any(|p| p.is_running())
+        });
+        if running {
+            profile_tick::spawn_after(PROFILE_PERIOD_MS.millis()).ok();
+        }
+    }
+
+    // Runs the charger on the latest measurements while it's charging
+    #[task(shared = [charger, state, desired_out, ui], priority = 2)]
+    fn charge_tick(mut cx: charge_tick::Context) {
+        let now = millis();
+        let state = cx.shared.state.lock(|s| s.clone());
+        let (before, after) = (cx.shared.charger, cx.shared.desired_out).lock(|charger, desired_out| {
+            let before = charger.state();
+            let Some(config) = charger.config().copied() else {
+                return (before, before);
+            };
+            let side = &state.channels[config.channel];
+            if let Some(level) = charger.update(now, state.channels[config.measure].input, side.current) {
+                desired_out.set_by(config.channel, Owner::Charge, level);
+            }
+            if !charger.state().is_charging() {
+                desired_out.release(config.channel, Owner::Charge);
+            }
+            (before, charger.state())
+        });
+        if after != before {
+            cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Charge: {}", after.name())));
+        }
+        if after.is_charging() {
+            charge_tick::spawn_after(CHARGE_PERIOD_MS.millis()).ok();
+        }
+    }
+
+    // Runs the tracker on its source's voltage and its output's power
+    #[task(shared = [mppt, state, desired_out], priority = 2)]
+    fn mppt_tick(cx: mppt_tick::Context) {
+        let now = millis();
+        let state = cx.shared.state.lock(|s| s.clone());
+        let running = (cx.shared.mppt, cx.shared.desired_out).lock(|mppt, desired_out| {
+            let Some(config) = mppt.config().copied() else {
+                return false;
+            };
+            let source = state.channels[ROUTES[config.channel].source].input;
+            if let Some(level) = mppt.update(now, source, state.channels[config.channel].power(), &desired_out.config[config.channel]) {
+                desired_out.set_by(config.channel, Owner::Mppt, level);
+            }
+            if !mppt.is_running() {
+                desired_out.release(config.channel, Owner::Mppt);
+            }
+            mppt.is_running()
+        });
+        if running {
+            mppt_tick::spawn_after(MPPT_POLL_MS.millis()).ok();
+        }
+    }
+
+    // Steps the sweep and feeds it the measured input while it runs
+    #[task(shared = [sweep, state, desired_out], priority = 2)]
+    fn sweep_tick(cx: sweep_tick::Context) {
+        let now = millis();
+        let running = (cx.shared.sweep, cx.shared.state, cx.shared.desired_out).lock(|sweep, state, desired_out| {
+            let Some(config) = sweep.config().copied() else {
+                return false;
+            };
+            if let Some(level) = sweep.update(now, state.channels[config.measure].input) {
+                desired_out.set_by(config.channel, Owner::Sweep, level);
+            }
+            if !sweep.is_running() {
+                desired_out.release(config.channel, Owner::Sweep);
+            }
+            sweep.is_running()
+        });
+        if running {
+            sweep_tick::spawn_after(SWEEP_PERIOD_MS.millis()).ok();
+        } else {
+            render::spawn().ok();
+        }
+    }
+
+    // Writes the sweep results one line at a time, waiting for the host
+    // to take each one instead of dropping it
+    #[task(shared = [sweep, serial], priority = 1)]
+    fn sweep_dump(cx: sweep_dump::Context, index: usize, offset: usize) {
+        let line = cx.shared.sweep.lock(|sweep| {
+            let point = sweep.results().get(index)?;
+            let mut line = LogLine::new();
+            crate::telemetry::sweep_point(&mut line, index, point).ok()?;
+            Some(line)
+        });
+        let Some(line) = line else {
+            return;
+        };
+        let written = offset + cx.shared.serial.lock(|serial| serial.write(&line.as_bytes()[offset..]));
+        if written == line.len() {
+            sweep_dump::spawn(index + 1, 0).ok();
+        } else {
+            sweep_dump::spawn_after(1.millis(), index, written).ok();
+        }
+    }
+
+    // Sends the finished capture record as a header line and a binary block,
+    // a chunk at a time, then lets the capture re-arm
+    #[task(shared = [capture, serial], priority = 1)]
+    fn capture_send(mut cx: capture_send::Context, offset: usize) {
+        const CHUNK: usize = 64;
+        let mut chunk = [0u8; CHUNK];
+        let (len, total) = cx.shared.capture.lock(|capture| {
+            let (Some(config), Some(scale)) = (capture.config().copied(), capture.scale().copied()) else {
+                return (0, 0);
+            };
+            // Rebuilt every chunk, nothing in it changes while the record is held
+            let mut header = LogLine::new();
+            crate::telemetry::capture(&mut header, &config, capture.period_us(), capture.forced(),
+                                      scale.to_volts(1), scale.input.negative.is_some()).ok();
+            let data = header.len() + config.length * 2;
+            let total = data + 2;
+            let mut len = 0;
+            for (byte, pos) in chunk.iter_mut().zip(offset..total) {
+                *byte = if pos < header.len() {
+                    header.as_bytes()[pos]
+                } else if pos < data {
+                    let i = pos - header.len();
+                    capture.get(i / 2).unwrap_or(0).to_le_bytes()[i % 2]
+                } else {
+                    b"\r\n"[pos - data]
+                };
+                len += 1;
+            }
+            (len, total)
+        });
+
+        let written = cx.shared.serial.lock(|serial| serial.write(&chunk[..len]));
+        let offset = offset + written;
+        if offset >= total {
+            cx.shared.capture.lock(|capture| {
+                capture.release();
+                capture.done_reading();
+            });
+        } else if written == len {
+            capture_send::spawn(offset).ok();
+        } else {
+            // Host hasn't taken the last chunk yet
+            capture_send::spawn_after(1.millis(), offset).ok();
+        }
+    }
 
-        write!(&mut buf, "Btn {:?}\n", event).expect("!write");
-        print::spawn(buf, Point::new(5, 35)).ok();
-        match event {
+    // Saves the energy counters every ENERGY_SAVE_PERIOD_S
+    #[task(shared = [store, energy, ui], priority = 1)]
+    fn energy_save(mut cx: energy_save::Context) {
+        let energy = cx.shared.energy.lock(|e| e.channels);
+        if let Err(e) = cx.shared.store.lock(|store| store.save_counters(&energy)) {
+            cx.shared.ui.lock(|ui| ui.log(Level::Error, format_args!("Energy save failed: {}", e.name())));
+        }
+        energy_save::spawn_after(ENERGY_SAVE_PERIOD_S.secs()).unwrap();
+    }
+
+    // Polled while a power sequence runs
+    #[task(shared = [sequence, sequencer, state, ui], priority = 2)]
+    fn power_sequence(mut cx: power_sequence::Context) {
+        let inputs = cx.shared.state.lock(|state| state.channels.map(|side| side.input));
+        let running = (cx.shared.sequence, cx.shared.sequencer, cx.shared.ui).lock(|sequence, sequencer, ui| {
+            while let Some(event) = sequencer.poll(sequence, millis(), &inputs) {
+                match event {
+                    Event::Enabled(channel) => ui.log(Level::Info, format_args!("Seq: channel {} on", channel)),
+                    Event::Disabled(channel) => ui.log(Level::Info, format_args!("Seq: channel {} off", channel)),
+                    Event::Timeout(channel) => ui.log(Level::Error, format_args!("Seq: channel {} timed out, aborting", channel)),
+                    Event::Done(phase) => ui.log(Level::Info, format_args!("Seq: {:?} done", phase)),
+                };
+            }
+            sequencer.is_running()
+        });
+        render::spawn().ok();
+        if running {
+            power_sequence::spawn_after(SEQUENCE_POLL_MS.millis()).ok();
+        }
+    }
+
+    #[task(shared = [desired_out, ui], priority = 2)]
+    fn button(mut cx: button::Context, event: ButtonEvent) {
+        cx.shared.ui.lock(|ui| ui.log(Level::Debug, format_args!("Btn {:?}", event)));
+        // (channel, up) for each setpoint button
+        let adjust = match &event {
             ButtonEvent {
                 button: Button::TopLeft,
                 down: true,
-            } => {
-                if cx.shared.desired_out.dac0 > 1f32 {
-                    cx.shared.desired_out.dac0 -= 0.5
-                }
-            }
+            } => Some((0, false)),
             ButtonEvent {
                 button: Button::TopMiddle,
                 down: true,
-            } => {
-
-                if cx.shared.desired_out.dac0 < 20f32 {
-                    cx.shared.desired_out.dac0 += 0.5
-                }
-            }
+            } => Some((0, true)),
             ButtonEvent {
                 button: Button::Down,
                 down: true,
-            } => {
-                if cx.shared.desired_out.dac1 > 1f32 {
-                    cx.shared.desired_out.dac1 -= 0.5
-                }
-            }
+            } => Some((1, false)),
             ButtonEvent {
                 button: Button::Up,
                 down: true,
-            } => {
-
-                if cx.shared.desired_out.dac1 < 20f32 {
-                    cx.shared.desired_out.dac1 += 0.5
-                }
-            }
-            ButtonEvent { .. } => {}
+            } => Some((1, true)),
+            ButtonEvent { .. } => None,
+        };
+        if let Some((channel, up)) = adjust {
+            cx.shared.desired_out.lock(|desired_out| desired_out.step(channel, up));
         }
+        send(cx.shared.ui, UiMessage::Button(event));
     }
 
-    #[task(binds = ADC0_RESRDY, local = [i_adc0], shared = [inputs])]
+    #[task(binds = ADC0_RESRDY, local = [i_adc0, decimator: Decimator = Decimator::new()], shared = [inputs, trigger, event_overruns, capture], priority = 4)]
     fn adc0_rdy(mut cx: adc0_rdy::Context) {
         let Some(sample) = cx.local.i_adc0.service_interrupt_ready() else {
             return;
         };
-        cx.shared.inputs.raw_adc_a0 = sample;
+        cx.shared.inputs.lock(|inputs| inputs.raw[0] = sample);
+        if cx.shared.capture.lock(|c| c.sample(0, sample, DWT::cycle_count())) {
+            render::spawn().ok();
+        }
+
+        let Trigger::Adc { decimation } = cx.shared.trigger.lock(|t| *t) else {
+            return;
+        };
+        if cx.local.decimator.sample(decimation) && control_event::spawn().is_err() {
+            // Previous run hasn't finished yet
+            cx.shared.event_overruns.lock(|o| *o += 1);
+        }
     }
 
-    #[task(binds = ADC1_RESRDY, local = [i_adc1], shared = [inputs])]
+    #[task(binds = ADC1_RESRDY, local = [i_adc1, scanner: Scanner = Scanner::new()], shared = [inputs, capture], priority = 4)]
     fn adc1_rdy(mut cx: adc1_rdy::Context) {
         let Some(sample) = cx.local.i_adc1.service_interrupt_ready() else {
             return;
         };
-        cx.shared.inputs.raw_adc_a1 = sample;
-    }
-
-    #[task(shared = [inputs, outputs, state])]
-    fn print_state(cx: print_state::Context) {
-        fn fmt(num: usize, side: &crate::logics::Side, adc: u16, dac: u16, txt: &str) -> ArrayString<[u8; 256]> {
-            let mut buf = ArrayString::new();
-            fn to_voltage(raw: u16) -> f32 {
-                (raw as f32) / 4096.0 * 3300f32
-            }
-            write!(&mut buf,
-r"ADC{}:
- Raw:
-   {:>9}
-   {:>7.2}mV
- Converted:
-   {:>08.4}V
-{}:
-  Desired Output:
-    {:>05.1}V
-  Raw:
-    {:>9}
-    {:>7.2}mV
-  Real:
-    {:>05.1}V
-",
-                   num,
-                   adc,
-                   to_voltage(adc),
-                   side.input,
-                   txt,
-                   side.desired_output,
-                   dac,
-                   to_voltage(dac),
-                   side.real_output
-            ).expect("!write");
-            buf
-        }
-        {
-            // let sample = 1;
-            // const MAX_VOLTAGE_RATIO: f32 = 3f32 / 3.3;
-            // let percent = sample as f32 / 4096f32;
-            // let volt = percent * 3300f32;
-            // let real = (percent / MAX_VOLTAGE_RATIO) * 20000f32;
-            // let mut buf = ArrayString::new();
-            // write!(&mut buf, "A1 Raw:{:>9.2}mV\n   Real:{:>8.2}mV", volt, real).expect("!write");
-            let buf = fmt(0, &cx.shared.state.left, cx.shared.inputs.raw_adc_a0, cx.shared.outputs.dac1, "Left to Right");
-            print::spawn(buf, Point::new(5, 30)).ok();
-            let buf = fmt(1, &cx.shared.state.right, cx.shared.inputs.raw_adc_a1, cx.shared.outputs.dac0, "Right to Left");
-            print::spawn(buf, Point::new(160, 30)).ok();
+        match cx.local.scanner.sample(unsafe { &*ADC1::ptr() }, INTERNAL_SCAN_EVERY, &CURRENT_SENSE_AIN, sample) {
+            Sample::External(raw) => {
+                cx.shared.inputs.lock(|inputs| inputs.raw[1] = raw);
+                if cx.shared.capture.lock(|c| c.sample(1, raw, DWT::cycle_count())) {
+                    render::spawn().ok();
+                }
+            }
+            Sample::Internal(channel, raw) => cx.shared.inputs.lock(|inputs| channel.store(&mut inputs.internal, raw)),
+            Sample::Sensor(sensor, raw) => cx.shared.inputs.lock(|inputs| inputs.current[sensor] = raw),
+            Sample::Discarded => {}
         }
+    }
+
+    #[task(shared = [inputs, outputs, state, policy, desired_out, profiles, players, stats, energy, charger, batteries, ui], priority = 1)]
+    fn print_state(mut cx: print_state::Context) {
+        let profiles = (cx.shared.profiles, cx.shared.players)
+            .lock(|profiles, players| core::array::from_fn(|i| players[i].progress(&profiles[i])));
+        let stats = cx.shared.stats.lock(|s| *s);
+        let energy = cx.shared.energy.lock(|e| e.channels);
+        let charge = cx.shared.charger.lock(|c| c.status(millis()));
+        let batteries = cx.shared.batteries.lock(|b| b.status());
+        let view = (cx.shared.inputs, cx.shared.outputs, cx.shared.state, cx.shared.policy, cx.shared.desired_out)
+            .lock(|inputs, outputs, state, policy, desired_out| StateView {
+                state: state.clone(),
+                inputs: *inputs,
+                outputs: *outputs,
+                internal: A1_INPUT.readings(&inputs.internal),
+                policy: *policy,
+                tracking: desired_out.tracking,
+                channels: desired_out.config,
+                fine: desired_out.fine,
+                profiles,
+                stats,
+                energy,
+                charge,
+                batteries,
+            });
+        send(cx.shared.ui, UiMessage::State(view));
         print_state::spawn_after(200.millis()).unwrap();
     }
 
+    #[task(shared = [serial, control_timing, trigger, event_overruns, dac, dac_writes, inputs, profiles, players, stats, energy, charger, mppt, batteries], priority = 1)]
+    fn telemetry(mut cx: telemetry::Context) {
+        let profiles = (cx.shared.profiles, cx.shared.players)
+            .lock(|profiles, players| core::array::from_fn::<_, CHANNELS, _>(|i| players[i].progress(&profiles[i])));
+        let internal = cx.shared.inputs.lock(|inputs| inputs.internal);
+        let stats = cx.shared.stats.lock(|s| *s);
+        let energy = cx.shared.energy.lock(|e| e.channels);
+        let charge = cx.shared.charger.lock(|c| c.status(millis()));
+        let mppt = cx.shared.mppt.lock(|m| m.status());
+        let batteries = cx.shared.batteries.lock(|b| b.status());
+        let timing = cx.shared.control_timing.lock(|t| *t);
+        let trigger = cx.shared.trigger.lock(|t| *t);
+        let overruns = cx.shared.event_overruns.lock(|o| *o);
+        let dac_overwritten = cx.shared.dac.lock(|dac| dac.overwritten());
+        let dac_writes = cx.shared.dac_writes.lock(|w| *w);
+        cx.shared.serial.lock(|serial| {
+            crate::telemetry::timing(serial, "control", &timing).ok();
+            crate::telemetry::trigger(serial, &trigger, overruns).ok();
+            crate::telemetry::dac(serial, dac_overwritten, &dac_writes).ok();
+            crate::telemetry::internal(serial, &A1_INPUT.readings(&internal)).ok();
+            for (channel, progress) in profiles.iter().enumerate() {
+                crate::telemetry::profile(serial, channel, progress).ok();
+            }
+            for signal in ChannelStats::signals() {
+                crate::telemetry::stats(serial, signal, &stats.get(signal).summary()).ok();
+            }
+            for (channel, counter) in energy.iter().enumerate() {
+                crate::telemetry::energy(serial, channel, counter).ok();
+            }
+            crate::telemetry::charge(serial, &charge).ok();
+            crate::telemetry::mppt(serial, &mppt).ok();
+            for (side, status) in batteries.iter().enumerate() {
+                if let Some(status) = status {
+                    crate::telemetry::battery(serial, side, status).ok();
+                }
+            }
+        });
+        telemetry::spawn_after(1000.millis()).unwrap();
+    }
+
+    // Applies a command from the host or the UI, answering the host
+    #[task(shared = [store, fault, policy, trigger, tables, programs, desired_out, sequence, sequencer, profiles, players, sweep, capture, stats, energy, sensors, charger, mppt, sources, batteries, serial, ui], priority = 1, capacity = 4)]
+    fn command(mut cx: command::Context, cmd: Command, origin: Origin) {
+        let result = match cmd {
+            Command::Policy(policy) => {
+                cx.shared.policy.lock(|p| *p = policy);
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Policy {:?}", policy)));
+                Ok(())
+            }
+            // The control task checks it every period, so a switch to polled
+            // takes over from the ADC within one
+            Command::Trigger(trigger) => {
+                cx.shared.trigger.lock(|t| *t = trigger);
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Control trigger: {:?}", trigger)));
+                Ok(())
+            }
+            Command::Set { channel, level } => {
+                cx.shared.desired_out.lock(|desired_out| {
+                    desired_out.check(channel).map_err(|e| e.name())?;
+                    let config = &desired_out.config[channel];
+                    if !config.allows(level) {
+                        return Err("level out of range");
+                    }
+                    desired_out.set(channel, level);
+                    Ok(())
+                })
+            }
+            Command::StepSize { fine } => {
+                cx.shared.desired_out.lock(|desired_out| desired_out.fine = fine);
+                Ok(())
+            }
+            Command::Channel { channel, update } => {
+                let (config, old) = cx.shared.desired_out.lock(|desired_out| {
+                    let config = update.apply(&desired_out.config[channel]);
+                    if !config.is_valid() {
+                        return Err("bad channel config");
+                    }
+                    let old = core::mem::replace(&mut desired_out.config[channel], config);
+                    // Pulled back inside the new limits
+                    let level = desired_out.levels[channel];
+                    desired_out.set(channel, level);
+                    Ok((config, old))
+                })?;
+                // The window limits are in volts, so its codes move with the full scale.
+                // Left alone while a fault is latched, clearing it re-arms the window.
+                if config.full_scale != old.full_scale {
+                    let input = ROUTES[channel].input;
+                    cx.shared.fault.lock(|fault| if fault.is_none() {
+                        ANALOG_INPUTS[input].configure_window(adc_registers(input), config.full_scale);
+                    });
+                }
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Channel {}: {}..{}{}, full scale {}",
+                                                                        channel, config.min, config.max, config.units, config.full_scale)));
+                Ok(())
+            }
+            Command::Table { channel, table } => {
+                let points = table.points().len();
+                cx.shared.tables.lock(|tables| tables[channel] = table);
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Table {} loaded, {} points", channel, points)));
+                Ok(())
+            }
+            Command::Expr { channel, program } => {
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("out{} = {}", channel, program.source())));
+                cx.shared.programs.lock(|programs| programs[channel] = program);
+                Ok(())
+            }
+            Command::Track(tracking) => {
+                let result = cx.shared.desired_out.lock(|desired_out| {
+                    // A follower can't be driven by anything else as well
+                    let followers = (0..CHANNELS).filter(|c| tracking.map_or(false, |t| t.leader != *c));
+                    for channel in followers {
+                        if let Some(owner) = desired_out.owners[channel] {
+                            return Err(SetpointError::Owned(owner).name());
+                        }
+                    }
+                    desired_out.tracking = tracking;
+                    Ok(())
+                });
+                match tracking {
+                    _ if result.is_err() => {}
+                    Some(t) => cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!(
+                        "Tracking channel {}: ratio {} offset {}V midpoint {}V", t.leader, t.ratio, t.offset, t.midpoint))),
+                    None => cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Tracking off"))),
+                };
+                result
+            }
+            Command::Sequence(new) => {
+                let steps = new.steps.len();
+                let result = (cx.shared.sequence, cx.shared.sequencer).lock(|sequence, sequencer| {
+                    if sequencer.is_running() {
+                        return Err("sequence running");
+                    }
+                    // Gains as at boot with this sequence, so a new one waits for power up
+                    // and an empty one leaves every output enabled
+                    *sequencer = Sequencer::new(&new);
+                    *sequence = new;
+                    Ok(())
+                });
+                if result.is_ok() {
+                    cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Sequence loaded, {} steps", steps)));
+                }
+                result
+            }
+            Command::Power(up) => {
+                let phase = if up { Phase::Up } else { Phase::Down };
+                cx.shared.sequencer.lock(|s| s.start(phase, millis()));
+                power_sequence::spawn().ok();
+                Ok(())
+            }
+            Command::Profile { channel, profile } => {
+                let segments = profile.segments.len();
+                // The player may point past the end of the new profile
+                (cx.shared.profiles, cx.shared.players, cx.shared.desired_out).lock(|profiles, players, desired_out| {
+                    if !profile.is_valid(&desired_out.config[channel]) {
+                        return Err("level out of range");
+                    }
+                    players[channel].abort();
+                    desired_out.release(channel, Owner::Profile);
+                    profiles[channel] = profile;
+                    Ok(())
+                })?;
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Profile {} loaded, {} segments", channel, segments)));
+                Ok(())
+            }
+            Command::Play { channel, action } => {
+                let now = millis();
+                let result = (cx.shared.players, cx.shared.desired_out).lock(|players, desired_out| {
+                    let player = &mut players[channel];
+                    match action {
+                        Play::Start => {
+                            desired_out.claim(channel, Owner::Profile).map_err(|e| e.name())?;
+                            player.start(now, desired_out.levels[channel]);
+                        }
+                        Play::Pause => player.pause(now),
+                        Play::Resume => player.resume(now),
+                        Play::Abort => {
+                            player.abort();
+                            desired_out.release(channel, Owner::Profile);
+                        }
+                    }
+                    Ok(())
+                });
+                if result.is_ok() {
+                    cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Profile {}: {:?}", channel, action)));
+                    // Already pending when another channel is running
+                    profile_tick::spawn().ok();
+                }
+                result
+            }
+            Command::SweepStart(config) => {
+                let result = (cx.shared.sweep, cx.shared.desired_out).lock(|sweep, desired_out| {
+                    config.validate(&desired_out.config[config.channel]).map_err(|e| e.name())?;
+                    desired_out.claim(config.channel, Owner::Sweep).map_err(|e| e.name())?;
+                    // Restarted on the other channel
+                    if let Some(old) = sweep.config().filter(|old| old.channel != config.channel) {
+                        desired_out.release(old.channel, Owner::Sweep);
+                    }
+                    let level = sweep.start(config, millis());
+                    desired_out.set_by(config.channel, Owner::Sweep, level);
+                    Ok(())
+                });
+                if result.is_ok() {
+                    cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Sweep out{} {}..{}V, {} points",
+                                                                            config.channel, config.start, config.stop, config.points)));
+                    sweep_tick::spawn().ok();
+                }
+                result
+            }
+            Command::SweepAbort => {
+                (cx.shared.sweep, cx.shared.desired_out).lock(|sweep, desired_out| {
+                    if let Some(config) = sweep.config() {
+                        desired_out.release(config.channel, Owner::Sweep);
+                    }
+                    sweep.abort();
+                });
+                Ok(())
+            }
+            Command::SweepDump => sweep_dump::spawn(0, 0).map_err(|_| "dump running"),
+            Command::CaptureStart(config) => {
+                let full_scale = cx.shared.desired_out.lock(|desired_out| input_full_scale(&desired_out.config, config.input));
+                let scale = Scale { input: ANALOG_INPUTS[config.input], full_scale };
+                let result = cx.shared.capture.lock(|capture| {
+                    if capture.is_held() {
+                        return Err("capture being read");
+                    }
+                    capture.configure(config, scale);
+                    Ok(())
+                });
+                if result.is_ok() {
+                    cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Capture in{} {} samples, {:?}",
+                                                                            config.input, config.length, config.mode)));
+                }
+                result
+            }
+            Command::CaptureArm => cx.shared.capture.lock(|capture| {
+                if capture.is_held() {
+                    return Err("capture being read");
+                }
+                if capture.config().is_none() {
+                    return Err("capture not set up");
+                }
+                capture.arm();
+                Ok(())
+            }),
+            Command::CaptureStop => cx.shared.capture.lock(|capture| {
+                if capture.is_held() {
+                    return Err("capture being read");
+                }
+                capture.stop();
+                Ok(())
+            }),
+            Command::CaptureRead => {
+                let ready = cx.shared.capture.lock(|capture| {
+                    let ready = capture.state() == CaptureState::Ready && !capture.is_held();
+                    if ready {
+                        capture.hold();
+                    }
+                    ready
+                });
+                match ready {
+                    // The block goes out after the reply
+                    true => capture_send::spawn(0).map_err(|_| "read running"),
+                    false => Err("no record"),
+                }
+            }
+            Command::Stats(signal) => {
+                let stats = cx.shared.stats.lock(|s| *s);
+                cx.shared.serial.lock(|serial| {
+                    for signal in ChannelStats::signals().filter(|s| signal.map_or(true, |want| want == *s)) {
+                        crate::telemetry::stats(serial, signal, &stats.get(signal).summary()).ok();
+                    }
+                });
+                Ok(())
+            }
+            Command::StatsReset(signal) => {
+                cx.shared.stats.lock(|s| s.reset(signal));
+                Ok(())
+            }
+            Command::Energy => {
+                let energy = cx.shared.energy.lock(|e| e.channels);
+                cx.shared.serial.lock(|serial| {
+                    for (channel, counter) in energy.iter().enumerate() {
+                        crate::telemetry::energy(serial, channel, counter).ok();
+                    }
+                });
+                Ok(())
+            }
+            Command::EnergyReset(channel) => {
+                let energy = cx.shared.energy.lock(|e| {
+                    e.reset(channel);
+                    e.channels
+                });
+                // Saved right away so a reset can't come back after a power cycle
+                cx.shared.store.lock(|store| store.save_counters(&energy)).map_err(|e| e.name())
+            }
+            Command::Sensor { index, sensor } => {
+                cx.shared.sensors.lock(|sensors| sensors[index] = sensor);
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Sensor {}: {}V/A, {}V at 0A",
+                                                                        index, sensor.volts_per_amp, sensor.offset)));
+                Ok(())
+            }
+            Command::SensorUnfitted(index) => {
+                cx.shared.sensors.lock(|sensors| sensors[index].fitted = false);
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Sensor {}: not fitted", index)));
+                Ok(())
+            }
+            Command::ChargeStart(config) => {
+                let result = (cx.shared.charger, cx.shared.desired_out).lock(|charger, desired_out| {
+                    config.validate(&desired_out.config[config.channel]).map_err(|e| e.name())?;
+                    desired_out.claim(config.channel, Owner::Charge).map_err(|e| e.name())?;
+                    // Restarted on the other channel
+                    if let Some(old) = charger.config().filter(|old| old.channel != config.channel) {
+                        desired_out.release(old.channel, Owner::Charge);
+                    }
+                    charger.start(config, millis(), desired_out.level(config.channel));
+                    Ok(())
+                });
+                if result.is_ok() {
+                    cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Charge out{} {}A to {}V",
+                                                                            config.channel, config.current_limit, config.cv_volts)));
+                    // Already pending when a charge was running
+                    charge_tick::spawn().ok();
+                }
+                result
+            }
+            Command::ChargeStop => {
+                (cx.shared.charger, cx.shared.desired_out).lock(|charger, desired_out| {
+                    if let Some(config) = charger.config().filter(|_| charger.state().is_charging()) {
+                        desired_out.set_by(config.channel, Owner::Charge, 0.0);
+                        desired_out.release(config.channel, Owner::Charge);
+                    }
+                    charger.stop();
+                });
+                Ok(())
+            }
+            Command::MpptStart(config) => {
+                let sensed = cx.shared.sensors.lock(|sensors| ROUTES[config.channel].fitted_sensor(sensors).is_some());
+                let result = (cx.shared.mppt, cx.shared.desired_out).lock(|mppt, desired_out| {
+                    desired_out.claim(config.channel, Owner::Mppt).map_err(|e| e.name())?;
+                    // Restarted on the other channel
+                    if let Some(old) = mppt.config().filter(|old| old.channel != config.channel) {
+                        desired_out.release(old.channel, Owner::Mppt);
+                    }
+                    let level = mppt.start(config, millis(), desired_out.level(config.channel), sensed);
+                    desired_out.set_by(config.channel, Owner::Mppt, level);
+                    Ok(mppt.status().method)
+                });
+                result.map(|method| {
+                    cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("MPPT out{} ({})", config.channel, method.name())));
+                    // Already pending when the tracker was running
+                    mppt_tick::spawn().ok();
+                })
+            }
+            Command::MpptStop => {
+                (cx.shared.mppt, cx.shared.desired_out).lock(|mppt, desired_out| {
+                    if let Some(config) = mppt.config() {
+                        desired_out.release(config.channel, Owner::Mppt);
+                    }
+                    mppt.stop();
+                });
+                Ok(())
+            }
+            Command::Detection { side, detection } => {
+                cx.shared.sources.lock(|s| s.detection[side] = detection);
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Source {} at {}V, hysteresis {}V",
+                                                                        side, detection.threshold, detection.hysteresis)));
+                Ok(())
+            }
+            Command::SourcePriority(priority) => {
+                cx.shared.sources.lock(|s| s.priority = priority);
+                cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Source priority {}", priority.name())));
+                Ok(())
+            }
+            Command::Battery { side, profile } => {
+                let channel = cx.shared.desired_out.lock(|desired_out| desired_out.config[side]);
+                if profile.as_ref().map_or(false, |p| !p.is_valid(&channel)) {
+                    Err("bad battery profile")
+                } else {
+                    match &profile {
+                        Some(p) => cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!(
+                            "Battery {}: {} x{}, cutoff {}V", side, p.chemistry.name(), p.cells, p.cutoff))),
+                        None => cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Battery {} off", side))),
+                    };
+                    cx.shared.batteries.lock(|b| b.set(side, profile));
+                    Ok(())
+                }
+            }
+            Command::BatteryOcv { side, ocv } => {
+                cx.shared.batteries.lock(|b| match b.profiles[side].as_mut() {
+                    Some(profile) => {
+                        profile.ocv = ocv;
+                        Ok(())
+                    }
+                    None => Err("no battery on that side"),
+                })
+            }
+            Command::FaultClear => {
+                // The windows are re-armed with the fault locked, so one that's
+                // still out trips again as soon as the lock is released
+                let config = cx.shared.desired_out.lock(|desired_out| desired_out.config);
+                let cleared = cx.shared.fault.lock(|fault| {
+                    let cleared = fault.take();
+                    for (input, analog) in ANALOG_INPUTS.iter().enumerate() {
+                        analog.configure_window(adc_registers(input), input_full_scale(&config, input));
+                    }
+                    cleared
+                });
+                match cleared {
+                    Some(fault) => {
+                        cx.shared.ui.lock(|ui| ui.log(Level::Info, format_args!("Cleared {}", fault.name())));
+                        Ok(())
+                    }
+                    None => Err("no fault"),
+                }
+            }
+            Command::Save => {
+                let settings = Settings {
+                    policy: cx.shared.policy.lock(|p| *p),
+                    tables: cx.shared.tables.lock(|t| t.clone()),
+                    sequence: cx.shared.sequence.lock(|s| s.clone()),
+                    profiles: cx.shared.profiles.lock(|p| p.clone()),
+                    sensors: cx.shared.sensors.lock(|s| *s),
+                    batteries: cx.shared.batteries.lock(|b| b.profiles.clone()),
+                    detection: cx.shared.sources.lock(|s| s.detection),
+                    source_priority: cx.shared.sources.lock(|s| s.priority),
+                    channels: cx.shared.desired_out.lock(|d| d.config),
+                    programs: cx.shared.programs.lock(|p| p.clone()),
+                    trigger: cx.shared.trigger.lock(|t| *t),
+                };
+                // Blocks for the erase, nothing below this priority minds
+                cx.shared.store.lock(|store| store.save(&settings)).map_err(|e| e.name())
+            }
+        };
+        match origin {
+            Origin::Host => cx.shared.serial.lock(|serial| match result {
+                Ok(()) => {
+                    serial.write(b"ok\r\n");
+                }
+                Err(e) => {
+                    write!(serial, "error {}\r\n", e).ok();
+                }
+            }),
+            // The UI has no one to answer, its errors go on the console
+            Origin::Ui => if let Err(e) = result {
+                cx.shared.ui.lock(|ui| ui.log(Level::Warn, format_args!("Command failed: {}", e)));
+            },
+        }
+    }
+
+    // Parses a line from the host, replying right away if it can't be run
+    fn received(serial: &mut Serial, line: CommandLine) {
+        match crate::command::parse(&line) {
+            Ok(cmd) => if command::spawn(cmd, Origin::Host).is_err() {
+                serial.write(b"error busy\r\n");
+            },
+            Err(e) => {
+                write!(serial, "error {}\r\n", e.name()).ok();
+            }
+        }
+    }
+
+    // Every USB interrupt services the device and hands over complete lines
+    #[task(binds = USB_OTHER, shared = [serial], priority = 2)]
+    fn usb_other(mut cx: usb_other::Context) {
+        cx.shared.serial.lock(|serial| serial.poll(received));
+    }
+
+    #[task(binds = USB_SOF_HSOF, shared = [serial], priority = 2)]
+    fn usb_sof(mut cx: usb_sof::Context) {
+        cx.shared.serial.lock(|serial| serial.poll(received));
+    }
+
+    #[task(binds = USB_TRCPT0, shared = [serial], priority = 2)]
+    fn usb_trcpt0(mut cx: usb_trcpt0::Context) {
+        cx.shared.serial.lock(|serial| serial.poll(received));
+    }
+
+    #[task(binds = USB_TRCPT1, shared = [serial], priority = 2)]
+    fn usb_trcpt1(mut cx: usb_trcpt1::Context) {
+        cx.shared.serial.lock(|serial| serial.poll(received));
+    }
+
 
     // task from macro does not currently work using pre-generated
     // ```
     // use crate::buttons::prelude::*;
     // button_interrupt!(button_ctr, button);
     // ```
-    #[task(binds = EIC_EXTINT_3, shared = [ button_ctr ])]
+    #[task(binds = EIC_EXTINT_3, shared = [ button_ctr ], priority = 2)]
     fn _btn_intr_3(mut cx: _btn_intr_3::Context) {
         if let Some(event) = cx.shared.button_ctr.interrupt_extint3() {
             button::spawn(event).ok();
         }
     }
 
-    #[task(binds = EIC_EXTINT_4, shared = [ button_ctr ])]
+    #[task(binds = EIC_EXTINT_4, shared = [ button_ctr ], priority = 2)]
     fn _btn_intr_4(mut cx: _btn_intr_4::Context) {
         if let Some(event) = cx.shared.button_ctr.interrupt_extint4() {
             button::spawn(event).ok();
         }
     }
 
-    #[task(binds = EIC_EXTINT_5, shared = [ button_ctr ])]
+    #[task(binds = EIC_EXTINT_5, shared = [ button_ctr ], priority = 2)]
     fn _btn_intr_5(mut cx: _btn_intr_5::Context) {
         if let Some(event) = cx.shared.button_ctr.interrupt_extint5() {
             button::spawn(event).ok();
         }
     }
 
-    #[task(binds = EIC_EXTINT_7, shared = [ button_ctr ])]
+    #[task(binds = EIC_EXTINT_7, shared = [ button_ctr ], priority = 2)]
     fn _btn_intr_7(mut cx: _btn_intr_7::Context) {
         if let Some(event) = cx.shared.button_ctr.interrupt_extint7() {
             button::spawn(event).ok();
         }
     }
 
-    #[task(binds = EIC_EXTINT_10, shared = [ button_ctr ])]
+    #[task(binds = EIC_EXTINT_10, shared = [ button_ctr ], priority = 2)]
     fn _btn_intr_10(mut cx: _btn_intr_10::Context) {
         if let Some(event) = cx.shared.button_ctr.interrupt_extint10() {
             button::spawn(event).ok();
         }
     }
 
-    #[task(binds = EIC_EXTINT_11, shared = [ button_ctr ])]
+    #[task(binds = EIC_EXTINT_11, shared = [ button_ctr ], priority = 2)]
     fn _btn_intr_11(mut cx: _btn_intr_11::Context) {
         if let Some(event) = cx.shared.button_ctr.interrupt_extint11() {
             button::spawn(event).ok();
         }
     }
 
-    #[task(binds = EIC_EXTINT_12, shared = [ button_ctr ])]
+    #[task(binds = EIC_EXTINT_12, shared = [ button_ctr ], priority = 2)]
     fn _btn_intr_12(mut cx: _btn_intr_12::Context) {
         if let Some(event) = cx.shared.button_ctr.interrupt_extint12() {
             button::spawn(event).ok();
@@ -0,0 +1,493 @@
+use crate::logics::{Route, Side, CHANNELS};
+use crate::expr::{Env, Program};
+use crate::table::Table;
+
+/// Decides every channel's real output.
+pub trait TransferPolicy {
+    fn apply(&self, channels: &mut [Side], routes: &[Route]);
+}
+
+/// A policy where each channel only looks at the channel it bridges from.
+pub trait ChannelRule {
+    fn output_for(&self, channel: usize, source: &Side, want: &Side) -> f32;
+}
+
+impl<T: ChannelRule> TransferPolicy for T {
+    fn apply(&self, channels: &mut [Side], routes: &[Route]) {
+        for (i, route) in routes.iter().enumerate() {
+            channels[i].real_output = self.output_for(i, &channels[route.source], &channels[i]);
+        }
+    }
+}
+
+/// Input level that counts as a source on one side. Once present it stays
+/// present until the input drops `hysteresis` below the threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Detection {
+    pub threshold: f32,
+    pub hysteresis: f32,
+}
+
+impl Default for Detection {
+    fn default() -> Self {
+        Self { threshold: 1.0, hysteresis: 0.0 }
+    }
+}
+
+/// Which source wins when more than one side presents one. Bridge only
+/// passes the preferred source on, so with sources on both sides this
+/// already leaves a single side driven, and `Policy::Priority` has nothing
+/// left to pick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourcePriority {
+    Left,
+    Right,
+    HigherVoltage,
+    LastConnected,
+}
+
+impl SourcePriority {
+    pub const ALL: [SourcePriority; 4] = [
+        SourcePriority::Left,
+        SourcePriority::Right,
+        SourcePriority::HigherVoltage,
+        SourcePriority::LastConnected,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SourcePriority::Left => "left",
+            SourcePriority::Right => "right",
+            SourcePriority::HigherVoltage => "higher",
+            SourcePriority::LastConnected => "last",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|p| p.name() == name)
+    }
+}
+
+/// Source detection for every side, remembering which sides are present
+/// and in what order they turned up.
+#[derive(Debug, Clone, Copy)]
+pub struct Sources {
+    pub detection: [Detection; CHANNELS],
+    pub priority: SourcePriority,
+    present: [bool; CHANNELS],
+    // connection count when each side last became present
+    connected: [u32; CHANNELS],
+    connections: u32,
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Self::new([Detection::default(); CHANNELS], SourcePriority::Left)
+    }
+}
+
+impl Sources {
+    pub fn new(detection: [Detection; CHANNELS], priority: SourcePriority) -> Self {
+        Self { detection, priority, present: [false; CHANNELS], connected: [0; CHANNELS], connections: 0 }
+    }
+
+    /// Marks which sides present a source, and which one of them is preferred.
+    pub fn detect(&mut self, channels: &mut [Side]) {
+        for (i, side) in channels.iter_mut().enumerate().take(CHANNELS) {
+            let Detection { threshold, hysteresis } = self.detection[i];
+            let present = if self.present[i] { side.input >= threshold - hysteresis } else { side.input >= threshold };
+            if present && !self.present[i] {
+                self.connections = self.connections.wrapping_add(1);
+                self.connected[i] = self.connections;
+            }
+            self.present[i] = present;
+            side.present = present;
+        }
+
+        let last = CHANNELS - 1;
+        let preferred = (0..channels.len().min(CHANNELS)).filter(|i| self.present[*i]).reduce(|best, i| {
+            let wins = match self.priority {
+                SourcePriority::Left => i == 0,
+                SourcePriority::Right => i == last,
+                SourcePriority::HigherVoltage => channels[i].input > channels[best].input,
+                SourcePriority::LastConnected => self.connected[i].wrapping_sub(self.connected[best]) as i32 > 0,
+            };
+            if wins { i } else { best }
+        });
+        for (i, side) in channels.iter_mut().enumerate() {
+            side.preferred = preferred == Some(i);
+        }
+    }
+}
+
+/// Pass the desired level when there's no source, the source itself if
+/// it's lower than desired, else shut off. When this side presents a
+/// source as well, only the preferred source is passed on, by the same
+/// rule.
+pub struct Bridge;
+
+impl ChannelRule for Bridge {
+    fn output_for(&self, _channel: usize, source: &Side, want: &Side) -> f32 {
+        if !source.present {
+            want.desired_output
+        } else if want.present && !source.preferred {
+            0.0
+        } else if source.input < want.desired_output {
+            source.input
+        } else {
+            0.0
+        }
+    }
+}
+
+pub struct AlwaysPass;
+
+impl ChannelRule for AlwaysPass {
+    fn output_for(&self, _channel: usize, _source: &Side, want: &Side) -> f32 {
+        want.desired_output
+    }
+}
+
+/// Follow the source, never above the desired level.
+pub struct ClampToDesired;
+
+impl ChannelRule for ClampToDesired {
+    fn output_for(&self, _channel: usize, source: &Side, want: &Side) -> f32 {
+        source.input.min(want.desired_output).max(0.0)
+    }
+}
+
+/// Bridge, but when more than one channel would be driven only `channel` is.
+/// That happens with at most one source present. With both present Bridge
+/// already drives one side at most, so this does nothing more and which
+/// side's source wins is up to `SourcePriority`.
+pub struct PrioritySide {
+    pub channel: usize,
+}
+
+impl TransferPolicy for PrioritySide {
+    fn apply(&self, channels: &mut [Side], routes: &[Route]) {
+        Bridge.apply(channels, routes);
+        let driven = channels.iter().filter(|side| side.real_output > 0.0).count();
+        if driven > 1 {
+            channels.iter_mut().enumerate()
+                .filter(|(i, _)| *i != self.channel)
+                .for_each(|(_, side)| side.real_output = 0.0);
+        }
+    }
+}
+
+/// Each channel's own table applied to the source's input.
+pub struct Lookup<'a> {
+    pub tables: &'a [Table],
+}
+
+impl ChannelRule for Lookup<'_> {
+    fn output_for(&self, channel: usize, source: &Side, _want: &Side) -> f32 {
+        self.tables.get(channel).map_or(0.0, |table| table.lookup(source.input))
+    }
+}
+
+/// Each channel's own expression, reading every channel's input and setpoint.
+pub struct Formula<'a> {
+    pub programs: &'a [Program],
+    // seconds since boot
+    pub time: f32,
+}
+
+impl TransferPolicy for Formula<'_> {
+    fn apply(&self, channels: &mut [Side], _routes: &[Route]) {
+        let mut inputs = [0.0; CHANNELS];
+        let mut setpoints = [0.0; CHANNELS];
+        for ((input, setpoint), side) in inputs.iter_mut().zip(setpoints.iter_mut()).zip(channels.iter()) {
+            *input = side.input;
+            *setpoint = side.desired_output;
+        }
+        let env = Env { inputs: &inputs, setpoints: &setpoints, time: self.time };
+
+        for (side, program) in channels.iter_mut().zip(self.programs.iter()) {
+            let out = program.eval(&env);
+            // NaN from a 0/0 ends up as 0V too, State::from limits the rest
+            side.real_output = if out > 0.0 { out } else { 0.0 };
+        }
+    }
+}
+
+pub struct Disabled;
+
+impl ChannelRule for Disabled {
+    fn output_for(&self, _channel: usize, _source: &Side, _want: &Side) -> f32 {
+        0.0
+    }
+}
+
+/// Runtime selectable policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Bridge,
+    AlwaysPass,
+    ClampToDesired,
+    Priority(usize),
+    // These need the tables or programs, run through Lookup or Formula
+    // rather than this enum
+    Table,
+    Disabled,
+    Expr,
+}
+
+impl Policy {
+    // Settings store the index into this, so new policies go at the end
+    pub const ALL: [Policy; 8] = [
+        Policy::Bridge,
+        Policy::AlwaysPass,
+        Policy::ClampToDesired,
+        Policy::Priority(0),
+        Policy::Priority(1),
+        Policy::Table,
+        Policy::Disabled,
+        Policy::Expr,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Policy::Bridge => "bridge",
+            Policy::AlwaysPass => "pass",
+            Policy::ClampToDesired => "clamp",
+            Policy::Priority(_) => "priority",
+            Policy::Table => "table",
+            Policy::Expr => "expr",
+            Policy::Disabled => "disabled",
+        }
+    }
+
+    pub fn parse(name: &str, arg: Option<&str>) -> Option<Policy> {
+        Some(match name {
+            "bridge" => Policy::Bridge,
+            "pass" => Policy::AlwaysPass,
+            "clamp" => Policy::ClampToDesired,
+            "priority" => match arg?.parse().ok()? {
+                channel if channel < CHANNELS => Policy::Priority(channel),
+                _ => return None,
+            },
+            "table" => Policy::Table,
+            "expr" => Policy::Expr,
+            "disabled" => Policy::Disabled,
+            _ => return None,
+        })
+    }
+
+    /// Next policy in `ALL`, for stepping through them from the buttons.
+    pub fn next(&self, step: isize) -> Policy {
+        let idx = Policy::ALL.iter().position(|p| p == self).unwrap_or(0) as isize;
+        let len = Policy::ALL.len() as isize;
+        Policy::ALL[(idx + step).rem_euclid(len) as usize]
+    }
+}
+
+impl TransferPolicy for Policy {
+    fn apply(&self, channels: &mut [Side], routes: &[Route]) {
+        match self {
+            Policy::Bridge => Bridge.apply(channels, routes),
+            Policy::AlwaysPass => AlwaysPass.apply(channels, routes),
+            Policy::ClampToDesired => ClampToDesired.apply(channels, routes),
+            Policy::Priority(channel) => PrioritySide { channel: *channel }.apply(channels, routes),
+            Policy::Table | Policy::Expr | Policy::Disabled => Disabled.apply(channels, routes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logics::ROUTES;
+
+    // input, desired, present, preferred
+    type Given = (f32, f32, bool, bool);
+
+    fn sides(given: [Given; CHANNELS]) -> [Side; CHANNELS] {
+        given.map(|(input, desired_output, present, preferred)| Side {
+            input,
+            desired_output,
+            present,
+            preferred,
+            ..Default::default()
+        })
+    }
+
+    fn outputs(policy: &impl TransferPolicy, given: [Given; CHANNELS]) -> [f32; CHANNELS] {
+        let mut channels = sides(given);
+        policy.apply(&mut channels, &ROUTES);
+        channels.map(|side| side.real_output)
+    }
+
+    // Each route takes its source from the other side, so channel 0 looks
+    // at channel 1's input and the other way round
+    const CASES: [(&str, [Given; CHANNELS]); 7] = [
+        ("no source", [(0.0, 5.0, false, false), (0.0, 12.0, false, false)]),
+        ("source below desired", [(0.0, 5.0, false, false), (3.0, 12.0, true, true)]),
+        ("source above desired", [(0.0, 5.0, false, false), (9.0, 12.0, true, true)]),
+        ("both present, left preferred", [(9.0, 5.0, true, true), (3.0, 12.0, true, false)]),
+        ("both present, right preferred", [(9.0, 5.0, true, false), (3.0, 12.0, true, true)]),
+        ("negative source", [(0.0, 5.0, false, false), (-1.0, 12.0, false, false)]),
+        ("both present, preferred above desired", [(15.0, 5.0, true, true), (3.0, 12.0, true, false)]),
+    ];
+
+    fn check(policy: &impl TransferPolicy, expected: [[f32; CHANNELS]; CASES.len()]) {
+        for ((name, given), expected) in CASES.iter().zip(expected) {
+            assert_eq!(outputs(policy, *given), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn bridge() {
+        check(&Bridge, [
+            [5.0, 12.0],
+            [3.0, 12.0],
+            [0.0, 12.0],
+            [0.0, 9.0],
+            [3.0, 0.0],
+            [5.0, 12.0],
+            // shut off, the same as with only that source
+            [0.0, 0.0],
+        ]);
+    }
+
+    #[test]
+    fn always_pass() {
+        check(&AlwaysPass, [[5.0, 12.0]; CASES.len()]);
+    }
+
+    #[test]
+    fn clamp_to_desired() {
+        check(&ClampToDesired, [
+            [0.0, 0.0],
+            [3.0, 0.0],
+            [5.0, 0.0],
+            [3.0, 9.0],
+            [3.0, 9.0],
+            [0.0, 0.0],
+            [3.0, 12.0],
+        ]);
+    }
+
+    #[test]
+    fn priority_side() {
+        // Bridge's outputs with only the priority side left when both are driven
+        check(&PrioritySide { channel: 0 }, [
+            [5.0, 0.0],
+            [3.0, 0.0],
+            [0.0, 12.0],
+            [0.0, 9.0],
+            [3.0, 0.0],
+            [5.0, 0.0],
+            [0.0, 0.0],
+        ]);
+        check(&PrioritySide { channel: 1 }, [
+            [0.0, 12.0],
+            [0.0, 12.0],
+            [0.0, 12.0],
+            [0.0, 9.0],
+            [3.0, 0.0],
+            [0.0, 12.0],
+            [0.0, 0.0],
+        ]);
+    }
+
+    #[test]
+    fn disabled() {
+        check(&Disabled, [[0.0; CHANNELS]; CASES.len()]);
+    }
+
+    #[test]
+    fn saved_indices_stay_put() {
+        // Settings written before expressions existed must load the same
+        let saved = [Policy::Bridge, Policy::AlwaysPass, Policy::ClampToDesired, Policy::Priority(0),
+                     Policy::Priority(1), Policy::Table, Policy::Disabled];
+        assert_eq!(Policy::ALL[..saved.len()], saved);
+    }
+
+    #[test]
+    fn enum_runs_the_matching_policy() {
+        for (_, given) in CASES {
+            assert_eq!(outputs(&Policy::Bridge, given), outputs(&Bridge, given));
+            assert_eq!(outputs(&Policy::AlwaysPass, given), outputs(&AlwaysPass, given));
+            assert_eq!(outputs(&Policy::ClampToDesired, given), outputs(&ClampToDesired, given));
+            assert_eq!(outputs(&Policy::Priority(1), given), outputs(&PrioritySide { channel: 1 }, given));
+            assert_eq!(outputs(&Policy::Disabled, given), [0.0; CHANNELS]);
+        }
+    }
+
+    const DESIRED: [f32; CHANNELS] = [10.0, 12.0];
+
+    // inputs, present, then the preferred side and Bridge's outputs for
+    // each of SourcePriority::ALL, one step after the other
+    type Step = (&'static str, [f32; CHANNELS], [bool; CHANNELS], [(Option<usize>, [f32; CHANNELS]); 4]);
+
+    const STEPS: [Step; 11] = [
+        ("nothing", [0.0, 0.0], [false, false], [(None, [10.0, 12.0]); 4]),
+        ("just under the threshold", [4.9, 0.0], [false, false], [(None, [10.0, 12.0]); 4]),
+        ("left arrives", [5.0, 0.0], [true, false], [(Some(0), [10.0, 5.0]); 4]),
+        ("within the hysteresis", [4.1, 0.0], [true, false], [(Some(0), [10.0, 4.1]); 4]),
+        ("below the hysteresis", [3.9, 0.0], [false, false], [(None, [10.0, 12.0]); 4]),
+        ("left back", [6.0, 0.0], [true, false], [(Some(0), [10.0, 6.0]); 4]),
+        ("right arrives higher", [6.0, 8.0], [true, true],
+         [(Some(0), [0.0, 6.0]), (Some(1), [8.0, 0.0]), (Some(1), [8.0, 0.0]), (Some(1), [8.0, 0.0])]),
+        ("left goes higher", [9.0, 8.0], [true, true],
+         [(Some(0), [0.0, 9.0]), (Some(1), [8.0, 0.0]), (Some(0), [0.0, 9.0]), (Some(1), [8.0, 0.0])]),
+        ("left drops out", [3.9, 8.0], [false, true], [(Some(1), [8.0, 12.0]); 4]),
+        ("left connects last", [6.0, 8.0], [true, true],
+         [(Some(0), [0.0, 6.0]), (Some(1), [8.0, 0.0]), (Some(1), [8.0, 0.0]), (Some(0), [0.0, 6.0])]),
+        ("equal, left keeps it", [8.0, 8.0], [true, true],
+         [(Some(0), [0.0, 8.0]), (Some(1), [8.0, 0.0]), (Some(0), [0.0, 8.0]), (Some(0), [0.0, 8.0])]),
+    ];
+
+    // Runs detection through STEPS with `priority`, handing each step's sides to `check`
+    fn detect_steps(priority: SourcePriority, mut check: impl FnMut(&Step, &[Side; CHANNELS])) {
+        let mut sources = Sources::new([Detection { threshold: 5.0, hysteresis: 1.0 }; CHANNELS], priority);
+        for step in STEPS.iter() {
+            let mut channels = [Side::default(); CHANNELS];
+            for (i, side) in channels.iter_mut().enumerate() {
+                side.input = step.1[i];
+                side.desired_output = DESIRED[i];
+            }
+            sources.detect(&mut channels);
+            check(step, &channels);
+        }
+    }
+
+    #[test]
+    fn detection_and_priority() {
+        for (p, priority) in SourcePriority::ALL.iter().enumerate() {
+            detect_steps(*priority, |(name, _, present, expected), channels| {
+                let (preferred, outputs) = expected[p];
+                assert_eq!(channels.map(|s| s.present), *present, "{} {}", priority.name(), name);
+                assert_eq!(channels.map(|s| s.preferred), [0, 1].map(|i| preferred == Some(i)), "{} {}", priority.name(), name);
+
+                let mut bridged = *channels;
+                Bridge.apply(&mut bridged, &ROUTES);
+                assert_eq!(bridged.map(|s| s.real_output), outputs, "{} {}", priority.name(), name);
+            });
+        }
+    }
+
+    #[test]
+    fn priority_policy_only_picks_without_two_sources() {
+        for priority in SourcePriority::ALL {
+            detect_steps(priority, |(name, _, present, _), channels| {
+                let mut bridged = *channels;
+                Bridge.apply(&mut bridged, &ROUTES);
+                for side in 0..CHANNELS {
+                    let mut prioritised = *channels;
+                    PrioritySide { channel: side }.apply(&mut prioritised, &ROUTES);
+                    let (bridged, prioritised) = (bridged.map(|s| s.real_output), prioritised.map(|s| s.real_output));
+                    if present.iter().all(|p| *p) {
+                        assert_eq!(prioritised, bridged, "{} {}", priority.name(), name);
+                    } else if bridged.iter().all(|out| *out > 0.0) {
+                        assert_eq!(prioritised[1 - side], 0.0, "{} {}", priority.name(), name);
+                        assert_eq!(prioritised[side], bridged[side], "{} {}", priority.name(), name);
+                    }
+                }
+            });
+        }
+    }
+}
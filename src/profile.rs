@@ -0,0 +1,306 @@
+use heapless::Vec;
+
+use crate::logics::ChannelConfig;
+
+pub const MAX_SEGMENTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    // jump to the target, then hold it
+    Step = 0,
+    // linear from wherever the previous segment ended
+    Ramp = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    // never 0, the player couldn't get past it
+    pub duration_ms: u32,
+    pub target: f32,
+    pub shape: Shape,
+}
+
+/// Setpoint over time for one channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub segments: Vec<Segment, MAX_SEGMENTS>,
+    // 0 repeats until stopped
+    pub loops: u16,
+}
+
+impl Profile {
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Whether every target is a level `limits` allows.
+    pub fn is_valid(&self, limits: &ChannelConfig) -> bool {
+        self.segments.iter().all(|s| limits.allows(s.target))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Idle,
+    Running,
+    Paused,
+    Done,
+}
+
+impl RunState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RunState::Idle => "idle",
+            RunState::Running => "running",
+            RunState::Paused => "paused",
+            RunState::Done => "done",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub state: RunState,
+    pub segment: usize,
+    pub segments: usize,
+    // counted from 1
+    pub pass: u16,
+    pub loops: u16,
+    pub elapsed_ms: u32,
+    pub level: f32,
+}
+
+/// Plays a `Profile`. Times are milliseconds from the monotonic.
+#[derive(Debug, Clone, Copy)]
+pub struct Player {
+    state: RunState,
+    segment: usize,
+    pass: u16,
+    segment_start_ms: u32,
+    // elapsed within the segment when paused
+    paused_elapsed_ms: u32,
+    // level the current segment ramps from
+    from: f32,
+    level: f32,
+    started_ms: u32,
+    total_elapsed_ms: u32,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self {
+            state: RunState::Idle,
+            segment: 0,
+            pass: 1,
+            segment_start_ms: 0,
+            paused_elapsed_ms: 0,
+            from: 0.0,
+            level: 0.0,
+            started_ms: 0,
+            total_elapsed_ms: 0,
+        }
+    }
+}
+
+impl Player {
+    pub fn start(&mut self, now_ms: u32, level: f32) {
+        *self = Self {
+            state: RunState::Running,
+            segment_start_ms: now_ms,
+            from: level,
+            level,
+            started_ms: now_ms,
+            ..Self::default()
+        };
+    }
+
+    pub fn pause(&mut self, now_ms: u32) {
+        if self.state == RunState::Running {
+            self.state = RunState::Paused;
+            self.paused_elapsed_ms = now_ms.wrapping_sub(self.segment_start_ms);
+            self.total_elapsed_ms = now_ms.wrapping_sub(self.started_ms);
+        }
+    }
+
+    pub fn resume(&mut self, now_ms: u32) {
+        if self.state == RunState::Paused {
+            self.state = RunState::Running;
+            // Shift the start times so the pause doesn't count
+            self.segment_start_ms = now_ms.wrapping_sub(self.paused_elapsed_ms);
+            self.started_ms = now_ms.wrapping_sub(self.total_elapsed_ms);
+        }
+    }
+
+    /// Stops where it is, the setpoint keeps its last level.
+    pub fn abort(&mut self) {
+        self.state = RunState::Idle;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == RunState::Running
+    }
+
+    /// Running or paused, either way it holds the setpoint.
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, RunState::Running | RunState::Paused)
+    }
+
+    /// Advances to `now_ms`, returning the setpoint while running.
+    pub fn update(&mut self, profile: &Profile, now_ms: u32) -> Option<f32> {
+        if self.state != RunState::Running {
+            return None;
+        }
+        if profile.is_empty() {
+            self.state = RunState::Done;
+            return None;
+        }
+
+        // Catch up on every segment that ended since the last update
+        loop {
+            let segment = profile.segments[self.segment];
+            let elapsed = now_ms.wrapping_sub(self.segment_start_ms);
+            if elapsed < segment.duration_ms {
+                self.level = match segment.shape {
+                    Shape::Step => segment.target,
+                    Shape::Ramp => self.from + (segment.target - self.from) * elapsed as f32 / segment.duration_ms as f32,
+                };
+                break;
+            }
+
+            self.level = segment.target;
+            self.from = segment.target;
+            self.segment_start_ms = self.segment_start_ms.wrapping_add(segment.duration_ms);
+            self.segment += 1;
+            if self.segment == profile.segments.len() {
+                self.segment = 0;
+                if profile.loops != 0 && self.pass >= profile.loops {
+                    self.state = RunState::Done;
+                    break;
+                }
+                self.pass = self.pass.wrapping_add(1);
+            }
+        }
+        self.total_elapsed_ms = now_ms.wrapping_sub(self.started_ms);
+        Some(self.level)
+    }
+
+    pub fn progress(&self, profile: &Profile) -> Progress {
+        Progress {
+            state: self.state,
+            segment: self.segment,
+            segments: profile.segments.len(),
+            pass: self.pass,
+            loops: profile.loops,
+            elapsed_ms: self.total_elapsed_ms,
+            level: self.level,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(segments: &[(u32, f32, Shape)], loops: u16) -> Profile {
+        let segments = segments.iter().map(|&(duration_ms, target, shape)| Segment { duration_ms, target, shape });
+        Profile { segments: segments.collect(), loops }
+    }
+
+    // 250ms a pass, ramping up, stepping down and ramping back to 0
+    fn three_segments(loops: u16) -> Profile {
+        profile(&[(100, 10.0, Shape::Ramp), (50, 4.0, Shape::Step), (100, 0.0, Shape::Ramp)], loops)
+    }
+
+    fn close(level: Option<f32>, expected: f32) -> bool {
+        level.map_or(false, |level| (level - expected).abs() < 1e-5)
+    }
+
+    #[test]
+    fn ramps_from_the_previous_level() {
+        let profile = three_segments(1);
+        let mut player = Player::default();
+        player.start(0, 2.0);
+        let cases = [(0, 2.0), (50, 6.0), (99, 9.92), (100, 4.0), (149, 4.0), (200, 2.0)];
+        for (now, level) in cases {
+            let got = player.update(&profile, now);
+            assert!(close(got, level), "at {}: {:?}", now, got);
+        }
+    }
+
+    #[test]
+    fn catches_up_on_missed_segments() {
+        let profile = three_segments(1);
+        let mut player = Player::default();
+        player.start(1000, 0.0);
+        assert!(close(player.update(&profile, 1160), 3.6));
+        let progress = player.progress(&profile);
+        assert_eq!((progress.segment, progress.pass, progress.elapsed_ms), (2, 1, 160));
+    }
+
+    #[test]
+    fn stops_after_its_loops() {
+        let profile = three_segments(2);
+        let mut player = Player::default();
+        player.start(0, 0.0);
+        // The second pass ramps from where the first ended
+        assert!(close(player.update(&profile, 260), 1.0));
+        assert_eq!(player.progress(&profile).pass, 2);
+        assert!(close(player.update(&profile, 600), 0.0));
+        assert_eq!(player.progress(&profile).state, RunState::Done);
+        assert!(!player.is_active());
+        assert_eq!(player.update(&profile, 700), None);
+    }
+
+    #[test]
+    fn zero_loops_repeats_forever() {
+        let profile = three_segments(0);
+        let mut player = Player::default();
+        player.start(0, 0.0);
+        assert!(close(player.update(&profile, 250 * 1000 + 50), 5.0));
+        let progress = player.progress(&profile);
+        assert_eq!((progress.state, progress.pass, progress.loops), (RunState::Running, 1001, 0));
+    }
+
+    #[test]
+    fn pause_doesnt_count() {
+        let profile = profile(&[(100, 10.0, Shape::Ramp)], 1);
+        let mut player = Player::default();
+        player.start(0, 0.0);
+        assert!(close(player.update(&profile, 40), 4.0));
+        player.pause(40);
+        assert!(player.is_active() && !player.is_running());
+        assert_eq!(player.update(&profile, 90), None);
+        // Picks up 40ms in, wherever the clock has got to
+        player.resume(1000);
+        assert!(close(player.update(&profile, 1010), 5.0));
+        assert_eq!(player.progress(&profile).elapsed_ms, 50);
+        assert!(close(player.update(&profile, 1060), 10.0));
+        assert_eq!(player.progress(&profile).state, RunState::Done);
+    }
+
+    #[test]
+    fn resumes_across_the_counter_wrapping() {
+        let profile = profile(&[(100, 10.0, Shape::Ramp)], 1);
+        let mut player = Player::default();
+        player.start(u32::MAX - 9, 0.0);
+        player.pause(10);
+        player.resume(100);
+        assert!(close(player.update(&profile, 130), 5.0));
+    }
+
+    #[test]
+    fn empty_profile_is_done_at_once() {
+        let mut player = Player::default();
+        player.start(0, 3.0);
+        assert_eq!(player.update(&Profile::default(), 10), None);
+        assert_eq!(player.progress(&Profile::default()).state, RunState::Done);
+    }
+
+    #[test]
+    fn targets_must_be_allowed_levels() {
+        let limits = ChannelConfig { min: 2.0, max: 12.0, ..Default::default() };
+        assert!(profile(&[(10, 0.0, Shape::Step), (10, 12.0, Shape::Ramp)], 1).is_valid(&limits));
+        assert!(!profile(&[(10, 1.0, Shape::Step)], 1).is_valid(&limits));
+        assert!(!profile(&[(10, 13.0, Shape::Step)], 1).is_valid(&limits));
+    }
+}
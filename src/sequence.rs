@@ -0,0 +1,293 @@
+use heapless::Vec;
+
+use crate::logics::CHANNELS;
+
+pub const MAX_STEPS: usize = 8;
+// How long a step may wait for its input before the sequence gives up
+pub const WAIT_TIMEOUT_MS: u32 = 2000;
+// Each channel is brought down over this long when aborting
+pub const ABORT_RAMP_MS: u32 = 200;
+
+/// Hold the sequence until input `input` reaches `volts`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub input: usize,
+    pub volts: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub channel: usize,
+    // after the previous step finished
+    pub delay_ms: u32,
+    // only checked powering up
+    pub wait: Option<Condition>,
+}
+
+/// Power-up order. Powering down runs it backwards with the same delays.
+/// Aborting also runs it backwards, ramping each channel down in turn
+/// without the delays.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sequence {
+    pub steps: Vec<Step, MAX_STEPS>,
+}
+
+impl Sequence {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Idle,
+    Up,
+    Down,
+    // Down after a fault or a timeout
+    Abort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Enabled(usize),
+    Disabled(usize),
+    // the step's condition wasn't met in time
+    Timeout(usize),
+    Done(Phase),
+}
+
+/// Steps through a `Sequence`, deciding which outputs may be driven. Time
+/// and measurements are passed in so it runs the same off target.
+pub struct Sequencer {
+    // share of each channel's output let through, 0 off to 1 on
+    gain: [f32; CHANNELS],
+    phase: Phase,
+    // next step to run, counted in the phase's own order
+    step: usize,
+    // when the last step finished, or the condition wait started
+    since_ms: u32,
+    waiting: bool,
+}
+
+impl Sequencer {
+    /// Without a sequence every output starts enabled, with one they wait
+    /// for `start(Phase::Up)`.
+    pub fn new(sequence: &Sequence) -> Self {
+        let on = if sequence.is_empty() { 1.0 } else { 0.0 };
+        Self {
+            gain: [on; CHANNELS],
+            phase: Phase::Idle,
+            step: 0,
+            since_ms: 0,
+            waiting: false,
+        }
+    }
+
+    pub fn gain(&self) -> [f32; CHANNELS] {
+        self.gain
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.phase != Phase::Idle
+    }
+
+    pub fn start(&mut self, phase: Phase, now_ms: u32) {
+        self.phase = phase;
+        self.step = 0;
+        self.since_ms = now_ms;
+        self.waiting = false;
+    }
+
+    /// Runs whatever steps are due, returning at most one event per call.
+    pub fn poll(&mut self, sequence: &Sequence, now_ms: u32, inputs: &[f32]) -> Option<Event> {
+        let count = sequence.steps.len();
+        if self.phase == Phase::Idle {
+            return None;
+        }
+        if self.step >= count {
+            // Channels a sequence doesn't mention follow the phase as a whole
+            let on = if self.phase == Phase::Up { 1.0 } else { 0.0 };
+            for (channel, gain) in self.gain.iter_mut().enumerate() {
+                if !sequence.steps.iter().any(|s| s.channel == channel) {
+                    *gain = on;
+                }
+            }
+            let done = core::mem::replace(&mut self.phase, Phase::Idle);
+            return Some(Event::Done(done));
+        }
+
+        let up = self.phase == Phase::Up;
+        let step = if up { sequence.steps[self.step] } else { sequence.steps[count - 1 - self.step] };
+        let elapsed = now_ms.wrapping_sub(self.since_ms);
+
+        if self.waiting {
+            let Some(wait) = step.wait else {
+                self.waiting = false;
+                return None;
+            };
+            if inputs.get(wait.input).map_or(false, |v| *v >= wait.volts) {
+                // Carries on with the next step straight away
+                self.next(now_ms);
+                return self.poll(sequence, now_ms, inputs);
+            } else if elapsed > WAIT_TIMEOUT_MS {
+                let channel = step.channel;
+                // Back out whatever came up so far, in reverse
+                self.phase = Phase::Abort;
+                self.step = count - 1 - self.step;
+                self.since_ms = now_ms;
+                self.waiting = false;
+                return Some(Event::Timeout(channel));
+            }
+            return None;
+        }
+
+        if self.phase == Phase::Abort {
+            let gain = &mut self.gain[step.channel];
+            *gain = gain.min(1.0 - elapsed as f32 / ABORT_RAMP_MS as f32).max(0.0);
+            if *gain > 0.0 {
+                return None;
+            }
+            self.next(now_ms);
+            return Some(Event::Disabled(step.channel));
+        }
+
+        if elapsed < step.delay_ms {
+            return None;
+        }
+        self.gain[step.channel] = if up { 1.0 } else { 0.0 };
+        if up && step.wait.is_some() {
+            self.waiting = true;
+            self.since_ms = now_ms;
+        } else {
+            self.next(now_ms);
+        }
+        Some(if up { Event::Enabled(step.channel) } else { Event::Disabled(step.channel) })
+    }
+
+    fn next(&mut self, now_ms: u32) {
+        self.step += 1;
+        self.since_ms = now_ms;
+        self.waiting = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(steps: &[Step]) -> Sequence {
+        Sequence { steps: Vec::from_slice(steps).unwrap() }
+    }
+
+    fn step(channel: usize, delay_ms: u32, wait: Option<Condition>) -> Step {
+        Step { channel, delay_ms, wait }
+    }
+
+    /// Polls every ms until the phase ends, collecting (time, event).
+    fn run(sequencer: &mut Sequencer, sequence: &Sequence, from_ms: u32, inputs: impl Fn(u32) -> [f32; CHANNELS]) -> std::vec::Vec<(u32, Event)> {
+        let mut events = std::vec::Vec::new();
+        for now in from_ms..from_ms + 10_000 {
+            while let Some(event) = sequencer.poll(sequence, now, &inputs(now)) {
+                events.push((now, event));
+            }
+            if !sequencer.is_running() {
+                break;
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn powers_up_in_order_with_delays() {
+        let sequence = sequence(&[step(1, 10, None), step(0, 50, None)]);
+        let mut sequencer = Sequencer::new(&sequence);
+        assert_eq!(sequencer.gain(), [0.0, 0.0]);
+        sequencer.start(Phase::Up, 0);
+        let events = run(&mut sequencer, &sequence, 0, |_| [0.0; CHANNELS]);
+        assert_eq!(events, [(10, Event::Enabled(1)), (60, Event::Enabled(0)), (60, Event::Done(Phase::Up))]);
+        assert_eq!(sequencer.gain(), [1.0, 1.0]);
+    }
+
+    #[test]
+    fn powers_down_in_reverse() {
+        let sequence = sequence(&[step(1, 10, None), step(0, 50, None)]);
+        let mut sequencer = Sequencer::new(&sequence);
+        sequencer.start(Phase::Up, 0);
+        run(&mut sequencer, &sequence, 0, |_| [0.0; CHANNELS]);
+        sequencer.start(Phase::Down, 100);
+        let events = run(&mut sequencer, &sequence, 100, |_| [0.0; CHANNELS]);
+        assert_eq!(events, [(150, Event::Disabled(0)), (160, Event::Disabled(1)), (160, Event::Done(Phase::Down))]);
+        assert_eq!(sequencer.gain(), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn waits_for_the_input() {
+        let sequence = sequence(&[step(0, 0, Some(Condition { input: 0, volts: 5.0 })), step(1, 0, None)]);
+        let mut sequencer = Sequencer::new(&sequence);
+        sequencer.start(Phase::Up, 0);
+        // in0 comes up at 300ms
+        let events = run(&mut sequencer, &sequence, 0, |now| [if now >= 300 { 5.0 } else { 0.0 }, 0.0]);
+        assert_eq!(events, [(0, Event::Enabled(0)), (300, Event::Enabled(1)), (300, Event::Done(Phase::Up))]);
+    }
+
+    #[test]
+    fn timeout_ramps_down_what_came_up_in_reverse() {
+        let sequence = sequence(&[step(1, 0, None), step(0, 0, Some(Condition { input: 0, volts: 5.0 }))]);
+        let mut sequencer = Sequencer::new(&sequence);
+        sequencer.start(Phase::Up, 0);
+
+        let mut gains = std::vec::Vec::new();
+        let mut events = std::vec::Vec::new();
+        for now in 0..10_000 {
+            while let Some(event) = sequencer.poll(&sequence, now, &[0.0; CHANNELS]) {
+                events.push((now, event));
+            }
+            gains.push(sequencer.gain());
+            if !sequencer.is_running() {
+                break;
+            }
+        }
+
+        let timeout = WAIT_TIMEOUT_MS + 1;
+        assert_eq!(events, [
+            (0, Event::Enabled(1)),
+            (0, Event::Enabled(0)),
+            (timeout, Event::Timeout(0)),
+            (timeout + ABORT_RAMP_MS, Event::Disabled(0)),
+            (timeout + 2 * ABORT_RAMP_MS, Event::Disabled(1)),
+            (timeout + 2 * ABORT_RAMP_MS, Event::Done(Phase::Abort)),
+        ]);
+        // Channel 0 ramps down first while 1 stays up, then 1 ramps
+        let halfway = |ms: u32| gains[(timeout + ms) as usize];
+        assert_eq!(halfway(ABORT_RAMP_MS / 2), [0.5, 1.0]);
+        assert_eq!(halfway(ABORT_RAMP_MS + ABORT_RAMP_MS / 2), [0.0, 0.5]);
+        assert!(gains.windows(2).skip(timeout as usize).all(|w| w[1][0] <= w[0][0] && w[1][1] <= w[0][1]));
+    }
+
+    #[test]
+    fn abort_skips_channels_not_up_yet() {
+        let sequence = sequence(&[step(0, 0, None), step(1, 1000, None)]);
+        let mut sequencer = Sequencer::new(&sequence);
+        sequencer.start(Phase::Up, 0);
+        sequencer.poll(&sequence, 0, &[0.0; CHANNELS]);
+        sequencer.start(Phase::Abort, 10);
+        let events = run(&mut sequencer, &sequence, 10, |_| [0.0; CHANNELS]);
+        assert_eq!(events, [
+            (10, Event::Disabled(1)),
+            (10 + ABORT_RAMP_MS, Event::Disabled(0)),
+            (10 + ABORT_RAMP_MS, Event::Done(Phase::Abort)),
+        ]);
+    }
+
+    #[test]
+    fn no_sequence_leaves_everything_on() {
+        let sequence = Sequence::default();
+        let sequencer = Sequencer::new(&sequence);
+        assert_eq!(sequencer.gain(), [1.0; CHANNELS]);
+        assert!(!sequencer.is_running());
+    }
+}
@@ -0,0 +1,95 @@
+use usb_device::bus::UsbBusAllocator;
+use usb_device::prelude::*;
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+use wio_terminal::hal::usb::UsbBus;
+
+use crate::command::CommandLine;
+
+/// USB CDC serial port. Writes are best-effort: whatever the host isn't
+/// reading fast enough is dropped so callers never block on it.
+pub struct Serial {
+    device: UsbDevice<'static, UsbBus>,
+    port: SerialPort<'static, UsbBus>,
+    dropped: u32,
+    line: CommandLine,
+    // the current line ran past CommandLine's capacity
+    overflow: bool,
+}
+
+impl Serial {
+    pub fn new(usb_alloc: &'static UsbBusAllocator<UsbBus>) -> Self {
+        let port = SerialPort::new(usb_alloc);
+        let device = UsbDeviceBuilder::new(usb_alloc, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("PHY405")
+            .product("proj-405")
+            .serial_number("405")
+            .device_class(USB_CLASS_CDC)
+            .build();
+
+        Self {
+            device,
+            port,
+            dropped: 0,
+            line: CommandLine::new(),
+            overflow: false,
+        }
+    }
+
+    /// Services the USB peripheral, to be called from each USB interrupt.
+    /// Calls `on_line` for every complete line received from the host.
+    pub fn poll(&mut self, mut on_line: impl FnMut(&mut Self, CommandLine)) {
+        if !self.device.poll(&mut [&mut self.port]) {
+            return;
+        }
+
+        let mut buf = [0u8; 64];
+        while let Ok(count) = self.port.read(&mut buf) {
+            if count == 0 {
+                break;
+            }
+            for &byte in &buf[..count] {
+                match byte {
+                    b'\r' | b'\n' => {
+                        let line = core::mem::replace(&mut self.line, CommandLine::new());
+                        if core::mem::replace(&mut self.overflow, false) {
+                            self.write(b"error line too long\r\n");
+                        } else if !line.is_empty() {
+                            on_line(self, line);
+                        }
+                    }
+                    _ if byte.is_ascii() && !byte.is_ascii_control() => {
+                        if self.line.try_push(byte as char).is_err() {
+                            self.overflow = true;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let mut written = 0;
+        while written < data.len() {
+            match self.port.write(&data[written..]) {
+                Ok(count) if count > 0 => written += count,
+                _ => break,
+            }
+        }
+        if written < data.len() {
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+        written
+    }
+
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+impl core::fmt::Write for Serial {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
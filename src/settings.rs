@@ -0,0 +1,582 @@
+use cortex_m::peripheral::DWT;
+use wio_terminal::pac::NVMCTRL;
+
+use crate::analog::CurrentSensor;
+use crate::battery::{BatteryProfile, Chemistry};
+use crate::control::Trigger;
+use crate::energy::Counter;
+use crate::expr::{Program, MAX_SOURCE};
+use crate::logics::{ChannelConfig, Units, CHANNELS};
+use crate::policy::{Detection, Policy, SourcePriority};
+use crate::profile::{Profile, Segment, Shape};
+use crate::sequence::{Condition, Sequence, Step};
+use crate::state::SENSORS;
+use crate::table::{Breakpoint, Table, MAX_POINTS};
+
+// Last 8KB erase block of the 512KB flash. It's in bank B, the code runs
+// from bank A, so the CPU keeps going while it's erased and written.
+const BLOCK_ADDR: u32 = 0x0007_E000;
+const PAGE_SIZE: usize = 512;
+// Room for full tables, battery curves and both expressions
+const PAGES: usize = 4;
+const SIZE: usize = PAGE_SIZE * PAGES;
+
+const MAGIC: u32 = 0x3530_3450; // "P405" little endian
+// 2 added the power sequence, 3 the profiles, 4 the current sensors,
+// 5 the battery profiles, 6 the source detection, 7 the channel setpoint
+// configuration, 8 the expressions, 9 which current sensors are fitted,
+// 10 the control trigger
+const VERSION: u16 = 10;
+// magic, version, payload length
+const HEADER: usize = 8;
+
+// The energy counters get the two blocks below, used in turn. They're saved
+// far more often than the settings, so every save goes to the next page. A
+// block is only erased when the saves move into it, the other one still
+// holds the newest counters until the first page there is written and read
+// back.
+const COUNTERS_ADDR: [u32; 2] = [0x0007_A000, 0x0007_C000];
+// per block
+const COUNTER_PAGES: usize = 16;
+const COUNTERS_MAGIC: u32 = 0x3530_3445; // "E405" little endian
+// magic, sequence number
+const COUNTERS_HEADER: usize = 8;
+
+// Block erase is specified at up to 200ms
+const TIMEOUT_US: u32 = 250_000;
+
+/// Everything that survives a reset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub policy: Policy,
+    pub tables: [Table; CHANNELS],
+    pub sequence: Sequence,
+    pub profiles: [Profile; CHANNELS],
+    pub sensors: [CurrentSensor; SENSORS],
+    pub batteries: [Option<BatteryProfile>; CHANNELS],
+    pub detection: [Detection; CHANNELS],
+    pub source_priority: SourcePriority,
+    pub channels: [ChannelConfig; CHANNELS],
+    // saved as source and compiled again on load
+    pub programs: [Program; CHANNELS],
+    pub trigger: Trigger,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            policy: Policy::Bridge,
+            tables: Default::default(),
+            sequence: Default::default(),
+            profiles: Default::default(),
+            sensors: Default::default(),
+            batteries: Default::default(),
+            detection: Default::default(),
+            source_priority: SourcePriority::Left,
+            channels: Default::default(),
+            programs: Default::default(),
+            trigger: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    TooLarge,
+    Timeout,
+    // NVMCTRL reported a programming, lock or NVM error
+    Flash,
+    // the page read back different from what was written
+    Verify,
+}
+
+impl StoreError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StoreError::TooLarge => "settings too large",
+            StoreError::Timeout => "flash timeout",
+            StoreError::Flash => "flash error",
+            StoreError::Verify => "flash verify failed",
+        }
+    }
+}
+
+impl Settings {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut w = Writer { buf, pos: HEADER };
+        w.u8(Policy::ALL.iter().position(|p| *p == self.policy)? as u8)?;
+        for table in self.tables.iter() {
+            w.u8(table.points().len() as u8)?;
+            for point in table.points() {
+                w.f32(point.input)?;
+                w.f32(point.output)?;
+            }
+        }
+        w.u8(self.sequence.steps.len() as u8)?;
+        for step in self.sequence.steps.iter() {
+            w.u8(step.channel as u8)?;
+            w.u32(step.delay_ms)?;
+            // input 0xff for no condition
+            let (input, volts) = step.wait.map_or((0xff, 0.0), |c| (c.input as u8, c.volts));
+            w.u8(input)?;
+            w.f32(volts)?;
+        }
+        for profile in self.profiles.iter() {
+            w.u16(profile.loops)?;
+            w.u8(profile.segments.len() as u8)?;
+            for segment in profile.segments.iter() {
+                w.u32(segment.duration_ms)?;
+                w.f32(segment.target)?;
+                w.u8(segment.shape as u8)?;
+            }
+        }
+        for sensor in self.sensors.iter() {
+            w.f32(sensor.volts_per_amp)?;
+            w.f32(sensor.offset)?;
+        }
+        for battery in self.batteries.iter() {
+            // chemistry 0xff for no battery
+            let Some(battery) = battery else {
+                w.u8(0xff)?;
+                continue;
+            };
+            w.u8(Chemistry::ALL.iter().position(|c| *c == battery.chemistry)? as u8)?;
+            w.u8(battery.cells)?;
+            w.f32(battery.cutoff)?;
+            w.f32(battery.hysteresis)?;
+            w.f32(battery.resistance)?;
+            w.u8(battery.ocv.points().len() as u8)?;
+            for point in battery.ocv.points() {
+                w.f32(point.input)?;
+                w.f32(point.output)?;
+            }
+        }
+        for detection in self.detection.iter() {
+            w.f32(detection.threshold)?;
+            w.f32(detection.hysteresis)?;
+        }
+        w.u8(SourcePriority::ALL.iter().position(|p| *p == self.source_priority)? as u8)?;
+        for channel in self.channels.iter() {
+            for value in [channel.min, channel.max, channel.coarse_step, channel.fine_step, channel.full_scale] {
+                w.f32(value)?;
+            }
+            w.u8(channel.units.len() as u8)?;
+            for byte in channel.units.bytes() {
+                w.u8(byte)?;
+            }
+            w.u8(channel.precision)?;
+        }
+        for program in self.programs.iter() {
+            w.u8(program.source().len() as u8)?;
+            w.bytes(program.source().as_bytes())?;
+        }
+        for sensor in self.sensors.iter() {
+            w.u8(sensor.fitted as u8)?;
+        }
+        // decimation 0 for polled
+        w.u16(match self.trigger {
+            Trigger::Polled => 0,
+            Trigger::Adc { decimation } => decimation,
+        })?;
+        let len = w.pos - HEADER;
+        let end = w.pos;
+        w.pos = 0;
+        w.u32(MAGIC)?;
+        w.u16(VERSION)?;
+        w.u16(len as u16)?;
+        w.pos = end;
+        w.u32(checksum(&w.buf[..end]))?;
+        Some(w.pos)
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut r = Reader { buf, pos: 0 };
+        if r.u32()? != MAGIC {
+            return None;
+        }
+        let version = r.u16()?;
+        if version == 0 || version > VERSION {
+            return None;
+        }
+        let end = HEADER + r.u16()? as usize;
+        let stored = Reader { buf, pos: end }.u32()?;
+        if checksum(&buf[..end]) != stored {
+            return None;
+        }
+
+        let mut settings = Settings::default();
+        settings.policy = *Policy::ALL.get(r.u8()? as usize)?;
+        for table in settings.tables.iter_mut() {
+            let count = r.u8()? as usize;
+            if count == 0 {
+                continue;
+            }
+            let mut points = [Breakpoint { input: 0.0, output: 0.0 }; MAX_POINTS];
+            for point in points.iter_mut().take(count) {
+                *point = Breakpoint { input: r.f32()?, output: r.f32()? };
+            }
+            // Checked again so a table that slipped through can't get used
+            *table = Table::new(points.get(..count)?).ok()?;
+        }
+        if version >= 2 {
+            for _ in 0..r.u8()? {
+                let channel = r.u8()? as usize;
+                let delay_ms = r.u32()?;
+                let (input, volts) = (r.u8()? as usize, r.f32()?);
+                if channel >= CHANNELS {
+                    return None;
+                }
+                let wait = (input < CHANNELS).then(|| Condition { input, volts });
+                settings.sequence.steps.push(Step { channel, delay_ms, wait }).ok()?;
+            }
+        }
+        if version >= 3 {
+            for profile in settings.profiles.iter_mut() {
+                profile.loops = r.u16()?;
+                for _ in 0..r.u8()? {
+                    let duration_ms = r.u32()?;
+                    let target = r.f32()?;
+                    let shape = match r.u8()? {
+                        0 => Shape::Step,
+                        1 => Shape::Ramp,
+                        _ => return None,
+                    };
+                    // A zero length segment would never let the player advance
+                    if duration_ms == 0 {
+                        return None;
+                    }
+                    profile.segments.push(Segment { duration_ms, target, shape }).ok()?;
+                }
+            }
+        }
+        if version >= 4 {
+            for sensor in settings.sensors.iter_mut() {
+                *sensor = CurrentSensor { volts_per_amp: r.f32()?, offset: r.f32()?, fitted: true };
+                if !sensor.is_valid() {
+                    return None;
+                }
+            }
+        }
+        if version >= 5 {
+            for battery in settings.batteries.iter_mut() {
+                let chemistry = r.u8()?;
+                if chemistry == 0xff {
+                    continue;
+                }
+                let mut profile = BatteryProfile::new(*Chemistry::ALL.get(chemistry as usize)?, r.u8()?);
+                profile.cutoff = r.f32()?;
+                profile.hysteresis = r.f32()?;
+                profile.resistance = r.f32()?;
+                let count = r.u8()? as usize;
+                let mut points = [Breakpoint { input: 0.0, output: 0.0 }; MAX_POINTS];
+                for point in points.get_mut(..count)? {
+                    *point = Breakpoint { input: r.f32()?, output: r.f32()? };
+                }
+                profile.ocv = Table::new(&points[..count]).ok()?;
+                *battery = Some(profile);
+            }
+        }
+        if version >= 6 {
+            for detection in settings.detection.iter_mut() {
+                *detection = Detection { threshold: r.f32()?, hysteresis: r.f32()? };
+                if !(0.0..=detection.threshold).contains(&detection.hysteresis) {
+                    return None;
+                }
+            }
+            settings.source_priority = *SourcePriority::ALL.get(r.u8()? as usize)?;
+        }
+        if version >= 7 {
+            for channel in settings.channels.iter_mut() {
+                channel.min = r.f32()?;
+                channel.max = r.f32()?;
+                channel.coarse_step = r.f32()?;
+                channel.fine_step = r.f32()?;
+                channel.full_scale = r.f32()?;
+                let mut units = [0; 4];
+                let len = r.u8()? as usize;
+                for byte in units.get_mut(..len)? {
+                    *byte = r.u8()?;
+                }
+                channel.units = Units::from(core::str::from_utf8(&units[..len]).ok()?).ok()?;
+                channel.precision = r.u8()?;
+                // Saved before max had to fit within full scale
+                channel.max = channel.max.min(channel.full_scale);
+                if !channel.is_valid() {
+                    return None;
+                }
+            }
+        }
+        if version >= 8 {
+            for program in settings.programs.iter_mut() {
+                let mut source = [0; MAX_SOURCE];
+                let len = r.u8()? as usize;
+                for byte in source.get_mut(..len)? {
+                    *byte = r.u8()?;
+                }
+                // Empty is the default program, it doesn't compile
+                if len > 0 {
+                    *program = Program::compile(core::str::from_utf8(&source[..len]).ok()?, CHANNELS).ok()?;
+                }
+            }
+        }
+        if version >= 9 {
+            for sensor in settings.sensors.iter_mut() {
+                sensor.fitted = r.u8()? != 0;
+            }
+        }
+        if version >= 10 {
+            settings.trigger = match r.u16()? {
+                0 => Trigger::Polled,
+                decimation => Trigger::Adc { decimation },
+            };
+        }
+        // Checked against the channel configs, which are read after them
+        for (battery, channel) in settings.batteries.iter().zip(settings.channels.iter()) {
+            if battery.as_ref().map_or(false, |profile| !profile.is_valid(channel)) {
+                return None;
+            }
+        }
+        Some(settings)
+    }
+}
+
+fn encode_counters(counters: &[Counter; CHANNELS], sequence: u32, buf: &mut [u8]) -> Option<usize> {
+    let mut w = Writer { buf, pos: 0 };
+    w.u32(COUNTERS_MAGIC)?;
+    w.u32(sequence)?;
+    for counter in counters.iter() {
+        w.f64(counter.watt_seconds)?;
+        w.f64(counter.amp_seconds)?;
+        w.u64(counter.active_ms)?;
+    }
+    let end = w.pos;
+    w.u32(checksum(&w.buf[..end]))?;
+    Some(w.pos)
+}
+
+// Sequence number and counters of one page
+fn decode_counters(buf: &[u8]) -> Option<(u32, [Counter; CHANNELS])> {
+    let mut r = Reader { buf, pos: 0 };
+    if r.u32()? != COUNTERS_MAGIC {
+        return None;
+    }
+    let sequence = r.u32()?;
+    let mut counters = [Counter::default(); CHANNELS];
+    for counter in counters.iter_mut() {
+        *counter = Counter { watt_seconds: r.f64()?, amp_seconds: r.f64()?, active_ms: r.u64()? };
+    }
+    let end = r.pos;
+    if checksum(&buf[..end]) != r.u32()? {
+        return None;
+    }
+    Some((sequence, counters))
+}
+
+/// Settings and energy counter blocks in flash.
+pub struct Store {
+    nvm: NVMCTRL,
+    // newest counter page, counted across both blocks, and its sequence number
+    counters_at: Option<(usize, u32)>,
+    // core clock, for the timeouts
+    cycles_per_us: u32,
+}
+
+// Address of counter page `page`, the second block's pages follow the first's
+fn counters_page(page: usize) -> u32 {
+    COUNTERS_ADDR[page / COUNTER_PAGES] + ((page % COUNTER_PAGES) * PAGE_SIZE) as u32
+}
+
+impl Store {
+    pub fn new(nvm: NVMCTRL, cycles_per_us: u32) -> Self {
+        Self { nvm, counters_at: None, cycles_per_us }
+    }
+
+    /// The stored settings, or None if nothing valid has been saved yet.
+    pub fn load(&self) -> Option<Settings> {
+        let flash = unsafe { core::slice::from_raw_parts(BLOCK_ADDR as *const u8, SIZE) };
+        Settings::decode(flash)
+    }
+
+    /// Erases the block and writes `settings`, blocking until it's done.
+    pub fn save(&mut self, settings: &Settings) -> Result<(), StoreError> {
+        let mut buf = [0xffu8; SIZE];
+        let len = settings.encode(&mut buf).ok_or(StoreError::TooLarge)?;
+
+        self.nvm.ctrla.modify(|_, w| w.wmode().man());
+        self.command(BLOCK_ADDR, |w| w.cmdex().key().cmd().eb())?;
+        for (page, data) in buf[..len].chunks(PAGE_SIZE).enumerate() {
+            self.write_page(BLOCK_ADDR + (page * PAGE_SIZE) as u32, data)?;
+        }
+        Ok(())
+    }
+
+    /// The newest saved energy counters, None if there are none.
+    pub fn load_counters(&mut self) -> Option<[Counter; CHANNELS]> {
+        let mut newest: Option<(usize, u32, [Counter; CHANNELS])> = None;
+        for page in 0..COUNTER_PAGES * COUNTERS_ADDR.len() {
+            let flash = unsafe { core::slice::from_raw_parts(counters_page(page) as *const u8, PAGE_SIZE) };
+            let Some((sequence, counters)) = decode_counters(flash) else {
+                continue;
+            };
+            // Compared by difference so it keeps working when it wraps
+            if newest.map_or(true, |(_, s, _)| (sequence.wrapping_sub(s) as i32) > 0) {
+                newest = Some((page, sequence, counters));
+            }
+        }
+        let (page, sequence, counters) = newest?;
+        self.counters_at = Some((page, sequence));
+        Some(counters)
+    }
+
+    /// Writes `counters` to the next free page and reads it back. Moving
+    /// into a block erases it, the newest counters are in the other one.
+    pub fn save_counters(&mut self, counters: &[Counter; CHANNELS]) -> Result<(), StoreError> {
+        let (page, sequence) = match self.counters_at {
+            Some((page, sequence)) => ((page + 1) % (COUNTER_PAGES * COUNTERS_ADDR.len()), sequence.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let mut buf = [0xffu8; PAGE_SIZE];
+        let len = encode_counters(counters, sequence, &mut buf).ok_or(StoreError::TooLarge)?;
+
+        self.nvm.ctrla.modify(|_, w| w.wmode().man());
+        // Page 0 also comes round first after a reset with nothing valid stored
+        if page % COUNTER_PAGES == 0 {
+            self.command(counters_page(page), |w| w.cmdex().key().cmd().eb())?;
+        }
+        // Used up either way, a page can't be written twice without an erase.
+        // A bad one fails its checksum and load skips it.
+        self.counters_at = Some((page, sequence));
+        self.write_page(counters_page(page), &buf[..len])?;
+
+        let flash = unsafe { core::slice::from_raw_parts(counters_page(page) as *const u8, len) };
+        if flash != &buf[..len] {
+            return Err(StoreError::Verify);
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, addr: u32, data: &[u8]) -> Result<(), StoreError> {
+        self.command(addr, |w| w.cmdex().key().cmd().pbc())?;
+        // The page buffer only takes 32 bit writes
+        for (i, word) in data.chunks(4).enumerate() {
+            let mut bytes = [0xff; 4];
+            bytes[..word.len()].copy_from_slice(word);
+            unsafe {
+                core::ptr::write_volatile((addr as *mut u32).add(i), u32::from_le_bytes(bytes));
+            }
+        }
+        self.command(addr, |w| w.cmdex().key().cmd().wp())
+    }
+
+    fn command(
+        &mut self,
+        addr: u32,
+        cmd: impl FnOnce(&mut wio_terminal::pac::nvmctrl::ctrlb::W) -> &mut wio_terminal::pac::nvmctrl::ctrlb::W,
+    ) -> Result<(), StoreError> {
+        self.wait_ready()?;
+        self.nvm.intflag.write(|w| w.done().set_bit().proge().set_bit().locke().set_bit().nvme().set_bit());
+        self.nvm.addr.write(|w| unsafe { w.addr().bits(addr) });
+        self.nvm.ctrlb.write(cmd);
+        self.wait_ready()?;
+
+        let flags = self.nvm.intflag.read();
+        if flags.proge().bit_is_set() || flags.locke().bit_is_set() || flags.nvme().bit_is_set() {
+            return Err(StoreError::Flash);
+        }
+        Ok(())
+    }
+
+    fn wait_ready(&self) -> Result<(), StoreError> {
+        let start = DWT::cycle_count();
+        while self.nvm.status.read().ready().bit_is_clear() {
+            if DWT::cycle_count().wrapping_sub(start) > TIMEOUT_US * self.cycles_per_us {
+                return Err(StoreError::Timeout);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Fletcher-32 over bytes, enough to tell an erased or half written block
+fn checksum(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (0xffffu32, 0xffffu32);
+    for &byte in data {
+        a = (a + byte as u32) % 65535;
+        b = (b + a) % 65535;
+    }
+    (b << 16) | a
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buf.get_mut(self.pos..self.pos + bytes.len())?.copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Some(())
+    }
+
+    fn u8(&mut self, v: u8) -> Option<()> {
+        self.bytes(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> Option<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> Option<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> Option<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn f32(&mut self, v: f32) -> Option<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn f64(&mut self, v: f64) -> Option<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.buf.get(self.pos..self.pos + N)?.try_into().ok()?;
+        self.pos += N;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.bytes().map(f32::from_le_bytes)
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        self.bytes().map(f64::from_le_bytes)
+    }
+}
@@ -1,11 +1,43 @@
 
-#[derive(Default)]
-pub struct InputValues {
-    pub raw_adc_a0: u16,
-    pub raw_adc_a1: u16,
-}
-#[derive(Default)]
-pub struct OutputValues {
-    pub dac0: u16,
-    pub dac1: u16,
+pub const INPUTS: usize = 2;
+pub const OUTPUTS: usize = 2;
+// Current sense inputs
+pub const SENSORS: usize = 2;
+// The sensors are scanned on this input's ADC and share its settings
+pub const SENSOR_INPUT: usize = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct InputValues<const N: usize = INPUTS> {
+    // raw ADC codes, indexed by input
+    pub raw: [u16; N],
+    // raw ADC codes, indexed by current sensor
+    pub current: [u16; SENSORS],
+    pub internal: InternalValues,
+}
+impl<const N: usize> Default for InputValues<N> {
+    fn default() -> Self {
+        Self {
+            raw: [0; N],
+            current: [0; SENSORS],
+            internal: Default::default(),
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InternalValues {
+    pub ptat: u16,
+    pub ctat: u16,
+    pub vddcore: u16,
+    pub vbat: u16,
+    pub iovcc: u16,
+}
+#[derive(Debug, Clone, Copy)]
+pub struct OutputValues<const N: usize = OUTPUTS> {
+    // raw DAC codes, indexed by output
+    pub raw: [u16; N],
+}
+impl<const N: usize> Default for OutputValues<N> {
+    fn default() -> Self {
+        Self { raw: [0; N] }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,214 @@
+use micromath::F32Ext;
+
+use crate::logics::{State, CHANNELS};
+
+/// Running statistics over one signal. Mean and variance use Welford's
+/// update, so a long run near a large offset doesn't lose the variance to
+/// cancellation the way sum and sum of squares would. They are kept in f64,
+/// in f32 the mean's own rounding swamps a small spread after a few
+/// thousand samples.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    count: u32,
+    mean: f64,
+    // sum of squared differences from the mean
+    m2: f64,
+    min: f32,
+    max: f32,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            min: f32::MAX,
+            max: f32::MIN,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub count: u32,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub rms: f32,
+    // sample standard deviation
+    pub std_dev: f32,
+    pub peak_to_peak: f32,
+}
+
+impl Stats {
+    pub fn add(&mut self, value: f32) {
+        if !value.is_finite() {
+            return;
+        }
+        self.count = self.count.saturating_add(1);
+        let value64 = value as f64;
+        let delta = value64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value64 - self.mean);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Everything at once, all 0 before the first sample.
+    pub fn summary(&self) -> Summary {
+        if self.count == 0 {
+            return Summary { count: 0, min: 0.0, max: 0.0, mean: 0.0, rms: 0.0, std_dev: 0.0, peak_to_peak: 0.0 };
+        }
+        let n = self.count as f64;
+        // Rounding can leave m2 a hair below 0
+        let m2 = self.m2.max(0.0);
+        // Only the results go back to f32, micromath has no f64 sqrt
+        let std_dev = if self.count > 1 { ((m2 / (n - 1.0)) as f32).sqrt() } else { 0.0 };
+        Summary {
+            count: self.count,
+            min: self.min,
+            max: self.max,
+            mean: self.mean as f32,
+            // mean square is the squared mean plus the population variance
+            rms: ((self.mean * self.mean + m2 / n) as f32).sqrt(),
+            std_dev,
+            peak_to_peak: self.max - self.min,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Input(usize),
+    Output(usize),
+}
+
+impl Signal {
+    pub fn parse(word: &str) -> Option<Self> {
+        let signal = match word.strip_prefix("in") {
+            Some(n) => Signal::Input(n.parse().ok()?),
+            None => Signal::Output(word.strip_prefix("out")?.parse().ok()?),
+        };
+        (signal.channel() < CHANNELS).then(|| signal)
+    }
+
+    pub fn channel(&self) -> usize {
+        match *self {
+            Signal::Input(c) | Signal::Output(c) => c,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Signal::Input(_) => "input",
+            Signal::Output(_) => "output",
+        }
+    }
+}
+
+/// Statistics for every channel's measured input and driven output.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelStats {
+    pub inputs: [Stats; CHANNELS],
+    pub outputs: [Stats; CHANNELS],
+}
+
+impl ChannelStats {
+    /// Adds one control run's readings.
+    pub fn add(&mut self, state: &State) {
+        for (i, side) in state.channels.iter().enumerate() {
+            self.inputs[i].add(side.input);
+            self.outputs[i].add(side.real_output);
+        }
+    }
+
+    pub fn get(&self, signal: Signal) -> &Stats {
+        match signal {
+            Signal::Input(c) => &self.inputs[c],
+            Signal::Output(c) => &self.outputs[c],
+        }
+    }
+
+    /// Resets one signal, or all of them for None.
+    pub fn reset(&mut self, signal: Option<Signal>) {
+        match signal {
+            Some(Signal::Input(c)) => self.inputs[c].reset(),
+            Some(Signal::Output(c)) => self.outputs[c].reset(),
+            None => *self = Self::default(),
+        }
+    }
+
+    /// Every signal, inputs first.
+    pub fn signals() -> impl Iterator<Item=Signal> {
+        (0..CHANNELS).map(Signal::Input).chain((0..CHANNELS).map(Signal::Output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mean, rms and sample standard deviation the slow way, in f64
+    fn two_pass(values: &[f32]) -> (f64, f64, f64) {
+        let n = values.len() as f64;
+        let mean = values.iter().map(|v| *v as f64).sum::<f64>() / n;
+        let squares = values.iter().map(|v| (*v as f64 - mean).powi(2)).sum::<f64>();
+        let rms = (values.iter().map(|v| (*v as f64).powi(2)).sum::<f64>() / n).sqrt();
+        (mean, rms, (squares / (n - 1.0)).sqrt())
+    }
+
+    // A small ripple riding on `offset`, deterministic so failures repeat
+    fn ripple(offset: f32, amplitude: f32, count: usize) -> Vec<f32> {
+        let mut seed = 12345u32;
+        (0..count).map(|i| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let noise = (seed >> 16) as f32 / 65536.0 - 0.5;
+            offset + amplitude * ((i as f32 * 0.1).sin() + noise)
+        }).collect()
+    }
+
+    fn close(actual: f32, expected: f64, relative: f64) -> bool {
+        (actual as f64 - expected).abs() <= relative * expected.abs().max(1e-9)
+    }
+
+    #[test]
+    fn matches_two_pass_at_a_large_offset() {
+        for (offset, amplitude) in [(0.0, 1.0), (12.0, 0.01), (1000.0, 0.001), (10_000.0, 0.01)] {
+            let values = ripple(offset, amplitude, 100_000);
+            let mut stats = Stats::default();
+            values.iter().for_each(|v| stats.add(*v));
+            let summary = stats.summary();
+            let (mean, rms, std_dev) = two_pass(&values);
+
+            assert_eq!(summary.count as usize, values.len());
+            assert!(close(summary.mean, mean, 1e-6), "{} {} {}", offset, summary.mean, mean);
+            assert!(close(summary.rms, rms, 1e-6), "{} {} {}", offset, summary.rms, rms);
+            assert!(close(summary.std_dev, std_dev, 1e-3), "{} {} {}", offset, summary.std_dev, std_dev);
+        }
+    }
+
+    #[test]
+    fn extremes_and_empty() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.summary().count, 0);
+        assert_eq!(stats.summary().std_dev, 0.0);
+
+        for v in [3.0, -1.0, f32::NAN, 5.0, f32::INFINITY] {
+            stats.add(v);
+        }
+        let summary = stats.summary();
+        assert_eq!(summary.count, 3);
+        assert_eq!((summary.min, summary.max, summary.peak_to_peak), (-1.0, 5.0, 6.0));
+        assert_eq!(summary.mean, 7.0 / 3.0);
+
+        stats.reset();
+        stats.add(2.0);
+        assert_eq!(stats.summary().std_dev, 0.0);
+        assert_eq!(stats.summary().rms, 2.0);
+    }
+}
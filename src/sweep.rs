@@ -0,0 +1,274 @@
+use heapless::Vec;
+use micromath::F32Ext;
+
+use crate::logics::{ChannelConfig, CHANNELS};
+
+pub const MAX_POINTS: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spacing {
+    Linear,
+    // needs start and stop above 0
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SweepConfig {
+    // setpoint being swept
+    pub channel: usize,
+    // input recorded at each point
+    pub measure: usize,
+    pub start: f32,
+    pub stop: f32,
+    pub points: usize,
+    pub spacing: Spacing,
+    // wait after each step before measuring
+    pub settle_ms: u32,
+    // measurements over this long are averaged
+    pub dwell_ms: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepError {
+    Channel,
+    Range,
+    Points,
+}
+
+impl SweepError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SweepError::Channel => "bad channel",
+            SweepError::Range => "level out of range",
+            SweepError::Points => "bad point count",
+        }
+    }
+}
+
+impl SweepConfig {
+    /// Number of points to go from start to stop in steps of `step` volts.
+    pub fn points_for_step(start: f32, stop: f32, step: f32) -> usize {
+        if step <= 0.0 {
+            return 0;
+        }
+        ((stop - start).abs() / step).floor() as usize + 1
+    }
+
+    /// Checks the levels against `limits`, the swept channel's config.
+    pub fn validate(&self, limits: &ChannelConfig) -> Result<(), SweepError> {
+        if self.channel >= CHANNELS || self.measure >= CHANNELS {
+            return Err(SweepError::Channel);
+        }
+        // 0 is off, which any channel can be set to
+        let in_range = |v: f32| v == 0.0 || (limits.min..=limits.max).contains(&v);
+        if !in_range(self.start) || !in_range(self.stop) || self.start == self.stop {
+            return Err(SweepError::Range);
+        }
+        if self.spacing == Spacing::Log && (self.start <= 0.0 || self.stop <= 0.0) {
+            return Err(SweepError::Range);
+        }
+        if !(2..=MAX_POINTS).contains(&self.points) {
+            return Err(SweepError::Points);
+        }
+        Ok(())
+    }
+
+    pub fn level(&self, point: usize) -> f32 {
+        let frac = point as f32 / (self.points - 1) as f32;
+        match self.spacing {
+            Spacing::Linear => self.start + (self.stop - self.start) * frac,
+            Spacing::Log => self.start * (self.stop / self.start).powf(frac),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SweepPoint {
+    pub level: f32,
+    pub measured: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Idle,
+    Settling,
+    Dwelling,
+    Done,
+}
+
+/// Steps a setpoint through `SweepConfig` and records one averaged
+/// measurement per point.
+#[derive(Debug, Clone)]
+pub struct Sweep {
+    config: Option<SweepConfig>,
+    results: Vec<SweepPoint, MAX_POINTS>,
+    phase: Phase,
+    point: usize,
+    since_ms: u32,
+    sum: f32,
+    count: u32,
+}
+
+impl Default for Sweep {
+    fn default() -> Self {
+        Self {
+            config: None,
+            results: Vec::new(),
+            phase: Phase::Idle,
+            point: 0,
+            since_ms: 0,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Sweep {
+    /// Starts over with `config`, returning the first level to set.
+    pub fn start(&mut self, config: SweepConfig, now_ms: u32) -> f32 {
+        *self = Self {
+            config: Some(config),
+            phase: Phase::Settling,
+            since_ms: now_ms,
+            ..Self::default()
+        };
+        config.level(0)
+    }
+
+    pub fn abort(&mut self) {
+        if self.is_running() {
+            self.phase = Phase::Idle;
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.phase, Phase::Settling | Phase::Dwelling)
+    }
+
+    pub fn config(&self) -> Option<&SweepConfig> {
+        self.config.as_ref()
+    }
+
+    pub fn results(&self) -> &[SweepPoint] {
+        &self.results
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Feeds one measurement, returning the next level when it moves on.
+    pub fn update(&mut self, now_ms: u32, measured: f32) -> Option<f32> {
+        let config = self.config?;
+        let elapsed = now_ms.wrapping_sub(self.since_ms);
+        match self.phase {
+            Phase::Settling if elapsed >= config.settle_ms => {
+                self.phase = Phase::Dwelling;
+                self.since_ms = now_ms;
+                self.sum = measured;
+                self.count = 1;
+                None
+            }
+            Phase::Dwelling => {
+                self.sum += measured;
+                self.count += 1;
+                if elapsed < config.dwell_ms {
+                    return None;
+                }
+
+                let point = SweepPoint {
+                    level: config.level(self.point),
+                    measured: self.sum / self.count as f32,
+                };
+                // Can't fail, points is at most MAX_POINTS
+                self.results.push(point).ok();
+                self.point += 1;
+                if self.point == config.points {
+                    self.phase = Phase::Done;
+                    return None;
+                }
+                self.phase = Phase::Settling;
+                self.since_ms = now_ms;
+                Some(config.level(self.point))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(start: f32, stop: f32, points: usize) -> SweepConfig {
+        SweepConfig {
+            channel: 0,
+            measure: 1,
+            start,
+            stop,
+            points,
+            spacing: Spacing::Linear,
+            settle_ms: 10,
+            dwell_ms: 5,
+        }
+    }
+
+    #[test]
+    fn validates_against_the_channel() {
+        let limits = ChannelConfig { min: 2.0, max: 12.0, ..ChannelConfig::default() };
+        assert_eq!(config(2.0, 12.0, 11).validate(&limits), Ok(()));
+        assert_eq!(config(0.0, 12.0, 11).validate(&limits), Ok(()));
+        assert_eq!(config(1.0, 12.0, 11).validate(&limits), Err(SweepError::Range));
+        assert_eq!(config(2.0, 12.5, 11).validate(&limits), Err(SweepError::Range));
+        assert_eq!(config(5.0, 5.0, 11).validate(&limits), Err(SweepError::Range));
+        assert_eq!(config(2.0, 12.0, 1).validate(&limits), Err(SweepError::Points));
+        assert_eq!(config(2.0, 12.0, MAX_POINTS + 1).validate(&limits), Err(SweepError::Points));
+        assert_eq!(SweepConfig { measure: CHANNELS, ..config(2.0, 12.0, 11) }.validate(&limits), Err(SweepError::Channel));
+        let log = SweepConfig { spacing: Spacing::Log, ..config(0.0, 12.0, 11) };
+        assert_eq!(log.validate(&limits), Err(SweepError::Range));
+    }
+
+    #[test]
+    fn levels_span_start_to_stop() {
+        let linear = config(12.0, 2.0, 6);
+        let levels: [f32; 6] = core::array::from_fn(|i| linear.level(i));
+        assert_eq!(levels, [12.0, 10.0, 8.0, 6.0, 4.0, 2.0]);
+
+        let log = SweepConfig { spacing: Spacing::Log, ..config(1.0, 16.0, 5) };
+        for (i, expected) in [1.0, 2.0, 4.0, 8.0, 16.0].into_iter().enumerate() {
+            assert!((log.level(i) - expected).abs() < 1e-4, "{}", i);
+        }
+        assert_eq!(SweepConfig::points_for_step(2.0, 12.0, 2.5), 5);
+    }
+
+    #[test]
+    fn settles_then_averages_each_point() {
+        let mut sweep = Sweep::default();
+        assert_eq!(sweep.start(config(2.0, 4.0, 2), 0), 2.0);
+        assert!(sweep.is_running());
+        // Still settling, these aren't counted
+        assert_eq!(sweep.update(5, 100.0), None);
+        assert_eq!(sweep.update(10, 1.0), None);
+        assert_eq!(sweep.update(12, 3.0), None);
+        assert_eq!(sweep.update(15, 2.0), Some(4.0));
+        assert_eq!(sweep.results(), [SweepPoint { level: 2.0, measured: 2.0 }]);
+
+        assert_eq!(sweep.update(25, 5.0), None);
+        assert_eq!(sweep.update(30, 7.0), None);
+        assert_eq!(sweep.phase(), Phase::Done);
+        assert!(!sweep.is_running());
+        assert_eq!(sweep.results()[1], SweepPoint { level: 4.0, measured: 6.0 });
+    }
+
+    #[test]
+    fn abort_keeps_the_results() {
+        let mut sweep = Sweep::default();
+        sweep.start(config(2.0, 4.0, 3), 0);
+        sweep.update(10, 1.0);
+        sweep.update(15, 1.0);
+        sweep.abort();
+        assert_eq!(sweep.phase(), Phase::Idle);
+        assert_eq!(sweep.results().len(), 1);
+        assert_eq!(sweep.update(100, 1.0), None);
+    }
+}
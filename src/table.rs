@@ -0,0 +1,136 @@
+use heapless::Vec;
+
+use crate::logics::MAX_LEVEL;
+
+pub const MAX_POINTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Breakpoint {
+    pub input: f32,
+    pub output: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableError {
+    TooFewPoints,
+    TooManyPoints,
+    // inputs have to be strictly increasing
+    NotMonotonic,
+    OutOfRange,
+}
+
+impl TableError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TableError::TooFewPoints => "too few points",
+            TableError::TooManyPoints => "too many points",
+            TableError::NotMonotonic => "inputs not increasing",
+            TableError::OutOfRange => "level out of range",
+        }
+    }
+}
+
+/// Piecewise-linear transfer function from (input V, output V) breakpoints.
+/// Inputs outside the table are clamped to its first or last point.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    points: Vec<Breakpoint, MAX_POINTS>,
+}
+
+impl Table {
+    pub fn new(points: &[Breakpoint]) -> Result<Self, TableError> {
+        if points.len() < 2 {
+            return Err(TableError::TooFewPoints);
+        }
+        let in_range = |v: f32| (0.0..=MAX_LEVEL).contains(&v);
+        if points.iter().any(|p| !in_range(p.input) || !in_range(p.output)) {
+            return Err(TableError::OutOfRange);
+        }
+        if points.windows(2).any(|w| w[1].input <= w[0].input) {
+            return Err(TableError::NotMonotonic);
+        }
+        let points = Vec::from_slice(points).map_err(|_| TableError::TooManyPoints)?;
+        Ok(Self { points })
+    }
+
+    pub fn points(&self) -> &[Breakpoint] {
+        &self.points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Output for `input`, 0V while no table has been loaded.
+    pub fn lookup(&self, input: f32) -> f32 {
+        let (Some(first), Some(last)) = (self.points.first(), self.points.last()) else {
+            return 0.0;
+        };
+        if input <= first.input {
+            return first.output;
+        }
+        if input >= last.input {
+            return last.output;
+        }
+
+        let upper = self.points.iter().position(|p| p.input >= input).unwrap_or(self.points.len() - 1);
+        let (a, b) = (self.points[upper - 1], self.points[upper]);
+        a.output + (input - a.input) * (b.output - a.output) / (b.input - a.input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn point(input: f32, output: f32) -> Breakpoint {
+        Breakpoint { input, output }
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        let mut rising = [point(0.0, 0.0); MAX_POINTS + 1];
+        for (i, p) in rising.iter_mut().enumerate() {
+            p.input = i as f32;
+        }
+        let cases: [(&[Breakpoint], TableError); 7] = [
+            (&[], TableError::TooFewPoints),
+            (&[point(1.0, 1.0)], TableError::TooFewPoints),
+            (&rising, TableError::TooManyPoints),
+            (&[point(1.0, 1.0), point(1.0, 2.0)], TableError::NotMonotonic),
+            (&[point(1.0, 1.0), point(3.0, 2.0), point(2.0, 3.0)], TableError::NotMonotonic),
+            (&[point(-1.0, 1.0), point(3.0, 2.0)], TableError::OutOfRange),
+            (&[point(1.0, 1.0), point(3.0, MAX_LEVEL + 1.0)], TableError::OutOfRange),
+        ];
+        for (points, error) in cases {
+            assert_eq!(Table::new(points), Err(error), "{:?}", points);
+        }
+        assert_eq!(Table::new(&[point(1.0, f32::NAN), point(2.0, 1.0)]), Err(TableError::OutOfRange));
+        assert!(Table::new(&rising[..MAX_POINTS]).is_ok());
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let table = Table::new(&[point(2.0, 0.0), point(4.0, 10.0), point(8.0, 12.0)]).unwrap();
+        let cases = [(2.0, 0.0), (3.0, 5.0), (4.0, 10.0), (5.0, 10.5), (7.0, 11.5), (8.0, 12.0)];
+        for (input, output) in cases {
+            assert!((table.lookup(input) - output).abs() < 1e-6, "{} gave {}", input, table.lookup(input));
+        }
+    }
+
+    #[test]
+    fn clamps_past_either_end() {
+        let table = Table::new(&[point(2.0, 3.0), point(4.0, 10.0)]).unwrap();
+        assert_eq!(table.lookup(0.0), 3.0);
+        assert_eq!(table.lookup(-5.0), 3.0);
+        assert_eq!(table.lookup(4.5), 10.0);
+        assert_eq!(table.lookup(100.0), 10.0);
+    }
+
+    #[test]
+    fn empty_table_is_off() {
+        let table = Table::default();
+        assert!(table.is_empty());
+        assert_eq!(table.lookup(5.0), 0.0);
+    }
+}
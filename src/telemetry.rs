@@ -0,0 +1,142 @@
+use core::fmt::{Result, Write};
+
+use crate::analog::InternalReadings;
+use crate::battery::BatteryStatus;
+use crate::capture::CaptureConfig;
+use crate::charge::ChargeStatus;
+use crate::control::Trigger;
+use crate::dac::WriteStats;
+use crate::energy::Counter;
+use crate::logics::MpptStatus;
+use crate::profile::Progress;
+use crate::stats::{Signal, Summary};
+use crate::sweep::SweepPoint;
+use crate::timing::TimingStats;
+
+// Telemetry goes out as one line per record: a record name followed by
+// space separated key=value pairs.
+
+pub fn timing(w: &mut impl Write, task: &str, stats: &TimingStats) -> Result {
+    write!(w, "timing task={} runs={} missed={} jitter_us={} jitter_max_us={} exec_us={} exec_max_us={}\r\n",
+           task,
+           stats.runs,
+           stats.missed,
+           stats.jitter_last,
+           stats.jitter_max,
+           stats.exec_last,
+           stats.exec_max,
+    )
+}
+
+pub fn trigger(w: &mut impl Write, trigger: &Trigger, overruns: u32) -> Result {
+    let decimation = match trigger {
+        Trigger::Adc { decimation } => *decimation,
+        Trigger::Polled => 0,
+    };
+    write!(w, "trigger mode={} decimation={} overruns={}\r\n", trigger.name(), decimation, overruns)
+}
+
+pub fn dac(w: &mut impl Write, overwritten: u32, writes: &[WriteStats; 2]) -> Result {
+    write!(w, "dac overwritten={}", overwritten)?;
+    for (channel, stats) in writes.iter().enumerate() {
+        write!(w, " written{}={} latency{}_us={} latency{}_max_us={}",
+               channel, stats.completed, channel, stats.latency_last, channel, stats.latency_max)?;
+    }
+    write!(w, "\r\n")
+}
+
+pub fn internal(w: &mut impl Write, readings: &InternalReadings) -> Result {
+    write!(w, "internal temp_c={:.1} vddcore={:.3} vbat={:.3} iovcc={:.3}\r\n",
+           readings.temperature, readings.vddcore, readings.vbat, readings.iovcc)
+}
+
+pub fn profile(w: &mut impl Write, channel: usize, progress: &Progress) -> Result {
+    write!(w, "profile channel={} state={} segment={} segments={} pass={} loops={} elapsed_ms={} level={:.3}\r\n",
+           channel,
+           progress.state.name(),
+           progress.segment,
+           progress.segments,
+           progress.pass,
+           progress.loops,
+           progress.elapsed_ms,
+           progress.level,
+    )
+}
+
+pub fn sweep_point(w: &mut impl Write, index: usize, point: &SweepPoint) -> Result {
+    write!(w, "sweep index={} level={:.4} measured={:.4}\r\n", index, point.level, point.measured)
+}
+
+/// Header for a capture readout. The samples follow as an IEEE 488.2
+/// definite length block, `#<digits><bytes>` then little endian u16 codes,
+/// `volts_per_code` scales them and `signed` says they're two's complement.
+pub fn capture(w: &mut impl Write, config: &CaptureConfig, period_us: f32, forced: bool, volts_per_code: f32, signed: bool) -> Result {
+    write!(w, "capture input={} samples={} pre_trigger={} period_us={:.3} forced={} volts_per_code={:.6e} signed={}\r\n",
+           config.input,
+           config.length,
+           config.pre_trigger,
+           period_us,
+           forced as u8,
+           volts_per_code,
+           signed as u8,
+    )?;
+    let bytes = config.length * 2;
+    let mut digits = 1;
+    while bytes >= 10usize.pow(digits) {
+        digits += 1;
+    }
+    write!(w, "#{}{}", digits, bytes)
+}
+
+pub fn stats(w: &mut impl Write, signal: Signal, summary: &Summary) -> Result {
+    write!(w, "stats {}={} count={} min={:.4} max={:.4} mean={:.4} rms={:.4} std={:.4} pp={:.4}\r\n",
+           signal.kind(),
+           signal.channel(),
+           summary.count,
+           summary.min,
+           summary.max,
+           summary.mean,
+           summary.rms,
+           summary.std_dev,
+           summary.peak_to_peak,
+    )
+}
+
+pub fn energy(w: &mut impl Write, channel: usize, counter: &Counter) -> Result {
+    write!(w, "energy channel={} wh={:.4} ah={:.4} active_s={}\r\n",
+           channel,
+           counter.watt_hours(),
+           counter.amp_hours(),
+           counter.active_ms / 1000,
+    )
+}
+
+pub fn charge(w: &mut impl Write, status: &ChargeStatus) -> Result {
+    write!(w, "charge state={} elapsed_s={} level={:.3} volts={:.3} amps={:.3}\r\n",
+           status.state.name(),
+           status.elapsed_ms / 1000,
+           status.level,
+           status.volts,
+           status.amps,
+    )
+}
+
+pub fn mppt(w: &mut impl Write, status: &MpptStatus) -> Result {
+    write!(w, "mppt state={} method={} level={:.3} volts={:.3} watts={:.3} voc={:.3}\r\n",
+           status.phase.name(),
+           status.method.name(),
+           status.level,
+           status.volts,
+           status.watts.unwrap_or(0.0),
+           status.voc.unwrap_or(0.0),
+    )
+}
+
+pub fn battery(w: &mut impl Write, side: usize, status: &BatteryStatus) -> Result {
+    write!(w, "battery side={} volts={:.3} soc={:.1} cut_off={}\r\n",
+           side,
+           status.volts,
+           status.soc * 100.0,
+           status.cut_off,
+    )
+}
@@ -1,29 +1,131 @@
-use core::ops::Add;
+use arrayvec::ArrayString;
 use embedded_graphics as eg;
 
-use eg::mono_font::{ascii::FONT_6X12, MonoTextStyle};
+use eg::mono_font::{MonoFont, MonoTextStyle};
 use eg::pixelcolor::Rgb565;
 use eg::prelude::*;
-use eg::primitives::{PrimitiveStyleBuilder, Rectangle};
-use eg::text::Text;
-use embedded_graphics::mono_font::ascii::{FONT_8X13, FONT_9X15};
+use eg::Pixel;
+use eg::primitives::{Line as Segment, PointsIter, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle};
+use eg::text::{Baseline, Text};
+use embedded_graphics::mono_font::ascii::{FONT_6X13, FONT_6X13_BOLD, FONT_8X13};
 use embedded_graphics::mono_font::MonoTextStyleBuilder;
-use embedded_graphics::text::TextStyle;
-use embedded_text::alignment::{HorizontalAlignment, VerticalAlignment};
-use embedded_text::style::TextBoxStyleBuilder;
-use embedded_text::TextBox;
 
 use wio_terminal::{Scroller, LCD};
 
+use crate::console::{Attr, Change, Console, Level, Line, COLUMNS};
+
 // From https://github.com/atsamd-rs/atsamd/blob/0c241f395e63ee25eb41984d703e4babdae454c2/boards/wio_terminal/examples/usb_serial_display.rs
 // By @jbeaurivage
 type TextSegment = ([u8; 32], usize);
 
+const WIDTH: i32 = 320;
+const HEIGHT: i32 = 240;
+
+// Both 6x13 fonts share metrics so bold runs line up with regular ones
+const CONSOLE_FONT: MonoFont = FONT_6X13;
+const CONSOLE_FONT_BOLD: MonoFont = FONT_6X13_BOLD;
+const LINE_HEIGHT: i32 = 13;
+// The console is drawn in portrait, see Portrait
+const CONSOLE_WIDTH: i32 = HEIGHT;
+const CONSOLE_HEIGHT: i32 = WIDTH;
+const ROWS: usize = (CONSOLE_HEIGHT / LINE_HEIGHT) as usize;
+// Lines past the last row, kept out of the scroll area so it holds whole rows
+const CONSOLE_SPARE: u16 = (CONSOLE_HEIGHT - ROWS as i32 * LINE_HEIGHT) as u16;
+// Largest area Portrait turns round in one go, a glyph of the console font
+const GLYPH_PIXELS: usize = 6 * 13;
+
+// Must divide WIDTH so the hardware scroll offset ends back at 0
+const CLEAR_STEP: i32 = 8;
+
+const PALETTE: [Rgb565; 16] = [
+    Rgb565::new(0, 0, 0),
+    Rgb565::new(21, 0, 0),
+    Rgb565::new(0, 42, 0),
+    Rgb565::new(21, 21, 0),
+    Rgb565::new(0, 0, 21),
+    Rgb565::new(21, 0, 21),
+    Rgb565::new(0, 42, 21),
+    Rgb565::new(21, 42, 21),
+    Rgb565::new(10, 21, 10),
+    Rgb565::new(31, 21, 10),
+    Rgb565::new(10, 63, 10),
+    Rgb565::new(31, 63, 10),
+    Rgb565::new(10, 21, 31),
+    Rgb565::new(31, 21, 31),
+    Rgb565::new(10, 63, 31),
+    Rgb565::new(31, 63, 31),
+];
+
 pub struct Terminal {
-    // text_style: MonoTextStyle<'a, Rgb565>,
-    cursor: Point,
     display: LCD,
     scroller: Scroller,
+    console: Console,
+    console_visible: bool,
+    // slot of the console's top row, the scroll offset in rows
+    top: usize,
+    // console rows on screen, and the console's line count when they were drawn
+    used: usize,
+    written: u32,
+}
+
+/// The console turned a quarter clockwise into a 240x320 portrait frame.
+/// In landscape the panel's vertical scroll (VSCRSADD) runs along screen x,
+/// drawn this way the console's rows lie along it and scroll in hardware.
+/// It reads with the device turned so its left edge is on top.
+struct Portrait<'a>(&'a mut LCD);
+
+impl Portrait<'_> {
+    fn to_landscape(point: Point) -> Point {
+        Point::new(point.y, HEIGHT - 1 - point.x)
+    }
+
+    fn area_to_landscape(area: &Rectangle) -> Rectangle {
+        let Size { width, height } = area.size;
+        Rectangle::new(
+            Point::new(area.top_left.y, HEIGHT - area.top_left.x - width as i32),
+            Size::new(height, width),
+        )
+    }
+}
+
+impl OriginDimensions for Portrait<'_> {
+    fn size(&self) -> Size {
+        Size::new(CONSOLE_WIDTH as u32, CONSOLE_HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Portrait<'_> {
+    type Color = Rgb565;
+    type Error = <LCD as DrawTarget>::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        self.0.draw_iter(pixels.into_iter().map(|Pixel(point, color)| Pixel(Self::to_landscape(point), color)))
+    }
+
+    // Glyphs come through here, turned round in a buffer so each still goes
+    // out as one window instead of pixel by pixel
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Rgb565>,
+    {
+        let (width, height) = (area.size.width as usize, area.size.height as usize);
+        if width * height > GLYPH_PIXELS {
+            return self.draw_iter(area.points().zip(colors).map(|(point, color)| Pixel(point, color)));
+        }
+        let mut buf = [Rgb565::BLACK; GLYPH_PIXELS];
+        buf.iter_mut().zip(colors).for_each(|(pixel, color)| *pixel = color);
+        let buf = &buf;
+        // Landscape rows run from the portrait area's right column to its left
+        let turned = (0..width).rev().flat_map(move |x| (0..height).map(move |y| buf[y * width + x]));
+        self.0.fill_contiguous(&Self::area_to_landscape(area), turned)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), Self::Error> {
+        self.0.fill_solid(&Self::area_to_landscape(area), color)
+    }
 }
 
 impl Terminal {
@@ -39,24 +141,37 @@ impl Terminal {
         let scroller = display.configure_vertical_scroll(0, 0).unwrap();
 
         Self {
-            // text_style: MonoTextStyle::new(&FONT_6X12, Rgb565::WHITE),
-            cursor: Point::new(0, 8),
             display,
             scroller,
+            console: Console::new(),
+            console_visible: false,
+            top: 0,
+            used: 0,
+            written: 0,
         }
     }
 
     pub fn write_str(&mut self, str: &str) {
-        for character in str.chars() {
-            self.write_character(character);
-        }
+        let change = self.console.write_str(str);
+        self.refresh_console(change);
+    }
+
+    pub fn log(&mut self, level: Level, str: &str) {
+        let mut change = self.console.write_str(level.sgr());
+        change = change.max(self.console.write_str(str));
+        change = change.max(self.console.write_str("\x1b[0m\n"));
+        self.refresh_console(change);
     }
 
     pub fn write_pos(&mut self, pos: Point, str: &str) {
+        self.write_pos_color(pos, str, Rgb565::YELLOW, Rgb565::BLUE);
+    }
+
+    pub fn write_pos_color(&mut self, pos: Point, str: &str, fg: Rgb565, bg: Rgb565) {
         let filled_background = MonoTextStyleBuilder::new()
             .font(&FONT_8X13)
-            .text_color(Rgb565::YELLOW)
-            .background_color(Rgb565::BLUE)
+            .text_color(fg)
+            .background_color(bg)
             .build();
 
         Text::new(
@@ -65,68 +180,237 @@ impl Terminal {
             filled_background,
         )
             .draw(&mut self.display).unwrap();
-        // Rectangle::with_corners(
-        //     pos,
-        //     pos.add(Point::new(20, 40)),
-        // )
-        //     .into_styled(
-        //         PrimitiveStyleBuilder::new()
-        //             .fill_color(Rgb565::BLACK)
-        //             .build(),
-        //     )
-        //     .draw(&mut self.display)
-        //     .ok()
-        //     .unwrap();
-        // Text::new(str, pos, MonoTextStyle::new(&FONT_6X12, Rgb565::WHITE))
-        //     .draw(&mut self.display)
-        //     .ok()
-        //     .unwrap();
     }
 
     pub fn write_character(&mut self, c: char) {
-        if self.cursor.x >= 320 || c == '\n' {
-            self.cursor = Point::new(0, self.cursor.y + FONT_6X12.character_size.height as i32);
-        }
-        if self.cursor.y >= 240 {
-            self.animate_clear();
-            self.cursor = Point::new(0, 0);
+        let change = self.console.write_char(c);
+        self.refresh_console(change);
+    }
+
+    pub fn write(&mut self, segment: TextSegment) {
+        let (buf, count) = segment;
+        let change = buf[..count].iter()
+            .fold(Change::None, |change, c| change.max(self.console.write_char(*c as char)));
+        self.refresh_console(change);
+    }
+
+    pub fn set_console_visible(&mut self, visible: bool) {
+        self.console_visible = visible;
+        self.animate_clear();
+        if self.console_visible {
+            self.draw_console();
         }
+    }
 
-        if c != '\n' {
-            let mut buf = [0u8; 8];
+    /// Clears `area`, outlines it and joins `points` with lines.
+    pub fn plot(&mut self, area: Rectangle, points: impl Iterator<Item=Point>) {
+        self.clear_plot(area);
+        let mut last: Option<Point> = None;
+        for point in points {
+            if let Some(last) = last {
+                Segment::new(last, point)
+                    .into_styled(PrimitiveStyle::with_stroke(Rgb565::YELLOW, 1))
+                    .draw(&mut self.display)
+                    .ok()
+                    .unwrap();
+            }
+            Rectangle::with_center(point, Size::new(3, 3))
+                .into_styled(PrimitiveStyle::with_fill(Rgb565::CYAN))
+                .draw(&mut self.display)
+                .ok()
+                .unwrap();
+            last = Some(point);
+        }
+    }
 
-            Text::new(
-                c.encode_utf8(&mut buf),
-                self.cursor,
-                MonoTextStyle::new(&FONT_6X12, Rgb565::WHITE),
-            )
+    /// Like `plot` over a `columns` by `rows` grid, without marking every
+    /// point so dense waveforms stay readable.
+    pub fn trace(&mut self, area: Rectangle, columns: u32, rows: u32, points: impl Iterator<Item=Point>) {
+        self.clear_plot(area);
+        let grid = PrimitiveStyle::with_stroke(Rgb565::CSS_DARK_SLATE_GRAY, 1);
+        let (left, top) = (area.top_left.x, area.top_left.y);
+        let (w, h) = (area.size.width as i32, area.size.height as i32);
+        for i in 1..columns as i32 {
+            let x = left + w * i / columns as i32;
+            Segment::new(Point::new(x, top + 1), Point::new(x, top + h - 2))
+                .into_styled(grid)
                 .draw(&mut self.display)
                 .ok()
                 .unwrap();
+        }
+        for i in 1..rows as i32 {
+            let y = top + h * i / rows as i32;
+            Segment::new(Point::new(left + 1, y), Point::new(left + w - 2, y))
+                .into_styled(grid)
+                .draw(&mut self.display)
+                .ok()
+                .unwrap();
+        }
 
-            self.cursor.x += (FONT_6X12.character_size.width + FONT_6X12.character_spacing) as i32;
+        let mut last: Option<Point> = None;
+        for point in points {
+            if let Some(last) = last {
+                Segment::new(last, point)
+                    .into_styled(PrimitiveStyle::with_stroke(Rgb565::YELLOW, 1))
+                    .draw(&mut self.display)
+                    .ok()
+                    .unwrap();
+            }
+            last = Some(point);
         }
     }
 
-    pub fn write(&mut self, segment: TextSegment) {
-        let (buf, count) = segment;
-        for (i, character) in buf.iter().enumerate() {
-            if i >= count {
-                break;
+    fn clear_plot(&mut self, area: Rectangle) {
+        area.into_styled(
+            PrimitiveStyleBuilder::new()
+                .fill_color(Rgb565::BLACK)
+                .stroke_color(Rgb565::CSS_DIM_GRAY)
+                .stroke_width(1)
+                .build(),
+        )
+            .draw(&mut self.display)
+            .ok()
+            .unwrap();
+    }
+
+    pub fn scroll_back(&mut self, lines: usize) {
+        if self.console.scroll_back(lines) {
+            self.draw_console();
+        }
+    }
+
+    pub fn scroll_forward(&mut self, lines: usize) {
+        if self.console.scroll_forward(lines) {
+            self.draw_console();
+        }
+    }
+
+    fn refresh_console(&mut self, change: Change) {
+        if !self.console_visible {
+            return;
+        }
+        match change {
+            Change::None => {}
+            Change::Line => {
+                if let Some(row) = self.console.current_row(ROWS) {
+                    let line = self.console.current().clone();
+                    self.draw_line(row, &line);
+                }
             }
-            self.write_character(*character as char);
+            Change::Scroll => self.scroll_console(),
+            Change::Clear => {
+                self.animate_clear();
+                self.draw_console();
+            }
+        }
+    }
+
+    // Scrolls the rows new lines pushed off the top away in hardware, and
+    // draws the rows that changed into the slots that freed up
+    fn scroll_console(&mut self) {
+        let added = self.console.written().wrapping_sub(self.written) as usize;
+        let visible = self.console.visible(ROWS).count();
+        let scrolled = (self.used + added).saturating_sub(visible);
+        if scrolled >= ROWS {
+            self.draw_console();
+            return;
+        }
+        self.top = (self.top + scrolled) % ROWS;
+        self.set_scroll(CONSOLE_SPARE, (self.top as i32 * LINE_HEIGHT) as u16);
+
+        // From the line that was being written, it may have got more text too
+        let from = self.used.saturating_sub(1 + scrolled);
+        let Self { display, console, top, .. } = self;
+        for (row, line) in console.visible(ROWS).enumerate().skip(from) {
+            Self::draw_line_on(display, (*top + row) % ROWS, line);
+        }
+        self.used = visible;
+        self.written = self.console.written();
+    }
+
+    fn draw_console(&mut self) {
+        self.top = 0;
+        self.set_scroll(CONSOLE_SPARE, 0);
+        let Self { display, console, .. } = self;
+        let mut rows = 0;
+        for (row, line) in console.visible(ROWS).enumerate() {
+            Self::draw_line_on(display, row, line);
+            rows += 1;
+        }
+        for row in rows..ROWS {
+            Self::draw_line_on(display, row, &Line::default());
+        }
+        self.used = rows;
+        self.written = self.console.written();
+    }
+
+    fn draw_line(&mut self, row: usize, line: &Line) {
+        Self::draw_line_on(&mut self.display, (self.top + row) % ROWS, line);
+    }
+
+    // Draws into the portrait slot `slot`, where the scroll offset puts it is up to top
+    fn draw_line_on(display: &mut LCD, slot: usize, line: &Line) {
+        let mut display = Portrait(display);
+        let char_width = CONSOLE_FONT.character_size.width as i32;
+        let y = slot as i32 * LINE_HEIGHT;
+
+        for (col, run) in line.runs() {
+            let attr = run[0].attr;
+            let mut text = ArrayString::<[u8; COLUMNS]>::new();
+            run.iter().for_each(|cell| text.push(cell.ch as char));
+
+            Text::with_baseline(
+                &text,
+                Point::new(col as i32 * char_width, y),
+                Self::console_style(attr),
+                Baseline::Top,
+            )
+                .draw(&mut display)
+                .ok()
+                .unwrap();
         }
+
+        // Blank out whatever the previous line left past the end of this one
+        let end = line.cells().len() as i32 * char_width;
+        Rectangle::new(Point::new(end, y), Size::new((CONSOLE_WIDTH - end) as u32, LINE_HEIGHT as u32))
+            .into_styled(
+                PrimitiveStyleBuilder::new()
+                    .fill_color(PALETTE[Attr::DEFAULT.bg as usize])
+                    .build(),
+            )
+            .draw(&mut display)
+            .ok()
+            .unwrap();
     }
 
+    fn console_style(attr: Attr) -> MonoTextStyle<'static, Rgb565> {
+        // Bold also brightens the base 8 colors, like most terminals
+        let fg = if attr.bold && attr.fg < 8 { attr.fg + 8 } else { attr.fg };
+        MonoTextStyleBuilder::new()
+            .font(if attr.bold { &CONSOLE_FONT_BOLD } else { &CONSOLE_FONT })
+            .text_color(PALETTE[fg as usize])
+            .background_color(PALETTE[attr.bg as usize])
+            .build()
+    }
+
+    // Sets the scroll area to all but `fixed_bottom` lines and the offset
+    // into it. A new Scroller starts at 0, so scrolling it sets the offset.
+    fn set_scroll(&mut self, fixed_bottom: u16, offset: u16) {
+        self.scroller = self.display.configure_vertical_scroll(0, fixed_bottom).ok().unwrap();
+        self.display.scroll_vertically(&mut self.scroller, offset).ok().unwrap();
+    }
+
+    // The panel is in landscape, so its vertical scroll runs along the x axis
     fn animate_clear(&mut self) {
-        for x in (0..320).step_by(FONT_6X12.character_size.width as usize) {
+        self.set_scroll(0, 0);
+        for x in (0..WIDTH).step_by(CLEAR_STEP as usize) {
             self.display
-                .scroll_vertically(&mut self.scroller, FONT_6X12.character_size.width as u16)
+                .scroll_vertically(&mut self.scroller, CLEAR_STEP as u16)
                 .ok()
                 .unwrap();
             Rectangle::with_corners(
                 Point::new(x, 0),
-                Point::new(x + FONT_6X12.character_size.width as i32, 240),
+                Point::new(x + CLEAR_STEP, HEIGHT),
             )
                 .into_styled(
                     PrimitiveStyleBuilder::new()
@@ -141,5 +425,6 @@ impl Terminal {
                 cortex_m::asm::nop();
             }
         }
+        self.set_scroll(0, 0);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,150 @@
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimingStats {
+    pub runs: u32,
+    pub missed: u32,
+    // in microseconds
+    pub jitter_last: u32,
+    pub jitter_max: u32,
+    pub exec_last: u32,
+    pub exec_max: u32,
+}
+
+/// Tracks a periodic task against its ideal release times using DWT cycle
+/// counts, so jitter isn't limited by the monotonic tick. The caller reads
+/// the counter.
+pub struct Deadline {
+    period: u32,
+    cycles_per_us: u32,
+    release: Option<u32>,
+    start: u32,
+    stats: TimingStats,
+}
+
+impl Deadline {
+    pub fn new(period_us: u32, core_freq: u32) -> Self {
+        let cycles_per_us = core_freq / 1_000_000;
+        Self {
+            period: period_us * cycles_per_us,
+            cycles_per_us,
+            release: None,
+            start: 0,
+            stats: Default::default(),
+        }
+    }
+
+    /// Marks a run starting at `now`, in DWT cycles.
+    pub fn start(&mut self, now: u32) {
+        self.start = now;
+        let release = *self.release.get_or_insert(now);
+        // The monotonic's 1ms tick can start a run a few cycles early, that's
+        // no jitter rather than a wrap to almost 2^32
+        let late = now.wrapping_sub(release) as i32;
+        let late = late.max(0) as u32;
+
+        if late >= self.period {
+            // Skipped or overran at least one whole period, pick the
+            // schedule up from now. Misses are only counted here.
+            self.stats.missed += late / self.period;
+            self.release = Some(now);
+        }
+
+        self.stats.jitter_last = (late % self.period) / self.cycles_per_us;
+        self.stats.jitter_max = self.stats.jitter_max.max(self.stats.jitter_last);
+    }
+
+    /// Marks the run finishing at `now`, in DWT cycles.
+    pub fn finish(&mut self, now: u32) {
+        let release = self.release.unwrap_or(self.start);
+        self.stats.runs = self.stats.runs.wrapping_add(1);
+        self.stats.exec_last = now.wrapping_sub(self.start) / self.cycles_per_us;
+        self.stats.exec_max = self.stats.exec_max.max(self.stats.exec_last);
+        self.release = Some(release.wrapping_add(self.period));
+    }
+
+    /// Lets a release pass without running, so it isn't counted as missed.
+    pub fn skip(&mut self) {
+        if let Some(release) = self.release.as_mut() {
+            *release = release.wrapping_add(self.period);
+        }
+    }
+
+    pub fn stats(&self) -> TimingStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1MHz core so a cycle is a microsecond, 1000us period
+    fn deadline() -> Deadline {
+        Deadline::new(1000, 1_000_000)
+    }
+
+    // Runs starting at `starts`, each taking `exec` cycles
+    fn run(deadline: &mut Deadline, starts: &[u32], exec: u32) -> TimingStats {
+        for start in starts {
+            deadline.start(*start);
+            deadline.finish(start.wrapping_add(exec));
+        }
+        deadline.stats()
+    }
+
+    #[test]
+    fn on_time_runs() {
+        let stats = run(&mut deadline(), &[5, 1005, 2005, 3005], 100);
+        assert_eq!((stats.runs, stats.missed, stats.jitter_max, stats.exec_max), (4, 0, 0, 100));
+    }
+
+    #[test]
+    fn early_starts_are_no_jitter() {
+        let stats = run(&mut deadline(), &[0, 997, 1999, 3000], 100);
+        assert_eq!((stats.missed, stats.jitter_max), (0, 0));
+    }
+
+    #[test]
+    fn late_starts_are_jitter() {
+        let stats = run(&mut deadline(), &[0, 1020, 2003], 100);
+        assert_eq!((stats.missed, stats.jitter_last, stats.jitter_max), (0, 3, 20));
+    }
+
+    #[test]
+    fn skipped_periods_count_once() {
+        let mut deadline = deadline();
+        // Two whole periods lost, then back on the new schedule
+        let stats = run(&mut deadline, &[0, 3010, 4010, 5010], 100);
+        assert_eq!((stats.missed, stats.jitter_max), (2, 10));
+    }
+
+    #[test]
+    fn overrun_counts_once() {
+        let mut deadline = deadline();
+        // Ran into the next period, that run is only late
+        deadline.start(0);
+        deadline.finish(1500);
+        assert_eq!(run(&mut deadline, &[1500], 100).missed, 0);
+        // Ran past the next period's whole window, that one is missed
+        deadline.start(2000);
+        deadline.finish(4500);
+        let stats = run(&mut deadline, &[4500, 5500], 100);
+        assert_eq!(stats.missed, 1);
+        assert_eq!(stats.exec_max, 2500);
+    }
+
+    #[test]
+    fn skip_moves_the_release_on() {
+        let mut deadline = deadline();
+        run(&mut deadline, &[0], 100);
+        deadline.skip();
+        let stats = run(&mut deadline, &[2000], 100);
+        assert_eq!((stats.missed, stats.jitter_max), (0, 0));
+    }
+
+    #[test]
+    fn wraps_with_the_counter() {
+        let start = u32::MAX - 500;
+        let stats = run(&mut deadline(), &[start, start.wrapping_add(1000), start.wrapping_add(1990)], 100);
+        assert_eq!((stats.missed, stats.jitter_max), (0, 0));
+    }
+}
@@ -0,0 +1,588 @@
+use arrayvec::ArrayString;
+use core::fmt::Write;
+use embedded_graphics::geometry::Point;
+use embedded_graphics::primitives::Rectangle;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use heapless::spsc::Queue;
+use micromath::F32Ext;
+use wio_terminal::{Button, ButtonEvent};
+
+use crate::analog::InternalReadings;
+use crate::battery::BatteryStatus;
+use crate::capture::{State as CaptureState, Waveform};
+use crate::charge::{ChargeState, ChargeStatus};
+use crate::command::Command;
+use crate::console::Level;
+use crate::energy::Counter;
+use crate::logics::{ChannelConfig, Side, State, Tracking, CHANNELS, ROUTES};
+use crate::policy::Policy;
+use crate::profile::{Progress, RunState};
+use crate::sweep::{Spacing, Sweep};
+use crate::state::{InputValues, OutputValues};
+use crate::stats::ChannelStats;
+use crate::terminal::Terminal;
+
+// spsc::Queue holds one less than its size
+const QUEUE_SIZE: usize = 17;
+
+pub type LogLine = ArrayString<[u8; 128]>;
+
+pub enum UiMessage {
+    State(StateView),
+    Button(ButtonEvent),
+    Fault(&'static str),
+    Log(Level, LogLine),
+}
+
+const KINDS: usize = 4;
+const KIND_NAMES: [&str; KINDS] = ["state", "button", "fault", "log"];
+
+#[derive(Clone, Copy)]
+enum Kind {
+    State,
+    Button,
+    Fault,
+    Log,
+}
+
+impl UiMessage {
+    fn kind(&self) -> Kind {
+        match self {
+            UiMessage::State(_) => Kind::State,
+            UiMessage::Button(_) => Kind::Button,
+            UiMessage::Fault(_) => Kind::Fault,
+            UiMessage::Log(..) => Kind::Log,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StateView {
+    pub state: State,
+    pub inputs: InputValues,
+    pub outputs: OutputValues,
+    pub internal: InternalReadings,
+    pub policy: Policy,
+    pub tracking: Option<Tracking>,
+    pub channels: [ChannelConfig; CHANNELS],
+    // the setpoint buttons move by the fine step
+    pub fine: bool,
+    pub profiles: [Progress; CHANNELS],
+    pub stats: ChannelStats,
+    pub energy: [Counter; CHANNELS],
+    pub charge: ChargeStatus,
+    // by side, None where there's no battery
+    pub batteries: [Option<BatteryStatus>; CHANNELS],
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Diagnostics {
+    pub sent: u32,
+    pub dropped: [u32; KINDS],
+    pub high_water: usize,
+}
+
+/// Messages from every producer to the renderer, counting what did not fit.
+pub struct UiQueue {
+    queue: Queue<UiMessage, QUEUE_SIZE>,
+    diagnostics: Diagnostics,
+}
+
+impl UiQueue {
+    pub fn new() -> Self {
+        Self {
+            queue: Queue::new(),
+            diagnostics: Default::default(),
+        }
+    }
+
+    pub fn send(&mut self, msg: UiMessage) -> bool {
+        let kind = msg.kind();
+        match self.queue.enqueue(msg) {
+            Ok(()) => {
+                self.diagnostics.sent = self.diagnostics.sent.wrapping_add(1);
+                self.diagnostics.high_water = self.diagnostics.high_water.max(self.queue.len());
+                true
+            }
+            Err(_) => {
+                self.diagnostics.dropped[kind as usize] += 1;
+                false
+            }
+        }
+    }
+
+    pub fn log(&mut self, level: Level, args: core::fmt::Arguments) -> bool {
+        let mut line = LogLine::new();
+        // Truncated lines are still worth showing
+        line.write_fmt(args).ok();
+        self.send(UiMessage::Log(level, line))
+    }
+
+    pub fn receive(&mut self) -> Option<UiMessage> {
+        self.queue.dequeue()
+    }
+
+    pub fn diagnostics(&self) -> Diagnostics {
+        self.diagnostics
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Page {
+    State,
+    Console,
+    Diagnostics,
+    Policy,
+    Profile,
+    Sweep,
+    Capture,
+    Stats,
+    Energy,
+    Charge,
+}
+
+impl Page {
+    fn next(self) -> Self {
+        match self {
+            Page::State => Page::Console,
+            Page::Console => Page::Diagnostics,
+            Page::Diagnostics => Page::Policy,
+            Page::Policy => Page::Profile,
+            Page::Profile => Page::Sweep,
+            Page::Sweep => Page::Capture,
+            Page::Capture => Page::Stats,
+            Page::Stats => Page::Energy,
+            Page::Energy => Page::Charge,
+            Page::Charge => Page::State,
+        }
+    }
+}
+
+const STATUS_POS: Point = Point::new(5, 14);
+// One column per channel
+const CHANNEL_Y: i32 = 30;
+const CHANNEL_WIDTH: i32 = 155;
+// Right of the "ADCn:" heading
+const TRACKING_X: i32 = 53;
+const DIAGNOSTICS_POS: Point = Point::new(5, 30);
+const INTERNAL_POS: Point = Point::new(160, 30);
+const POLICY_POS: Point = Point::new(5, 30);
+const PROFILE_POS: Point = Point::new(5, 30);
+const SWEEP_POS: Point = Point::new(5, 30);
+const SWEEP_PLOT: Rectangle = Rectangle::new(Point::new(5, 48), Size::new(310, 170));
+const SWEEP_AXES_POS: Point = Point::new(5, 222);
+const STATS_POS: Point = Point::new(5, 30);
+const ENERGY_POS: Point = Point::new(5, 30);
+const CHARGE_POS: Point = Point::new(5, 30);
+const CAPTURE_POS: Point = Point::new(5, 30);
+const CAPTURE_PLOT: Rectangle = Rectangle::new(Point::new(5, 48), Size::new(310, 170));
+const CAPTURE_SCALE_POS: Point = Point::new(5, 222);
+// Divisions across and up the capture plot
+const CAPTURE_COLUMNS: u32 = 10;
+const CAPTURE_ROWS: u32 = 8;
+
+/// Owns the display and decides where everything goes.
+pub struct Renderer {
+    terminal: Terminal,
+    page: Page,
+    // sweep points on screen, None when the plot needs a full redraw
+    sweep_drawn: Option<usize>,
+    // capture record on screen, None when the page needs a redraw
+    capture_drawn: Option<u32>,
+    // last one seen in a StateView
+    policy: Policy,
+}
+
+impl Renderer {
+    pub fn new(terminal: Terminal) -> Self {
+        Self {
+            terminal,
+            page: Page::State,
+            policy: Policy::Bridge,
+            sweep_drawn: None,
+            capture_drawn: None,
+        }
+    }
+
+    /// Draws `msg`, returning a command when a button asked for a change.
+    pub fn render(&mut self, msg: UiMessage, diagnostics: &Diagnostics) -> Option<Command> {
+        match msg {
+            UiMessage::State(view) => {
+                let changed = view.policy != self.policy;
+                self.policy = view.policy;
+                match self.page {
+                    Page::State => self.draw_state(&view),
+                    Page::Diagnostics => {
+                        self.draw_diagnostics(diagnostics);
+                        self.draw_internal(&view.internal);
+                    }
+                    Page::Policy if changed => self.draw_policy(),
+                    Page::Profile => self.draw_profiles(&view.profiles),
+                    Page::Stats => self.draw_stats(&view.stats),
+                    Page::Energy => self.draw_energy(&view.energy),
+                    Page::Charge => self.draw_charge(&view.charge),
+                    // Sweep and capture redraw when their data changes
+                    Page::Policy | Page::Console | Page::Sweep | Page::Capture => {}
+                }
+            }
+            UiMessage::Button(event) => return self.button(event, diagnostics),
+            UiMessage::Fault(fault) => {
+                self.terminal.log(Level::Error, fault);
+                if self.page == Page::State {
+                    let mut buf = ArrayString::<[u8; 40]>::new();
+                    write!(&mut buf, "FAULT {:<33}", fault).ok();
+                    self.terminal.write_pos_color(STATUS_POS, &buf, Rgb565::WHITE, Rgb565::RED);
+                }
+            }
+            UiMessage::Log(level, line) => self.terminal.log(level, &line),
+        }
+        None
+    }
+
+    fn button(&mut self, event: ButtonEvent, diagnostics: &Diagnostics) -> Option<Command> {
+        let mut command = None;
+        match &event {
+            ButtonEvent {
+                button: Button::Click,
+                down: true,
+            } => {
+                self.page = self.page.next();
+                self.terminal.set_console_visible(self.page == Page::Console);
+                match self.page {
+                    Page::Diagnostics => self.draw_diagnostics(diagnostics),
+                    Page::Policy => self.draw_policy(),
+                    Page::Sweep => self.sweep_drawn = None,
+                    Page::Capture => self.capture_drawn = None,
+                    _ => {}
+                }
+            }
+            ButtonEvent {
+                button: Button::Left,
+                down: true,
+            } if self.page == Page::Console => self.terminal.scroll_back(1),
+            ButtonEvent {
+                button: Button::Right,
+                down: true,
+            } if self.page == Page::Console => self.terminal.scroll_forward(1),
+            // The page is redrawn once the next StateView shows the change took
+            ButtonEvent {
+                button: Button::Left,
+                down: true,
+            } if self.page == Page::Policy => command = Some(Command::Policy(self.policy.next(-1))),
+            ButtonEvent {
+                button: Button::Right,
+                down: true,
+            } if self.page == Page::Policy => command = Some(Command::Policy(self.policy.next(1))),
+            ButtonEvent {
+                button: Button::Left,
+                down: true,
+            } if self.page == Page::Energy => command = Some(Command::EnergyReset(Some(0))),
+            ButtonEvent {
+                button: Button::Right,
+                down: true,
+            } if self.page == Page::Energy => command = Some(Command::EnergyReset(Some(1))),
+            ButtonEvent {
+                button: Button::Left,
+                down: true,
+            } if self.page == Page::State => command = Some(Command::StepSize { fine: true }),
+            ButtonEvent {
+                button: Button::Right,
+                down: true,
+            } if self.page == Page::State => command = Some(Command::StepSize { fine: false }),
+            _ => {}
+        }
+
+        if self.page == Page::State {
+            let mut buf = ArrayString::<[u8; 40]>::new();
+            write!(&mut buf, "Btn {:?} {}", event.button, if event.down { "down" } else { "up" }).ok();
+            // Pad over whatever the previous status line left behind
+            while buf.len() < 38 {
+                buf.push(' ');
+            }
+            self.terminal.write_pos(STATUS_POS, &buf);
+        }
+        command
+    }
+
+    fn draw_state(&mut self, view: &StateView) {
+        // Dashes in place of a reading from a sensor that isn't fitted
+        struct Measured(Option<f32>, &'static str);
+        impl core::fmt::Display for Measured {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                match self.0 {
+                    Some(v) => write!(f, "{:>6.2}{}", v, self.1),
+                    None => write!(f, "{:>6}{}", "--", self.1),
+                }
+            }
+        }
+
+        // State of charge, flagged once the cutoff has stopped the transfer
+        struct Battery(Option<BatteryStatus>);
+        impl core::fmt::Display for Battery {
+            fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                match self.0 {
+                    Some(b) => write!(f, "Batt: {:>3.0}% {:<3}", b.soc * 100.0, if b.cut_off { "CUT" } else { "" }),
+                    None => write!(f, "{:<14}", ""),
+                }
+            }
+        }
+
+        fn fmt(num: usize, side: &Side, adc: u16, dac: u16, txt: &str, battery: Option<BatteryStatus>,
+               config: &ChannelConfig, fine: bool) -> ArrayString<[u8; 256]> {
+            let (units, precision) = (config.units.as_str(), config.precision as usize);
+            let mut buf = ArrayString::new();
+            fn to_voltage(raw: u16) -> f32 {
+                (raw as f32) / 4096.0 * 3300f32
+            }
+            write!(&mut buf,
+r"ADC{}:
+ Raw:
+   {:>9}
+   {:>7.2}mV
+ Converted:
+   {:>08.*}{:<4}
+{}:
+  Desired {:<8}
+    {:>05.*}{:<4}
+  Raw:
+    {:>9}
+    {:>7.2}mV
+  Real: {}
+    {:>05.*}{:<4} {}
+{}
+",
+                   num,
+                   adc,
+                   to_voltage(adc),
+                   precision + 3,
+                   side.input,
+                   units,
+                   txt,
+                   if fine { "(fine):" } else { "Output:" },
+                   precision,
+                   side.desired_output,
+                   units,
+                   dac,
+                   to_voltage(dac),
+                   Measured(side.power(), "W"),
+                   precision,
+                   side.real_output,
+                   units,
+                   Measured(side.current, "A"),
+                   Battery(battery),
+            ).expect("!write");
+            buf
+        }
+
+        for (i, (side, route)) in view.state.channels.iter().zip(ROUTES.iter()).enumerate() {
+            let buf = fmt(route.input, side, view.inputs.raw[route.input], view.outputs.raw[route.output], route.name, view.batteries[i],
+                          &view.channels[i], view.fine);
+            self.terminal.write_pos(Point::new(5 + CHANNEL_WIDTH * i as i32, CHANNEL_Y), &buf);
+
+            let mut tag = ArrayString::<[u8; 16]>::new();
+            match view.tracking {
+                Some(t) if t.leader == i => write!(&mut tag, "{:<12}", "LEADING"),
+                Some(t) => write!(&mut tag, "x{:<11.2}", t.ratio),
+                None => write!(&mut tag, "{:<12}", ""),
+            }.ok();
+            self.terminal.write_pos(Point::new(TRACKING_X + CHANNEL_WIDTH * i as i32, CHANNEL_Y), &tag);
+        }
+    }
+
+    fn draw_diagnostics(&mut self, diagnostics: &Diagnostics) {
+        let mut buf = ArrayString::<[u8; 256]>::new();
+        write!(&mut buf, "UI queue:\n  sent {:>10}\n  peak {:>6}/{}\n\nDropped:\n",
+               diagnostics.sent, diagnostics.high_water, QUEUE_SIZE - 1).ok();
+        for (name, dropped) in KIND_NAMES.iter().zip(diagnostics.dropped.iter()) {
+            write!(&mut buf, "  {:<8}{:>10}\n", name, dropped).ok();
+        }
+        self.terminal.write_pos(DIAGNOSTICS_POS, &buf);
+    }
+
+    fn draw_internal(&mut self, internal: &InternalReadings) {
+        let mut buf = ArrayString::<[u8; 128]>::new();
+        write!(&mut buf, "Internal:\n  Temp   {:>6.1}C\n  VDDCORE{:>6.3}V\n  VBAT   {:>6.3}V\n  IOVCC  {:>6.3}V\n",
+               internal.temperature, internal.vddcore, internal.vbat, internal.iovcc).ok();
+        self.terminal.write_pos(INTERNAL_POS, &buf);
+    }
+
+    fn draw_policy(&mut self) {
+        let mut buf = ArrayString::<[u8; 256]>::new();
+        write!(&mut buf, "Transfer policy:\n").ok();
+        for policy in Policy::ALL.iter() {
+            let marker = if *policy == self.policy { '>' } else { ' ' };
+            match policy {
+                Policy::Priority(channel) => write!(&mut buf, " {} {} {}\n", marker, policy.name(), ROUTES[*channel].name),
+                _ => write!(&mut buf, " {} {:<30}\n", marker, policy.name()),
+            }.ok();
+        }
+        write!(&mut buf, "\nLeft/Right to change").ok();
+        self.terminal.write_pos(POLICY_POS, &buf);
+    }
+
+    fn draw_profiles(&mut self, profiles: &[Progress]) {
+        let mut buf = ArrayString::<[u8; 512]>::new();
+        for (channel, (progress, route)) in profiles.iter().zip(ROUTES.iter()).enumerate() {
+            write!(&mut buf, "Profile {} ({})\n  {:<10}\n", channel, route.name, progress.state.name()).ok();
+            if progress.state == RunState::Idle {
+                write!(&mut buf, "{:<36}\n{:<36}\n\n", "", "").ok();
+                continue;
+            }
+            write!(&mut buf, "  segment {:>2}/{:<2} pass {:>4}/", progress.segment + 1, progress.segments, progress.pass).ok();
+            match progress.loops {
+                0 => write!(&mut buf, "{:<5}\n", "inf"),
+                loops => write!(&mut buf, "{:<5}\n", loops),
+            }.ok();
+            write!(&mut buf, "  {:>9.1}s {:>6.2}V{:<14}\n\n", progress.elapsed_ms as f32 / 1000.0, progress.level, "").ok();
+        }
+        self.terminal.write_pos(PROFILE_POS, &buf);
+    }
+
+    fn draw_stats(&mut self, stats: &ChannelStats) {
+        let mut buf = ArrayString::<[u8; 768]>::new();
+        for signal in ChannelStats::signals() {
+            let s = stats.get(signal).summary();
+            write!(&mut buf, "{} {} n={:<10}\n", signal.kind(), signal.channel(), s.count).ok();
+            write!(&mut buf, " min {:>7.3} max {:>7.3} pp {:>7.3}\n", s.min, s.max, s.peak_to_peak).ok();
+            write!(&mut buf, " avg {:>7.3} rms {:>7.3} sd {:>7.3}\n", s.mean, s.rms, s.std_dev).ok();
+        }
+        self.terminal.write_pos(STATS_POS, &buf);
+    }
+
+    fn draw_energy(&mut self, energy: &[Counter]) {
+        let mut buf = ArrayString::<[u8; 512]>::new();
+        for (counter, route) in energy.iter().zip(ROUTES.iter()) {
+            let secs = counter.active_ms / 1000;
+            write!(&mut buf, "{}\n  {:>12.4} Wh\n  {:>12.4} Ah\n  active {:>4}:{:02}:{:02}\n\n",
+                   route.name, counter.watt_hours(), counter.amp_hours(), secs / 3600, secs / 60 % 60, secs % 60).ok();
+        }
+        write!(&mut buf, "Left/Right to reset").ok();
+        self.terminal.write_pos(ENERGY_POS, &buf);
+    }
+
+    fn draw_charge(&mut self, status: &ChargeStatus) {
+        let mut buf = ArrayString::<[u8; 256]>::new();
+        write!(&mut buf, "Charger\n  {:<20}\n\n", status.state.name()).ok();
+        if status.state == ChargeState::Idle {
+            write!(&mut buf, "{:<30}\n{:<30}\n{:<30}\n{:<30}\n", "", "", "", "").ok();
+        } else {
+            let secs = status.elapsed_ms / 1000;
+            write!(&mut buf, "  battery {:>7.3}V\n  current {:>7.3}A\n  output  {:>7.3}V\n  time    {:>4}:{:02}:{:02}\n",
+                   status.volts, status.amps, status.level, secs / 3600, secs / 60 % 60, secs % 60).ok();
+        }
+        self.terminal.write_pos(CHARGE_POS, &buf);
+    }
+
+    /// True when the sweep page is up and doesn't show `points` yet.
+    pub fn needs_sweep(&self, points: usize) -> bool {
+        self.page == Page::Sweep && self.sweep_drawn != Some(points)
+    }
+
+    pub fn draw_sweep(&mut self, sweep: &Sweep) {
+        let results = sweep.results();
+        self.sweep_drawn = Some(results.len());
+
+        let mut buf = ArrayString::<[u8; 40]>::new();
+        match sweep.config() {
+            Some(config) => write!(&mut buf, "Sweep out{} -> in{} {:>3}/{:<3} {:?}",
+                                   config.channel, config.measure, results.len(), config.points, sweep.phase()),
+            None => write!(&mut buf, "Sweep: none run yet"),
+        }.ok();
+        while buf.len() < 38 {
+            buf.push(' ');
+        }
+        self.terminal.write_pos(SWEEP_POS, &buf);
+
+        let Some(config) = sweep.config() else {
+            self.terminal.plot(SWEEP_PLOT, core::iter::empty());
+            return;
+        };
+        let log = config.spacing == Spacing::Log;
+        let scale_x = |v: f32| if log { v.ln() } else { v };
+        let (x_min, x_max) = (config.start.min(config.stop), config.start.max(config.stop));
+        let (sx_min, sx_max) = (scale_x(x_min), scale_x(x_max));
+        let (mut y_min, mut y_max) = results.iter()
+            .fold((f32::MAX, f32::MIN), |(lo, hi), p| (lo.min(p.measured), hi.max(p.measured)));
+        if results.is_empty() || y_max - y_min < 0.01 {
+            y_min -= 0.5;
+            y_max += 0.5;
+        }
+
+        let area = SWEEP_PLOT;
+        let (w, h) = (area.size.width as f32 - 4.0, area.size.height as f32 - 4.0);
+        let points = results.iter().map(|p| {
+            let x = (scale_x(p.level) - sx_min) / (sx_max - sx_min);
+            let y = (p.measured - y_min) / (y_max - y_min);
+            area.top_left + Point::new(2 + (x * w) as i32, 2 + ((1.0 - y) * h) as i32)
+        });
+        self.terminal.plot(area, points);
+
+        let mut axes = ArrayString::<[u8; 40]>::new();
+        if results.is_empty() {
+            write!(&mut axes, "x {:.2}..{:.2}V{}", x_min, x_max, if log { " log" } else { "" }).ok();
+        } else {
+            write!(&mut axes, "x {:.2}..{:.2}V y {:.2}..{:.2}V", x_min, x_max, y_min, y_max).ok();
+        }
+        while axes.len() < 38 {
+            axes.push(' ');
+        }
+        self.terminal.write_pos(SWEEP_AXES_POS, &axes);
+    }
+
+    /// True when the capture page is up and doesn't show record `records` yet.
+    pub fn needs_capture(&self, records: u32) -> bool {
+        self.page == Page::Capture && self.capture_drawn != Some(records)
+    }
+
+    /// Draws `waveform` if there is one.
+    pub fn draw_capture(&mut self, records: u32, state: CaptureState, waveform: Option<&Waveform>) {
+        self.capture_drawn = Some(records);
+
+        let mut buf = ArrayString::<[u8; 40]>::new();
+        match waveform {
+            Some(w) => write!(&mut buf, "Capture in{} {:?} {}{}", w.config.input, w.config.mode, state.name(),
+                              if w.forced { " (auto)" } else { "" }),
+            None => write!(&mut buf, "Capture: {}", state.name()),
+        }.ok();
+        while buf.len() < 38 {
+            buf.push(' ');
+        }
+        self.terminal.write_pos(CAPTURE_POS, &buf);
+
+        let Some(waveform) = waveform else {
+            self.terminal.trace(CAPTURE_PLOT, CAPTURE_COLUMNS, CAPTURE_ROWS, core::iter::empty());
+            return;
+        };
+        // The full input range, so the trace doesn't jump between records
+        let scale = &waveform.scale;
+        let full = scale.input.full_scale();
+        let (v_min, v_max) = match scale.input.negative {
+            Some(_) => (scale.to_volts((full / 2) as u16), scale.to_volts((full / 2 - 1) as u16)),
+            None => (0.0, scale.to_volts((full - 1) as u16)),
+        };
+
+        let samples = waveform.samples();
+        let area = CAPTURE_PLOT;
+        let (w, h) = (area.size.width as f32 - 4.0, area.size.height as f32 - 4.0);
+        let last = (samples.len() - 1) as f32;
+        let points = samples.iter().enumerate().map(|(i, raw)| {
+            let y = ((scale.to_volts(*raw) - v_min) / (v_max - v_min)).max(0.0).min(1.0);
+            area.top_left + Point::new(2 + (i as f32 / last * w) as i32, 2 + ((1.0 - y) * h) as i32)
+        });
+        self.terminal.trace(area, CAPTURE_COLUMNS, CAPTURE_ROWS, points);
+
+        let per_div_us = waveform.period_us * samples.len() as f32 / CAPTURE_COLUMNS as f32;
+        let mut scale = ArrayString::<[u8; 40]>::new();
+        if per_div_us < 1000.0 {
+            write!(&mut scale, "{:.1}us/div", per_div_us).ok();
+        } else {
+            write!(&mut scale, "{:.2}ms/div", per_div_us / 1000.0).ok();
+        }
+        write!(&mut scale, " {:.2}V/div pre {}", (v_max - v_min) / CAPTURE_ROWS as f32, waveform.config.pre_trigger).ok();
+        while scale.len() < 38 {
+            scale.push(' ');
+        }
+        self.terminal.write_pos(CAPTURE_SCALE_POS, &scale);
+    }
+}